@@ -1,7 +1,7 @@
 use criterion::{criterion_group, criterion_main, Criterion};
 
-use rustsynth::module::Rack;
 use rustsynth::module::{Buf, EG, IIRLPF, VCO};
+use rustsynth::module::{ProcessContext, Rack};
 use rustsynth::WaveForm;
 use rustsynth::{define_input, define_rack};
 
@@ -36,6 +36,7 @@ define_rack! {
             in_waveform: { input.vco1_waveform } ,
             freq_min: 100.0,
             freq_max: 15000.0,
+            band_limited: true,
         },
         eg1: EG {
             in_gate: { input.eg1_gate },
@@ -61,10 +62,11 @@ define_rack! {
 fn bench1(c: &mut Criterion) {
     c.bench_function("rack1_update_10ms", |b| {
         let rack = Rack1::new();
-        let input = Rack1::new_input();
+        let input = rack.new_input();
+        let ctx = ProcessContext::new(44_100);
         b.iter(|| {
             for _ in 0..441 {
-                rack.update(&input);
+                rack.update(&input, &ctx);
             }
         });
     });
@@ -1,182 +1,753 @@
 use crate::input::{
-    ButtonMode, FieldType, InputConfig, Key, OutputConfig, StateInput, StateOutput,
+    ButtonMode, Curve, FieldType, InputConfig, Key, NoteInputConfig, NotePriority, OutputConfig,
+    StateDefinition, StateInput, StateOutput, Takeover, ValueRange,
 };
+use crate::poly::VoiceStealing;
+use crate::smoothing::Smoothing;
 use anyhow::{Context, Result};
+use serde::de::value::{MapAccessDeserializer, SeqAccessDeserializer};
+use serde::de::{self, MapAccess, SeqAccess, Unexpected, Visitor};
+use serde::{Deserialize, Deserializer};
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Schema of the config file
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    #[serde(default)]
+    device: DeviceSection,
+    rack: RackSection,
+    #[serde(default)]
+    notes: NotesSection,
+    poly: Option<PolySection>,
+    #[serde(default)]
+    presets: PresetsSection,
+    #[serde(default)]
+    keys: BTreeMap<String, KeyConfig>,
+    #[serde(default)]
+    smoothing: BTreeMap<String, SmoothingSection>,
+    #[serde(default)]
+    inputs: toml::value::Table,
+    #[serde(default)]
+    modules: Vec<toml::value::Table>,
+    #[serde(default)]
+    patch: toml::value::Table,
+}
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DeviceSection {
+    #[serde(default)]
+    default: DevicePorts,
+}
+/// MIDI port names
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DevicePorts {
+    input: Option<String>,
+    output: Option<String>,
+}
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RackSection {
+    name: String,
+    out: Option<String>,
+    out_r: Option<String>,
+}
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NotesSection {
+    #[serde(default)]
+    priority: NotePriority,
+    #[serde(default)]
+    legato: bool,
+}
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PolySection {
+    voices: usize,
+    #[serde(default)]
+    stealing: VoiceStealing,
+}
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PresetsSection {
+    startup: Option<String>,
+    #[serde(default)]
+    slots: Vec<PresetSlotSection>,
+}
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PresetSlotSection {
+    file: String,
+    #[serde(default, deserialize_with = "cc_number")]
+    recall: Option<u8>,
+    #[serde(default, deserialize_with = "cc_number")]
+    save: Option<u8>,
+}
+
+/// lpf1_freq = { mode = "linear", time = 0.05 }
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SmoothingSection {
+    #[serde(default)]
+    mode: SmoothingMode,
+    /// sec, 0 disables the smoothing defined by the rack
+    time: f32,
+}
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum SmoothingMode {
+    #[default]
+    OnePole,
+    Linear,
+}
+
+/// Mapping of an input field in [keys]. The form depends on the field type.
+#[derive(Debug, Clone)]
+enum KeyConfig {
+    /// f32: CC number
+    ControlChange(u8),
+    /// { note = "pitch", freq_min = 100.0, freq_max = 15000.0 }
+    Note(NoteKey),
+    /// f32: { key = 0x02, min = 0.001, max = 4.0, curve = "exp", invert = false, takeover = "pickup" }
+    Range(RangeKey),
+    /// bool: { key = 0x20, mode = "toggle", out = 0x20 }
+    Button(ButtonKey),
+    /// enum: [{ key = 0x20, values = ["Sine", "Triangle"], out = 0x20 }, ...]
+    Enum(Vec<EnumKey>),
+}
+// Not untagged: it would discard the position and the message of errors inside a mapping.
+impl<'de> Deserialize<'de> for KeyConfig {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        deserializer.deserialize_any(KeyConfigVisitor)
+    }
+}
+struct KeyConfigVisitor;
+impl<'de> Visitor<'de> for KeyConfigVisitor {
+    type Value = KeyConfig;
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(
+            "a CC number, a note mapping, a range mapping, a button mapping or an array of enum mappings",
+        )
+    }
+    fn visit_i64<E: de::Error>(self, v: i64) -> std::result::Result<KeyConfig, E> {
+        CcVisitor.visit_i64(v).map(KeyConfig::ControlChange)
+    }
+    fn visit_u64<E: de::Error>(self, v: u64) -> std::result::Result<KeyConfig, E> {
+        CcVisitor.visit_u64(v).map(KeyConfig::ControlChange)
+    }
+    fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> std::result::Result<KeyConfig, A::Error> {
+        Vec::deserialize(SeqAccessDeserializer::new(seq)).map(KeyConfig::Enum)
+    }
+    fn visit_map<A: MapAccess<'de>>(self, map: A) -> std::result::Result<KeyConfig, A::Error> {
+        MappingKey::deserialize(MapAccessDeserializer::new(map))?.into_key_config()
+    }
+}
+/// MIDI CC numbers are 7-bit, larger ones would never match
+struct CcVisitor;
+impl<'de> Visitor<'de> for CcVisitor {
+    type Value = u8;
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a CC number")
+    }
+    fn visit_i64<E: de::Error>(self, v: i64) -> std::result::Result<u8, E> {
+        match u8::try_from(v) {
+            Ok(n) if n <= 0x7F => Ok(n),
+            _ => Err(E::invalid_value(Unexpected::Signed(v), &self)),
+        }
+    }
+    fn visit_u64<E: de::Error>(self, v: u64) -> std::result::Result<u8, E> {
+        match u8::try_from(v) {
+            Ok(n) if n <= 0x7F => Ok(n),
+            _ => Err(E::invalid_value(Unexpected::Unsigned(v), &self)),
+        }
+    }
+}
+fn cc_number<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Option<u8>, D::Error> {
+    deserializer.deserialize_any(CcVisitor).map(Some)
+}
+/// All fields of the note, range and button mappings. The form is chosen by the fields present.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MappingKey {
+    note: Option<NoteKind>,
+    freq_min: Option<f32>,
+    freq_max: Option<f32>,
+    #[serde(default, deserialize_with = "cc_number")]
+    key: Option<u8>,
+    min: Option<f32>,
+    max: Option<f32>,
+    curve: Option<Curve>,
+    invert: Option<bool>,
+    takeover: Option<Takeover>,
+    mode: Option<ButtonMode>,
+    #[serde(default, deserialize_with = "cc_number")]
+    out: Option<u8>,
+}
+impl MappingKey {
+    fn into_key_config<E: de::Error>(self) -> std::result::Result<KeyConfig, E> {
+        let note_fields = [
+            ("freq_min", self.freq_min.is_some()),
+            ("freq_max", self.freq_max.is_some()),
+        ];
+        let range_fields = [
+            ("min", self.min.is_some()),
+            ("max", self.max.is_some()),
+            ("curve", self.curve.is_some()),
+            ("invert", self.invert.is_some()),
+            ("takeover", self.takeover.is_some()),
+        ];
+        let button_fields = [("mode", self.mode.is_some()), ("out", self.out.is_some())];
+        let key_field = [("key", self.key.is_some())];
+        let present = |fields: &[(&str, bool)]| fields.iter().any(|(_, p)| *p);
+        let reject = |kind: &str, fields: &[&[(&'static str, bool)]]| match fields
+            .iter()
+            .flat_map(|f| f.iter())
+            .find(|(_, p)| *p)
+        {
+            Some((name, _)) => Err(E::custom(format!(
+                "field `{}` is not allowed in a {} mapping",
+                name, kind
+            ))),
+            None => Ok(()),
+        };
+        if let Some(note) = self.note {
+            reject("note", &[&key_field, &range_fields, &button_fields])?;
+            Ok(KeyConfig::Note(NoteKey {
+                note,
+                freq_min: self.freq_min,
+                freq_max: self.freq_max,
+            }))
+        } else if present(&note_fields) {
+            Err(E::missing_field("note"))
+        } else if present(&button_fields) {
+            reject("button", &[&range_fields])?;
+            Ok(KeyConfig::Button(ButtonKey {
+                key: self.key,
+                mode: self.mode,
+                out: self.out,
+            }))
+        } else if present(&key_field) || present(&range_fields) {
+            Ok(KeyConfig::Range(RangeKey {
+                key: self.key.ok_or_else(|| E::missing_field("key"))?,
+                min: self.min,
+                max: self.max,
+                curve: self.curve.unwrap_or_default(),
+                invert: self.invert.unwrap_or_default(),
+                takeover: self.takeover.unwrap_or_default(),
+            }))
+        } else {
+            Ok(KeyConfig::Button(ButtonKey {
+                key: None,
+                mode: None,
+                out: None,
+            }))
+        }
+    }
+}
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum NoteKind {
+    Pitch,
+    Velocity,
+    Gate,
+    Trigger,
+}
+#[derive(Debug, Clone)]
+struct NoteKey {
+    note: NoteKind,
+    freq_min: Option<f32>,
+    freq_max: Option<f32>,
+}
+#[derive(Debug, Clone)]
+struct RangeKey {
+    key: u8,
+    min: Option<f32>,
+    max: Option<f32>,
+    curve: Curve,
+    invert: bool,
+    takeover: Takeover,
+}
+#[derive(Debug, Clone)]
+struct ButtonKey {
+    key: Option<u8>,
+    mode: Option<ButtonMode>,
+    out: Option<u8>,
+}
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct EnumKey {
+    #[serde(default, deserialize_with = "cc_number")]
+    key: Option<u8>,
+    values: Vec<String>,
+    #[serde(default, deserialize_with = "cc_number")]
+    out: Option<u8>,
+}
 
 #[derive(Debug)]
 pub struct Config {
     pub midi_in_name: Option<String>,
     pub midi_out_name: Option<String>,
     pub rack_name: String,
-    keys: toml::map::Map<String, toml::value::Value>,
+    pub note_priority: NotePriority,
+    pub legato: bool,
+    pub poly: Option<PolyConfig>,
+    pub patch: PatchConfig,
+    pub presets: PresetConfig,
+    /// [smoothing]: Overrides the smoothing defined by the rack
+    pub smoothing: BTreeMap<String, Smoothing>,
+    keys: BTreeMap<String, KeyConfig>,
+}
+/// Module graph of `DynRack`
+#[derive(Debug, Clone, Default)]
+pub struct PatchConfig {
+    /// rack.out: Expression of the rack output, the left channel if out_r is set
+    pub out: Option<String>,
+    /// rack.out_r: Expression of the right channel
+    pub out_r: Option<String>,
+    /// [inputs]: Input fields and their default values
+    pub inputs: toml::value::Table,
+    /// [[modules]]: Modules in the order of update
+    pub modules: Vec<toml::value::Table>,
+    /// [patch]: Module inputs (module.in_xxx = "expression")
+    pub patch: toml::value::Table,
+}
+#[derive(Debug, Default)]
+pub struct PresetConfig {
+    /// Preset file loaded on startup
+    pub startup: Option<String>,
+    pub slots: Vec<PresetSlot>,
+}
+/// Preset file recalled or saved by buttons
+#[derive(Debug)]
+pub struct PresetSlot {
+    pub file: String,
+    pub recall: Option<Key>,
+    pub save: Option<Key>,
+}
+#[derive(Debug)]
+pub struct PolyConfig {
+    pub voices: usize,
+    pub stealing: VoiceStealing,
 }
 pub fn load_config(path: &str) -> Result<Config> {
-    use std::io::Read;
-    use toml::Value;
-
-    let mut file = std::fs::File::open(path)?;
-    let mut content = String::new();
-    file.read_to_string(&mut content)?;
-    let parsed = content.parse::<Value>()?;
-    let midi_in_name = parsed.get("default").and_then(|d| d.get("input"));
-    let midi_in_name = match midi_in_name {
-        Some(x) => Some(
-            x.as_str()
-                .context("Type error at default.input")?
-                .to_owned(),
-        ),
-        None => None,
-    };
-    let midi_out_name = parsed.get("default").and_then(|d| d.get("output"));
-    let midi_out_name = match midi_out_name {
-        Some(x) => Some(
-            x.as_str()
-                .context("Type error at default.output")?
-                .to_owned(),
-        ),
-        None => None,
-    };
-    let rack_name = parsed
-        .get("rack")
-        .and_then(|d| d.get("name"))
-        .context("rack.name is not defined")?
-        .as_str()
-        .context("Type error at rack.name")?
-        .to_owned();
-    let keys = parsed
-        .get("keys")
-        .and_then(|d| d.as_table())
-        .cloned()
-        .unwrap_or_else(toml::map::Map::new);
+    let content = std::fs::read_to_string(path).with_context(|| format!("Can't read {}", path))?;
+    parse_config(&content).with_context(|| format!("Invalid config: {}", path))
+}
+pub fn parse_config(content: &str) -> Result<Config> {
+    // The error message contains the line and column
+    let parsed: ConfigFile = toml::from_str(content)?;
+    if parsed.poly.as_ref().is_some_and(|p| p.voices == 0) {
+        anyhow::bail!("poly.voices must be positive");
+    }
+    let smoothing = parsed
+        .smoothing
+        .into_iter()
+        .map(|(name, s)| {
+            if s.time.is_nan() || s.time < 0.0 {
+                anyhow::bail!("smoothing.{}.time must not be negative", name);
+            }
+            let smoothing = match s.mode {
+                SmoothingMode::OnePole => Smoothing::OnePole(s.time),
+                SmoothingMode::Linear => Smoothing::Linear(s.time),
+            };
+            Ok((name, smoothing))
+        })
+        .collect::<Result<_>>()?;
+    let cc = |n: Option<u8>| n.map(Key::ControlChange);
     Ok(Config {
-        midi_in_name,
-        midi_out_name,
-        rack_name,
-        keys,
+        midi_in_name: parsed.device.default.input,
+        midi_out_name: parsed.device.default.output,
+        rack_name: parsed.rack.name,
+        note_priority: parsed.notes.priority,
+        legato: parsed.notes.legato,
+        poly: parsed.poly.map(|p| PolyConfig {
+            voices: p.voices,
+            stealing: p.stealing,
+        }),
+        patch: PatchConfig {
+            out: parsed.rack.out,
+            out_r: parsed.rack.out_r,
+            inputs: parsed.inputs,
+            modules: parsed.modules,
+            patch: parsed.patch,
+        },
+        presets: PresetConfig {
+            startup: parsed.presets.startup,
+            slots: parsed
+                .presets
+                .slots
+                .into_iter()
+                .map(|s| PresetSlot {
+                    file: s.file,
+                    recall: cc(s.recall),
+                    save: cc(s.save),
+                })
+                .collect(),
+        },
+        smoothing,
+        keys: parsed.keys,
     })
 }
 
+/// Problems that don't prevent running: input fields without any mapping and
+/// CC numbers used by more than one input or output.
+pub fn validate<S>(config: &Config, state_definition: &StateDefinition<S>) -> Vec<String> {
+    let mut warnings = Vec::new();
+    let mut fields = state_definition
+        .fields()
+        .map(|(name, _)| name)
+        .filter(|name| !config.keys.contains_key(*name))
+        .collect::<Vec<_>>();
+    fields.sort_unstable();
+    for name in fields {
+        warnings.push(format!("Field not mapped: {}", name));
+    }
+
+    // CC number -> where it's used
+    let mut inputs = BTreeMap::<u8, Vec<String>>::new();
+    let mut outputs = BTreeMap::<u8, Vec<String>>::new();
+    for (name, key) in config.keys.iter() {
+        match key {
+            KeyConfig::ControlChange(n) | KeyConfig::Range(RangeKey { key: n, .. }) => {
+                inputs.entry(*n).or_default().push(format!("keys.{}", name))
+            }
+            KeyConfig::Note(_) => {}
+            KeyConfig::Button(b) => {
+                if let Some(n) = b.key {
+                    inputs.entry(n).or_default().push(format!("keys.{}", name));
+                }
+                if let Some(n) = b.out {
+                    outputs.entry(n).or_default().push(format!("keys.{}", name));
+                }
+            }
+            KeyConfig::Enum(items) => {
+                for (i, item) in items.iter().enumerate() {
+                    if let Some(n) = item.key {
+                        inputs
+                            .entry(n)
+                            .or_default()
+                            .push(format!("keys.{}[{}]", name, i));
+                    }
+                    if let Some(n) = item.out {
+                        outputs
+                            .entry(n)
+                            .or_default()
+                            .push(format!("keys.{}[{}]", name, i));
+                    }
+                }
+            }
+        }
+    }
+    for (i, slot) in config.presets.slots.iter().enumerate() {
+        for (key, kind) in [(&slot.recall, "recall"), (&slot.save, "save")] {
+            if let Some(Key::ControlChange(n)) = key {
+                inputs
+                    .entry(*n)
+                    .or_default()
+                    .push(format!("presets.slots[{}].{}", i, kind));
+            }
+        }
+    }
+    for (kind, map) in [("input", inputs), ("output", outputs)] {
+        for (n, names) in map {
+            if names.len() > 1 {
+                warnings.push(format!(
+                    "CC 0x{:02X} is mapped to multiple {}s: {}",
+                    n,
+                    kind,
+                    names.join(", ")
+                ));
+            }
+        }
+    }
+    warnings
+}
+
 pub fn setup_state_io<S>(
     config: &Config,
     state_in: &mut StateInput<S>,
     state_out: &mut StateOutput<S>,
 ) -> Result<()> {
+    state_in.set_note_mode(config.note_priority, config.legato);
     for (name, value) in config.keys.iter() {
-        match state_in.field_type(name) {
-            None => {
-                anyhow::bail!("Field not defined: {}", name);
+        let field_type = state_in.field_type(name);
+        match (field_type, value) {
+            (_, KeyConfig::Note(note)) => {
+                state_in.define_note_input(note_input(name, note, state_in.field_type(name))?);
             }
-            Some(FieldType::F32) => {
-                let key = value
-                    .as_integer()
-                    .ok_or_else(|| anyhow::anyhow!("Type error at keys.{}", name))?;
-                let key = Key::ControlChange(key as u8);
-                state_in.define_input(
-                    key,
-                    InputConfig::F32 {
-                        name: name.to_owned(),
-                    },
-                )
+            (None, _) => {
+                anyhow::bail!("Field not defined: {}", name);
             }
-            Some(FieldType::Bool) => {
-                let value = value
-                    .as_table()
-                    .ok_or_else(|| anyhow::anyhow!("Type error at keys.{}", name))?;
-                let key = match value.get("key") {
-                    None => None,
-                    Some(x) => Some(
-                        x.as_integer()
-                            .ok_or_else(|| anyhow::anyhow!("Type error at keys.{}.key", name))?,
-                    ),
-                };
-                let key = key.map(|x| Key::ControlChange(x as u8));
-                if let Some(key) = key {
-                    let mode = match value.get("mode") {
-                        Some(toml::value::Value::String(s)) => match s.as_ref() {
-                            "toggle" => ButtonMode::Toggle,
-                            "momentary" => ButtonMode::Momentary,
-                            _ => return Err(anyhow::anyhow!("Invalid mode at keys.{}.mode", name)),
-                        },
-                        Some(_) => return Err(anyhow::anyhow!("Type error at keys.{}.mode", name)),
-                        None => return Err(anyhow::anyhow!("keys.{}.mode required", name)),
-                    };
+            (Some(FieldType::F32), KeyConfig::ControlChange(key)) => state_in.define_input(
+                Key::ControlChange(*key),
+                InputConfig::F32 {
+                    name: name.to_owned(),
+                    range: ValueRange::default(),
+                    takeover: Takeover::default(),
+                },
+            ),
+            (Some(FieldType::F32), KeyConfig::Range(range)) => state_in.define_input(
+                Key::ControlChange(range.key),
+                InputConfig::F32 {
+                    name: name.to_owned(),
+                    range: value_range(name, range)?,
+                    takeover: range.takeover,
+                },
+            ),
+            (Some(FieldType::Bool), KeyConfig::Button(button)) => {
+                if let Some(key) = button.key {
+                    let mode = button
+                        .mode
+                        .with_context(|| format!("keys.{}.mode required", name))?;
                     state_in.define_input(
-                        key,
+                        Key::ControlChange(key),
                         InputConfig::Bool {
                             name: name.to_owned(),
                             mode,
                         },
                     );
                 }
-                let out = match value.get("out") {
-                    Some(toml::value::Value::Integer(n)) => Some(Key::ControlChange(*n as u8)),
-                    Some(_) => return Err(anyhow::anyhow!("Type error at keys.{}.out", name)),
-                    None => None,
-                };
-                if let Some(out) = out {
+                if let Some(out) = button.out {
                     state_out.define_output(OutputConfig::Bool {
                         name: name.to_owned(),
-                        out,
+                        out: Key::ControlChange(out),
                     });
                 }
             }
-            Some(FieldType::Enum) => {
-                let value = value
-                    .as_array()
-                    .ok_or_else(|| anyhow::anyhow!("Type error at keys.{}", name))?;
-                for (i, v) in value.iter().enumerate() {
-                    let v = v
-                        .as_table()
-                        .ok_or_else(|| anyhow::anyhow!("Type error at keys.{}[{}]", name, i))?;
-                    let key = v.get("key");
-                    let key = match key.map(|x| x.as_integer()) {
-                        Some(Some(v)) => Some(Key::ControlChange(v as u8)),
-                        Some(None) => {
-                            return Err(anyhow::anyhow!("Type error at keys.{}[{}].key", name, i))
-                        }
-                        None => None,
-                    };
-                    let out = v.get("out");
-                    let out = match out.map(|x| x.as_integer()) {
-                        Some(Some(v)) => Some(Key::ControlChange(v as u8)),
-                        Some(None) => {
-                            return Err(anyhow::anyhow!("Type error at keys.{}[{}].out", name, i))
-                        }
-                        None => None,
-                    };
-                    let values = v
-                        .get("values")
-                        .ok_or_else(|| anyhow::anyhow!("Required: keys.{}[{}].values", name, i))?
-                        .as_array()
-                        .ok_or_else(|| {
-                            anyhow::anyhow!("Type error at keys.{}[{}].values", name, i)
-                        })?;
-                    let values = values
-                        .iter()
-                        .map(|x| x.as_str().map(|x| x.to_owned()))
-                        .collect::<Option<Vec<_>>>()
-                        .ok_or_else(|| {
-                            anyhow::anyhow!("Type error at keys.{}.[{}].values", name, i)
-                        })?;
-                    if let Some(key) = key {
+            (Some(FieldType::Enum), KeyConfig::Enum(items)) => {
+                for item in items.iter() {
+                    if let Some(key) = item.key {
                         state_in.define_input(
-                            key,
+                            Key::ControlChange(key),
                             InputConfig::Enum {
                                 name: name.to_owned(),
-                                values: values.clone(),
+                                values: item.values.clone(),
                             },
                         );
                     }
-                    if let Some(out) = out {
+                    if let Some(out) = item.out {
                         state_out.define_output(OutputConfig::Enum {
                             name: name.to_owned(),
-                            values,
-                            out,
+                            values: item.values.clone(),
+                            out: Key::ControlChange(out),
                         });
                     }
                 }
             }
+            (Some(field_type), _) => {
+                anyhow::bail!(
+                    "Type error at keys.{}: mapping doesn't match the field type {:?}",
+                    name,
+                    field_type
+                )
+            }
         }
     }
     Ok(())
 }
+
+fn value_range(name: &str, key: &RangeKey) -> Result<ValueRange> {
+    let default = ValueRange::default();
+    let range = ValueRange {
+        min: key.min.unwrap_or(default.min),
+        max: key.max.unwrap_or(default.max),
+        curve: key.curve,
+        invert: key.invert,
+    };
+    if range.curve == Curve::Exp && !(0.0 < range.min && 0.0 < range.max) {
+        anyhow::bail!("keys.{}: exp curve requires positive min and max", name);
+    }
+    Ok(range)
+}
+
+/// Note inputs defined in [keys]
+pub fn note_inputs<S>(
+    config: &Config,
+    state_definition: &StateDefinition<S>,
+) -> Result<Vec<NoteInputConfig>> {
+    let mut inputs = Vec::new();
+    for (name, value) in config.keys.iter() {
+        if let KeyConfig::Note(note) = value {
+            inputs.push(note_input(name, note, state_definition.field_type(name))?);
+        }
+    }
+    Ok(inputs)
+}
+
+fn note_input(
+    name: &str,
+    note: &NoteKey,
+    field_type: Option<FieldType>,
+) -> Result<NoteInputConfig> {
+    let required = |value: Option<f32>, key: &str| {
+        value.with_context(|| format!("keys.{}.{} required", name, key))
+    };
+    let name = name.to_owned();
+    match (note.note, field_type) {
+        (_, None) => anyhow::bail!("Field not defined: {}", name),
+        (NoteKind::Pitch, Some(FieldType::F32)) => Ok(NoteInputConfig::Pitch {
+            freq_min: required(note.freq_min, "freq_min")?,
+            freq_max: required(note.freq_max, "freq_max")?,
+            name,
+        }),
+        (NoteKind::Velocity, Some(FieldType::F32)) => Ok(NoteInputConfig::Velocity { name }),
+        (NoteKind::Gate, Some(FieldType::Bool)) => Ok(NoteInputConfig::Gate { name }),
+        (NoteKind::Trigger, Some(FieldType::Bool)) => Ok(NoteInputConfig::Trigger { name }),
+        (_, Some(_)) => {
+            anyhow::bail!("Type error at keys.{}.note: field type mismatch", name)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::define_input;
+    use crate::input::StaticInput;
+
+    define_input! {
+        TestInput {
+            freq: f32,
+            gate: bool,
+            level: f32,
+        }
+    }
+
+    #[test]
+    fn test_parse_config() {
+        let config = parse_config(
+            r#"
+            [device]
+            default = { input = "in", output = "out" }
+            [rack]
+            name = "Test"
+            [notes]
+            priority = "high"
+            [poly]
+            voices = 4
+            stealing = "same_note"
+            [keys]
+            freq = { note = "pitch", freq_min = 100.0, freq_max = 1000.0 }
+            gate = { key = 0x20, mode = "toggle", out = 0x20 }
+            level = { key = 0x00, min = 0.001, max = 4.0, curve = "exp", invert = true }
+            [smoothing]
+            freq = { time = 0.01 }
+            level = { mode = "linear", time = 0.05 }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.midi_in_name.as_deref(), Some("in"));
+        assert_eq!(config.midi_out_name.as_deref(), Some("out"));
+        assert_eq!(config.note_priority, NotePriority::High);
+        assert_eq!(
+            config.poly.as_ref().unwrap().stealing,
+            VoiceStealing::SameNote
+        );
+        assert_eq!(config.smoothing["freq"], Smoothing::OnePole(0.01));
+        assert_eq!(config.smoothing["level"], Smoothing::Linear(0.05));
+        let (mut state_in, mut state_out) = TestInput::new_state_definition().into_io();
+        setup_state_io(&config, &mut state_in, &mut state_out).unwrap();
+        assert!(validate(&config, state_in.state_definition()).is_empty());
+        let mut input = TestInput::default();
+        state_in.update_state(&mut input, Key::ControlChange(0x00), 0);
+        assert!((input.level - 4.0).abs() < 1e-4);
+        state_in.update_state(&mut input, Key::ControlChange(0x00), 127);
+        assert!((input.level - 0.001).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = |src: &str| format!("{:#}", parse_config(src).err().unwrap());
+        assert_eq!(
+            error("[rack]\nname = \"Test\"\n\n[device]\ninput = \"in\"\n"),
+            "unknown field `input`, expected `default` for key `device` at line 4 column 1"
+        );
+        assert_eq!(
+            error("[rack]\nname = 1\n"),
+            "invalid type: integer `1`, expected a string for key `rack.name` at line 2 column 8"
+        );
+        assert_eq!(
+            error("[rack]\nname = \"Test\"\n[keys]\nlevel = 0x100\n"),
+            "invalid value: integer `256`, expected a CC number for key `keys.level` at line 4 column 9"
+        );
+        assert_eq!(
+            error("[rack]\nname = \"Test\"\n[keys]\nlevel = 0x80\n"),
+            "invalid value: integer `128`, expected a CC number for key `keys.level` at line 4 column 9"
+        );
+        assert_eq!(
+            error("[rack]\nname = \"Test\"\n[keys]\nlevel = { key = 0x80 }\n"),
+            "invalid value: integer `128`, expected a CC number for key `keys.level` at line 4 column 17"
+        );
+        assert!(error(
+            "[rack]\nname = \"Test\"\n[presets]\nslots = [{ file = \"a.toml\", save = 0x80 }]\n"
+        )
+        .starts_with("invalid value: integer `128`, expected a CC number"));
+        // errors inside a mapping keep their message and position
+        assert_eq!(
+            error("[rack]\nname = \"Test\"\n[keys]\nfreq = 0x01\nlevel = { key = 0x00, curve = \"expo\" }\n"),
+            "unknown variant `expo`, expected `linear` or `exp` for key `keys.level` at line 5 column 9"
+        );
+        assert!(
+            error("[rack]\nname = \"Test\"\n[keys]\nlevel = { key = 0x00, curv = \"exp\" }\n")
+                .starts_with("unknown field `curv`, expected one of")
+        );
+        assert!(error(
+            "[rack]\nname = \"Test\"\n[keys]\nlevel = { key = 0x00, mode = \"toggle\", min = 1.0 }\n"
+        )
+        .starts_with("field `min` is not allowed in a button mapping"));
+
+        let config = parse_config(
+            "[rack]\nname = \"Test\"\n[keys]\nlevel = { key = 0x00, mode = \"toggle\" }\n",
+        );
+        let (mut state_in, mut state_out) = TestInput::new_state_definition().into_io();
+        assert_eq!(
+            format!(
+                "{:#}",
+                setup_state_io(&config.unwrap(), &mut state_in, &mut state_out)
+                    .err()
+                    .unwrap()
+            ),
+            "Type error at keys.level: mapping doesn't match the field type F32"
+        );
+        let config = parse_config(
+            "[rack]\nname = \"Test\"\n[keys]\nlevel = { key = 0x00, max = 2.0, curve = \"exp\" }\n",
+        );
+        let (mut state_in, mut state_out) = TestInput::new_state_definition().into_io();
+        assert_eq!(
+            format!(
+                "{:#}",
+                setup_state_io(&config.unwrap(), &mut state_in, &mut state_out)
+                    .err()
+                    .unwrap()
+            ),
+            "keys.level: exp curve requires positive min and max"
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        let config = parse_config(
+            r#"
+            [rack]
+            name = "Test"
+            [presets]
+            slots = [{ file = "preset.toml", recall = 0x20 }]
+            [keys]
+            gate = { key = 0x20, mode = "toggle", out = 0x20 }
+            level = 0x20
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            validate(&config, &TestInput::new_state_definition()),
+            &[
+                "Field not mapped: freq",
+                "CC 0x20 is mapped to multiple inputs: keys.gate, keys.level, presets.slots[0].recall",
+            ]
+        );
+    }
+}
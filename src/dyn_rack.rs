@@ -0,0 +1,1496 @@
+use crate::config::PatchConfig;
+use crate::input::{FieldAccessor, Input, StateDefinition};
+use crate::module::{
+    Buf, Delay, Envelope, EnvelopeMode, In, Interpolation, LadderLPF, Mixer, MixerChannel, Module,
+    Pan, ProcessContext, Rack, Reverb, Segment, Step, StepSequencer, StereoBuf, EG, IIRLPF,
+    MAX_STEPS, SVF, VCO,
+};
+use crate::{Division, SimpleEnum, TriState, WaveForm};
+use anyhow::{Context, Result};
+use std::cell::{Cell, RefCell};
+
+type Table = toml::map::Map<String, toml::value::Value>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DynValue {
+    F32(f32),
+    Bool(bool),
+    Enum(&'static str),
+}
+impl DynValue {
+    /// bool is 0.0 or 1.0
+    fn as_f32(&self) -> f32 {
+        match self {
+            DynValue::F32(x) => *x,
+            DynValue::Bool(true) => 1.0,
+            DynValue::Bool(false) => 0.0,
+            DynValue::Enum(_) => 0.0,
+        }
+    }
+    fn as_bool(&self) -> bool {
+        match self {
+            DynValue::Bool(x) => *x,
+            _ => false,
+        }
+    }
+    fn as_enum(&self) -> &'static str {
+        match self {
+            DynValue::Enum(x) => x,
+            _ => "",
+        }
+    }
+}
+
+/// Input of `DynRack`. The fields are defined by the [inputs] section of the config.
+#[derive(Debug, Default)]
+pub struct DynInput {
+    pub values: Vec<DynValue>,
+}
+impl Clone for DynInput {
+    fn clone(&self) -> Self {
+        DynInput {
+            values: self.values.clone(),
+        }
+    }
+    /// Reuses the buffer, so that the audio thread doesn't allocate
+    fn clone_from(&mut self, source: &Self) {
+        self.values.clone_from(&source.values);
+    }
+}
+impl Input for DynInput {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FieldKind {
+    F32,
+    Bool,
+    WaveForm,
+    TriState,
+    Division,
+}
+
+#[derive(Debug)]
+struct FieldDef {
+    name: String,
+    kind: FieldKind,
+    default: DynValue,
+}
+fn enum_default<E: SimpleEnum + Default>(
+    name: &str,
+    value: Option<&toml::value::Value>,
+) -> Result<DynValue> {
+    match value {
+        None => Ok(DynValue::Enum(E::default().to_name())),
+        Some(v) => {
+            let v = v
+                .as_str()
+                .with_context(|| format!("Type error at inputs.{}.default", name))?;
+            let e = E::from_name(v)
+                .with_context(|| format!("Invalid value at inputs.{}.default: {}", name, v))?;
+            Ok(DynValue::Enum(e.to_name()))
+        }
+    }
+}
+impl FieldDef {
+    fn parse(name: &str, value: &toml::value::Value) -> Result<FieldDef> {
+        use toml::value::Value;
+        let (kind, default) = match value {
+            Value::Float(x) => (FieldKind::F32, DynValue::F32(*x as f32)),
+            Value::Integer(x) => (FieldKind::F32, DynValue::F32(*x as f32)),
+            Value::Boolean(x) => (FieldKind::Bool, DynValue::Bool(*x)),
+            Value::Table(t) => {
+                let ty = t
+                    .get("type")
+                    .and_then(|x| x.as_str())
+                    .with_context(|| format!("inputs.{}.type required", name))?;
+                let default = t.get("default");
+                match ty {
+                    "f32" => (
+                        FieldKind::F32,
+                        DynValue::F32(
+                            default
+                                .map(|x| x.as_float().or_else(|| x.as_integer().map(|x| x as f64)))
+                                .unwrap_or(Some(0.0))
+                                .with_context(|| format!("Type error at inputs.{}.default", name))?
+                                as f32,
+                        ),
+                    ),
+                    "bool" => (
+                        FieldKind::Bool,
+                        DynValue::Bool(
+                            default
+                                .map(|x| x.as_bool())
+                                .unwrap_or(Some(false))
+                                .with_context(|| {
+                                    format!("Type error at inputs.{}.default", name)
+                                })?,
+                        ),
+                    ),
+                    "WaveForm" => (
+                        FieldKind::WaveForm,
+                        enum_default::<WaveForm>(name, default)?,
+                    ),
+                    "Division" => (
+                        FieldKind::Division,
+                        enum_default::<Division>(name, default)?,
+                    ),
+                    "TriState" => (
+                        FieldKind::TriState,
+                        enum_default::<TriState>(name, default)?,
+                    ),
+                    _ => anyhow::bail!("Invalid type at inputs.{}.type: {}", name, ty),
+                }
+            }
+            _ => anyhow::bail!("Type error at inputs.{}", name),
+        };
+        Ok(FieldDef {
+            name: name.to_owned(),
+            kind,
+            default,
+        })
+    }
+    fn accessor(&self, i: usize) -> FieldAccessor<DynInput> {
+        fn enum_accessor<E: SimpleEnum>(i: usize) -> FieldAccessor<DynInput> {
+            FieldAccessor::Enum(
+                Box::new(move |s: &DynInput| s.values[i].as_enum()),
+                Box::new(move |s: &mut DynInput, v: &str| {
+                    if let Some(e) = E::from_name(v) {
+                        s.values[i] = DynValue::Enum(e.to_name());
+                    }
+                }),
+            )
+        }
+        match self.kind {
+            FieldKind::F32 => FieldAccessor::F32(
+                Box::new(move |s: &DynInput| s.values[i].as_f32()),
+                Box::new(move |s: &mut DynInput, v| s.values[i] = DynValue::F32(v)),
+            ),
+            FieldKind::Bool => FieldAccessor::Bool(
+                Box::new(move |s: &DynInput| s.values[i].as_bool()),
+                Box::new(move |s: &mut DynInput, v| s.values[i] = DynValue::Bool(v)),
+            ),
+            FieldKind::WaveForm => enum_accessor::<WaveForm>(i),
+            FieldKind::TriState => enum_accessor::<TriState>(i),
+            FieldKind::Division => enum_accessor::<Division>(i),
+        }
+    }
+}
+
+/// Arithmetic expression over input fields and module outputs
+#[derive(Debug)]
+enum Expr {
+    Const(f32),
+    Field(usize),
+    /// (module, port)
+    Port(usize, usize),
+    Neg(Box<Expr>),
+    Add(Box<Expr>, Box<Expr>),
+    Sub(Box<Expr>, Box<Expr>),
+    Mul(Box<Expr>, Box<Expr>),
+    Div(Box<Expr>, Box<Expr>),
+}
+impl Expr {
+    fn eval(&self, rack: &DynRack, input: &DynInput) -> f32 {
+        match self {
+            Expr::Const(x) => *x,
+            Expr::Field(i) => input.values[*i].as_f32(),
+            Expr::Port(m, p) => rack.modules[*m].borrow().output(*p),
+            Expr::Neg(x) => -x.eval(rack, input),
+            Expr::Add(a, b) => a.eval(rack, input) + b.eval(rack, input),
+            Expr::Sub(a, b) => a.eval(rack, input) - b.eval(rack, input),
+            Expr::Mul(a, b) => a.eval(rack, input) * b.eval(rack, input),
+            Expr::Div(a, b) => a.eval(rack, input) / b.eval(rack, input),
+        }
+    }
+}
+
+/// Names visible from expressions
+struct Scope<'a> {
+    fields: &'a [FieldDef],
+    /// (name, type)
+    modules: &'a [(String, String)],
+    /// Module that owns the expression. It can't refer to its own outputs.
+    current: Option<usize>,
+}
+impl<'a> Scope<'a> {
+    fn field(&self, name: &str) -> Option<(usize, FieldKind)> {
+        self.fields
+            .iter()
+            .position(|f| f.name == name)
+            .map(|i| (i, self.fields[i].kind))
+    }
+    fn resolve(&self, name: &str) -> Result<Expr> {
+        if let Some((module, port)) = name.split_once('.') {
+            let m = self
+                .modules
+                .iter()
+                .position(|(n, _)| n == module)
+                .with_context(|| format!("Undefined module: {}", module))?;
+            if Some(m) == self.current {
+                anyhow::bail!("Module can't refer to its own output: {}", name);
+            }
+            let p = port_names(&self.modules[m].1)
+                .iter()
+                .position(|p| *p == port)
+                .with_context(|| format!("Undefined port: {}", name))?;
+            Ok(Expr::Port(m, p))
+        } else {
+            match self.field(name) {
+                Some((i, FieldKind::F32 | FieldKind::Bool)) => Ok(Expr::Field(i)),
+                Some(_) => anyhow::bail!("Enum field can't be used in expression: {}", name),
+                None => anyhow::bail!("Undefined input field: {}", name),
+            }
+        }
+    }
+}
+
+/// expr := term (('+' | '-') term)*
+/// term := unary (('*' | '/') unary)*
+/// unary := '-' unary | atom
+/// atom := number | name | name '.' port | '(' expr ')'
+struct Parser<'a> {
+    src: &'a str,
+    pos: usize,
+    scope: &'a Scope<'a>,
+}
+impl<'a> Parser<'a> {
+    fn parse(src: &'a str, scope: &'a Scope<'a>) -> Result<Expr> {
+        let mut parser = Parser { src, pos: 0, scope };
+        let expr = parser.expr()?;
+        parser.skip_ws();
+        if parser.pos < src.len() {
+            return Err(parser.error("Unexpected character"));
+        }
+        Ok(expr)
+    }
+    fn error(&self, msg: &str) -> anyhow::Error {
+        anyhow::anyhow!("{} at {} in \"{}\"", msg, self.pos, self.src)
+    }
+    fn skip_ws(&mut self) {
+        while self.peek().is_some_and(|c| c.is_whitespace()) {
+            self.pos += 1;
+        }
+    }
+    fn peek(&self) -> Option<char> {
+        self.src[self.pos..].chars().next()
+    }
+    fn eat(&mut self, c: char) -> bool {
+        self.skip_ws();
+        if self.peek() == Some(c) {
+            self.pos += c.len_utf8();
+            true
+        } else {
+            false
+        }
+    }
+    fn expr(&mut self) -> Result<Expr> {
+        let mut lhs = self.term()?;
+        loop {
+            if self.eat('+') {
+                lhs = Expr::Add(Box::new(lhs), Box::new(self.term()?));
+            } else if self.eat('-') {
+                lhs = Expr::Sub(Box::new(lhs), Box::new(self.term()?));
+            } else {
+                return Ok(lhs);
+            }
+        }
+    }
+    fn term(&mut self) -> Result<Expr> {
+        let mut lhs = self.unary()?;
+        loop {
+            if self.eat('*') {
+                lhs = Expr::Mul(Box::new(lhs), Box::new(self.unary()?));
+            } else if self.eat('/') {
+                lhs = Expr::Div(Box::new(lhs), Box::new(self.unary()?));
+            } else {
+                return Ok(lhs);
+            }
+        }
+    }
+    fn unary(&mut self) -> Result<Expr> {
+        if self.eat('-') {
+            Ok(Expr::Neg(Box::new(self.unary()?)))
+        } else {
+            self.atom()
+        }
+    }
+    fn atom(&mut self) -> Result<Expr> {
+        if self.eat('(') {
+            let e = self.expr()?;
+            if !self.eat(')') {
+                return Err(self.error("')' expected"));
+            }
+            return Ok(e);
+        }
+        self.skip_ws();
+        let start = self.pos;
+        match self.peek() {
+            Some(c) if c.is_ascii_digit() || c == '.' => {
+                while self
+                    .peek()
+                    .is_some_and(|c| c.is_ascii_digit() || c == '.' || c == '_')
+                {
+                    self.pos += 1;
+                }
+                let s = self.src[start..self.pos].replace('_', "");
+                s.parse::<f32>()
+                    .map(Expr::Const)
+                    .map_err(|_| self.error("Invalid number"))
+            }
+            Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+                while self
+                    .peek()
+                    .is_some_and(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+                {
+                    self.pos += 1;
+                }
+                self.scope.resolve(&self.src[start..self.pos])
+            }
+            _ => Err(self.error("Value expected")),
+        }
+    }
+}
+
+pub enum DynModule {
+    VCO(VCO<DynRack>),
+    EG(EG<DynRack>),
+    IIRLPF(IIRLPF<DynRack>),
+    SVF(SVF<DynRack>),
+    LadderLPF(LadderLPF<DynRack>),
+    Envelope(Envelope<DynRack>),
+    Delay(Delay<DynRack>),
+    Reverb(Box<Reverb<DynRack>>),
+    Buf(Buf<DynRack>),
+    StereoBuf(StereoBuf<DynRack>),
+    Pan(Pan<DynRack>),
+    Mixer(Mixer<DynRack>),
+    StepSequencer(StepSequencer<DynRack>),
+}
+/// Output ports of the module type, or empty if the type is unknown
+fn port_names(module_type: &str) -> &'static [&'static str] {
+    match module_type {
+        "VCO" => &["out", "out_wrap", "out_sync"],
+        "EG" | "IIRLPF" | "LadderLPF" | "Delay" | "Reverb" | "Buf" => &["out"],
+        "SVF" => &["out_lp", "out_hp", "out_bp", "out_notch"],
+        "Envelope" => &["out", "out_eoc"],
+        "StereoBuf" | "Pan" => &["out_l", "out_r"],
+        "Mixer" => &["out", "out_l", "out_r"],
+        "StepSequencer" => &["out_pitch", "out_gate", "out_accent", "out_trigger"],
+        _ => &[],
+    }
+}
+impl DynModule {
+    fn output(&self, port: usize) -> f32 {
+        match (self, port) {
+            (DynModule::VCO(m), 0) => m.out,
+            (DynModule::VCO(m), 1) => m.out_wrap,
+            (DynModule::VCO(m), 2) => m.out_sync,
+            (DynModule::EG(m), 0) => m.out,
+            (DynModule::IIRLPF(m), 0) => m.out,
+            (DynModule::LadderLPF(m), 0) => m.out,
+            (DynModule::Delay(m), 0) => m.out,
+            (DynModule::Reverb(m), 0) => m.out,
+            (DynModule::Envelope(m), 0) => m.out,
+            (DynModule::Envelope(m), 1) => m.out_eoc as u8 as f32,
+            (DynModule::SVF(m), 0) => m.out_lp,
+            (DynModule::SVF(m), 1) => m.out_hp,
+            (DynModule::SVF(m), 2) => m.out_bp,
+            (DynModule::SVF(m), 3) => m.out_notch,
+            (DynModule::Buf(m), 0) => m.out,
+            (DynModule::StereoBuf(m), 0) => m.out_l,
+            (DynModule::StereoBuf(m), 1) => m.out_r,
+            (DynModule::Pan(m), 0) => m.out_l,
+            (DynModule::Pan(m), 1) => m.out_r,
+            (DynModule::Mixer(m), 0) => m.out,
+            (DynModule::Mixer(m), 1) => m.out_l,
+            (DynModule::Mixer(m), 2) => m.out_r,
+            (DynModule::StepSequencer(m), 0) => m.out_pitch,
+            (DynModule::StepSequencer(m), 1) => m.out_gate as u8 as f32,
+            (DynModule::StepSequencer(m), 2) => m.out_accent as u8 as f32,
+            (DynModule::StepSequencer(m), 3) => m.out_trigger as u8 as f32,
+            _ => panic!("Undefined port: {}", port),
+        }
+    }
+}
+impl Module<DynRack> for DynModule {
+    fn update(&mut self, rack: &DynRack, input: &DynInput, ctx: &ProcessContext) {
+        match self {
+            DynModule::VCO(m) => m.update(rack, input, ctx),
+            DynModule::EG(m) => m.update(rack, input, ctx),
+            DynModule::IIRLPF(m) => m.update(rack, input, ctx),
+            DynModule::SVF(m) => m.update(rack, input, ctx),
+            DynModule::LadderLPF(m) => m.update(rack, input, ctx),
+            DynModule::Envelope(m) => m.update(rack, input, ctx),
+            DynModule::Delay(m) => m.update(rack, input, ctx),
+            DynModule::Reverb(m) => m.update(rack, input, ctx),
+            DynModule::Buf(m) => m.update(rack, input, ctx),
+            DynModule::StereoBuf(m) => m.update(rack, input, ctx),
+            DynModule::Pan(m) => m.update(rack, input, ctx),
+            DynModule::Mixer(m) => m.update(rack, input, ctx),
+            DynModule::StepSequencer(m) => m.update(rack, input, ctx),
+        }
+    }
+}
+
+/// Parameters and input expressions of a module being built
+struct ModuleBuilder<'a> {
+    name: &'a str,
+    params: &'a Table,
+    patch: Table,
+    scope: Scope<'a>,
+}
+impl<'a> ModuleBuilder<'a> {
+    fn param_f32(&self, key: &str) -> Result<Option<f32>> {
+        match self.params.get(key) {
+            None => Ok(None),
+            Some(v) => v
+                .as_float()
+                .or_else(|| v.as_integer().map(|x| x as f64))
+                .map(|x| Some(x as f32))
+                .with_context(|| format!("Type error at modules.{}.{}", self.name, key)),
+        }
+    }
+    fn param_bool(&self, key: &str) -> Result<Option<bool>> {
+        match self.params.get(key) {
+            None => Ok(None),
+            Some(v) => v
+                .as_bool()
+                .map(Some)
+                .with_context(|| format!("Type error at modules.{}.{}", self.name, key)),
+        }
+    }
+    fn param_index(&self, key: &str) -> Result<Option<usize>> {
+        match self.params.get(key) {
+            None => Ok(None),
+            Some(v) => v
+                .as_integer()
+                .filter(|x| *x >= 0)
+                .map(|x| Some(x as usize))
+                .with_context(|| format!("Type error at modules.{}.{}", self.name, key)),
+        }
+    }
+    /// Number or expression in the module parameters
+    fn param_f32_in(&self, path: &str, value: &toml::value::Value) -> Result<In<DynRack, f32>> {
+        let src = match value {
+            toml::value::Value::String(s) => s.clone(),
+            toml::value::Value::Float(x) => x.to_string(),
+            toml::value::Value::Integer(x) => x.to_string(),
+            _ => anyhow::bail!("Type error at modules.{}.{}", self.name, path),
+        };
+        let e = Parser::parse(&src, &self.scope)
+            .with_context(|| format!("Invalid expression at modules.{}.{}", self.name, path))?;
+        Ok(Box::new(move |rack, input| e.eval(rack, input)))
+    }
+    fn segments(&self) -> Result<Vec<Segment<DynRack>>> {
+        let segments = match self.params.get("segments") {
+            None => return Ok(Vec::new()),
+            Some(x) => x
+                .as_array()
+                .with_context(|| format!("Type error at modules.{}.segments", self.name))?,
+        };
+        segments
+            .iter()
+            .enumerate()
+            .map(|(i, segment)| {
+                let value = |key: &str| -> Result<In<DynRack, f32>> {
+                    let path = format!("segments[{}].{}", i, key);
+                    match segment.get(key) {
+                        Some(v) => self.param_f32_in(&path, v),
+                        None if key == "curve" => Ok(Box::new(|_, _| 0.0)),
+                        None => anyhow::bail!("modules.{}.{} is not defined", self.name, path),
+                    }
+                };
+                Ok(Segment {
+                    in_level: value("level")?,
+                    in_time: value("time")?,
+                    in_curve: value("curve")?,
+                })
+            })
+            .collect()
+    }
+    /// Array parameter such as channels = [{ ... }, ...]
+    fn param_array(&self, key: &str) -> Result<&[toml::value::Value]> {
+        match self.params.get(key) {
+            None => Ok(&[]),
+            Some(x) => x
+                .as_array()
+                .map(|x| x.as_slice())
+                .with_context(|| format!("Type error at modules.{}.{}", self.name, key)),
+        }
+    }
+    /// Number or expression at `key` of the `i`th item of the array parameter
+    fn item_f32_in(
+        &self,
+        array: &str,
+        i: usize,
+        item: &toml::value::Value,
+        key: &str,
+    ) -> Result<Option<In<DynRack, f32>>> {
+        match item.get(key) {
+            Some(v) => self
+                .param_f32_in(&format!("{}[{}].{}", array, i, key), v)
+                .map(Some),
+            None => Ok(None),
+        }
+    }
+    /// true if the value >= 0.5
+    fn item_bool_in(
+        &self,
+        array: &str,
+        i: usize,
+        item: &toml::value::Value,
+        key: &str,
+    ) -> Result<Option<In<DynRack, bool>>> {
+        Ok(self
+            .item_f32_in(array, i, item, key)?
+            .map(|x| -> In<DynRack, bool> { Box::new(move |rack, input| x(rack, input) >= 0.5) }))
+    }
+    fn mixer_channels(&self) -> Result<Vec<MixerChannel<DynRack>>> {
+        let mut channels = Vec::new();
+        for (i, item) in self.param_array("channels")?.iter().enumerate() {
+            let value = self
+                .item_f32_in("channels", i, item, "value")?
+                .with_context(|| {
+                    format!("modules.{}.channels[{}].value is not defined", self.name, i)
+                })?;
+            let mut c = MixerChannel::new(value);
+            if let Some(x) = self.item_f32_in("channels", i, item, "gain")? {
+                c.in_gain = x;
+            }
+            if let Some(x) = self.item_f32_in("channels", i, item, "pan")? {
+                c.in_pan = x;
+            }
+            if let Some(x) = self.item_bool_in("channels", i, item, "mute")? {
+                c.in_mute = x;
+            }
+            if let Some(x) = self.item_bool_in("channels", i, item, "solo")? {
+                c.in_solo = x;
+            }
+            channels.push(c);
+        }
+        Ok(channels)
+    }
+    /// Gate is on if omitted
+    fn sequencer_steps(&self) -> Result<Vec<Step<DynRack>>> {
+        let items = self.param_array("steps")?;
+        if MAX_STEPS < items.len() {
+            anyhow::bail!(
+                "Too many steps at modules.{}.steps: max {}",
+                self.name,
+                MAX_STEPS
+            );
+        }
+        let mut steps = Vec::new();
+        for (i, item) in items.iter().enumerate() {
+            let pitch = self
+                .item_f32_in("steps", i, item, "pitch")?
+                .with_context(|| {
+                    format!("modules.{}.steps[{}].pitch is not defined", self.name, i)
+                })?;
+            let mut step = Step::new(pitch, |_, _| true, |_, _| false, |_, _| false);
+            if let Some(x) = self.item_bool_in("steps", i, item, "gate")? {
+                step.in_gate = x;
+            }
+            if let Some(x) = self.item_bool_in("steps", i, item, "accent")? {
+                step.in_accent = x;
+            }
+            if let Some(x) = self.item_bool_in("steps", i, item, "slide")? {
+                step.in_slide = x;
+            }
+            steps.push(step);
+        }
+        Ok(steps)
+    }
+    fn take_source(&mut self, key: &str) -> Result<Option<String>> {
+        match self.patch.remove(key) {
+            None => Ok(None),
+            Some(toml::value::Value::String(s)) => Ok(Some(s)),
+            Some(toml::value::Value::Float(x)) => Ok(Some(x.to_string())),
+            Some(toml::value::Value::Integer(x)) => Ok(Some(x.to_string())),
+            Some(_) => anyhow::bail!("Type error at patch.{}.{}", self.name, key),
+        }
+    }
+    fn expr(&self, key: &str, src: &str) -> Result<Expr> {
+        Parser::parse(src, &self.scope)
+            .with_context(|| format!("Invalid expression at patch.{}.{}", self.name, key))
+    }
+    fn in_f32(&mut self, key: &str) -> Result<Option<In<DynRack, f32>>> {
+        match self.take_source(key)? {
+            None => Ok(None),
+            Some(src) => {
+                let e = self.expr(key, &src)?;
+                Ok(Some(Box::new(move |rack, input| e.eval(rack, input))))
+            }
+        }
+    }
+    /// true if the value >= 0.5
+    fn in_bool(&mut self, key: &str) -> Result<Option<In<DynRack, bool>>> {
+        match self.take_source(key)? {
+            None => Ok(None),
+            Some(src) => {
+                let e = self.expr(key, &src)?;
+                Ok(Some(Box::new(move |rack, input| {
+                    e.eval(rack, input) >= 0.5
+                })))
+            }
+        }
+    }
+    /// Name of an enum field of `kind` or a value of `E`
+    fn in_enum<E: SimpleEnum + Default + Copy + Send + 'static>(
+        &mut self,
+        key: &str,
+        kind: FieldKind,
+        type_name: &str,
+    ) -> Result<Option<In<DynRack, E>>> {
+        match self.take_source(key)? {
+            None => Ok(None),
+            Some(src) => {
+                let src = src.trim();
+                if let Some(value) = E::from_name(src) {
+                    return Ok(Some(Box::new(move |_, _| value)));
+                }
+                match self.scope.field(src) {
+                    Some((i, k)) if k == kind => Ok(Some(Box::new(move |_, input| {
+                        E::from_name(input.values[i].as_enum()).unwrap_or_default()
+                    }))),
+                    _ => anyhow::bail!(
+                        "{} field or value required at patch.{}.{}: {}",
+                        type_name,
+                        self.name,
+                        key,
+                        src
+                    ),
+                }
+            }
+        }
+    }
+    fn check_unused(&self, known_params: &[&str]) -> Result<()> {
+        for key in self.params.keys() {
+            if key != "name" && key != "type" && !known_params.contains(&key.as_str()) {
+                anyhow::bail!("Unknown parameter: modules.{}.{}", self.name, key);
+            }
+        }
+        if let Some(key) = self.patch.keys().next() {
+            anyhow::bail!("Unknown input: patch.{}.{}", self.name, key);
+        }
+        Ok(())
+    }
+}
+fn build_module(module_type: &str, b: &mut ModuleBuilder) -> Result<DynModule> {
+    match module_type {
+        "VCO" => {
+            let mut m = VCO::default();
+            if let Some(x) = b.in_f32("in_freq")? {
+                m.in_freq = x;
+            }
+            if let Some(x) =
+                b.in_enum::<WaveForm>("in_waveform", FieldKind::WaveForm, "WaveForm")?
+            {
+                m.in_waveform = x;
+            }
+            if let Some(x) = b.in_f32("in_sync")? {
+                m.in_sync = x;
+            }
+            if let Some(x) = b.in_f32("in_pulse_width")? {
+                m.in_pulse_width = x;
+            }
+            if let Some(x) =
+                b.in_enum::<Division>("in_division", FieldKind::Division, "Division")?
+            {
+                m.in_division = x;
+            }
+            if let Some(x) = b.param_f32("freq_min")? {
+                m.freq_min = x;
+            }
+            if let Some(x) = b.param_f32("freq_max")? {
+                m.freq_max = x;
+            }
+            if let Some(x) = b.param_bool("band_limited")? {
+                m.band_limited = x;
+            }
+            b.check_unused(&["freq_min", "freq_max", "band_limited"])?;
+            Ok(DynModule::VCO(m))
+        }
+        "EG" => {
+            let mut m = EG::default();
+            if let Some(x) = b.in_bool("in_gate")? {
+                m.in_gate = x;
+            }
+            if let Some(x) = b.in_bool("in_repeat")? {
+                m.in_repeat = x;
+            }
+            if let Some(x) = b.in_bool("in_trigger")? {
+                m.in_trigger = x;
+            }
+            if let Some(x) = b.in_f32("in_a")? {
+                m.in_a = x;
+            }
+            if let Some(x) = b.in_f32("in_d")? {
+                m.in_d = x;
+            }
+            if let Some(x) = b.in_f32("in_s")? {
+                m.in_s = x;
+            }
+            if let Some(x) = b.in_f32("in_r")? {
+                m.in_r = x;
+            }
+            if let Some(x) = b.in_f32("in_a_curve")? {
+                m.in_a_curve = x;
+            }
+            if let Some(x) = b.in_f32("in_d_curve")? {
+                m.in_d_curve = x;
+            }
+            if let Some(x) = b.in_f32("in_r_curve")? {
+                m.in_r_curve = x;
+            }
+            b.check_unused(&[])?;
+            Ok(DynModule::EG(m))
+        }
+        "IIRLPF" => {
+            let mut m = IIRLPF::default();
+            if let Some(x) = b.in_f32("in_freq")? {
+                m.in_freq = x;
+            }
+            if let Some(x) = b.in_f32("in_resonance")? {
+                m.in_resonance = x;
+            }
+            if let Some(x) = b.in_f32("in_value")? {
+                m.in_value = x;
+            }
+            if let Some(x) = b.param_f32("freq_min")? {
+                m.freq_min = x;
+            }
+            if let Some(x) = b.param_f32("freq_max")? {
+                m.freq_max = x;
+            }
+            b.check_unused(&["freq_min", "freq_max"])?;
+            Ok(DynModule::IIRLPF(m))
+        }
+        "SVF" => {
+            let mut m = SVF::default();
+            if let Some(x) = b.in_f32("in_freq")? {
+                m.in_freq = x;
+            }
+            if let Some(x) = b.in_f32("in_resonance")? {
+                m.in_resonance = x;
+            }
+            if let Some(x) = b.in_f32("in_value")? {
+                m.in_value = x;
+            }
+            if let Some(x) = b.param_f32("freq_min")? {
+                m.freq_min = x;
+            }
+            if let Some(x) = b.param_f32("freq_max")? {
+                m.freq_max = x;
+            }
+            b.check_unused(&["freq_min", "freq_max"])?;
+            Ok(DynModule::SVF(m))
+        }
+        "LadderLPF" => {
+            let mut m = LadderLPF::default();
+            if let Some(x) = b.in_f32("in_freq")? {
+                m.in_freq = x;
+            }
+            if let Some(x) = b.in_f32("in_resonance")? {
+                m.in_resonance = x;
+            }
+            if let Some(x) = b.in_f32("in_drive")? {
+                m.in_drive = x;
+            }
+            if let Some(x) = b.in_f32("in_value")? {
+                m.in_value = x;
+            }
+            if let Some(x) = b.param_f32("freq_min")? {
+                m.freq_min = x;
+            }
+            if let Some(x) = b.param_f32("freq_max")? {
+                m.freq_max = x;
+            }
+            b.check_unused(&["freq_min", "freq_max"])?;
+            Ok(DynModule::LadderLPF(m))
+        }
+        "Envelope" => {
+            let mut m = Envelope::default();
+            if let Some(x) = b.in_bool("in_gate")? {
+                m.in_gate = x;
+            }
+            if let Some(x) = b.in_bool("in_trigger")? {
+                m.in_trigger = x;
+            }
+            if let Some(x) = b.in_f32("in_delay")? {
+                m.in_delay = x;
+            }
+            m.segments = b.segments()?;
+            if let Some(x) = b.params.get("mode") {
+                m.mode = match x.as_str() {
+                    Some("one_shot") => EnvelopeMode::OneShot,
+                    Some("gated") => EnvelopeMode::Gated,
+                    _ => anyhow::bail!("Invalid value at modules.{}.mode", b.name),
+                };
+            }
+            m.sustain = b.param_index("sustain")?;
+            m.loop_segments = match (b.param_index("loop_start")?, b.param_index("loop_end")?) {
+                (Some(start), Some(end)) => Some((start, end)),
+                (None, None) => None,
+                _ => anyhow::bail!("modules.{}: loop_start and loop_end are required", b.name),
+            };
+            let len = m.segments.len();
+            if m.sustain
+                .iter()
+                .chain(m.loop_segments.iter().flat_map(|(s, e)| [s, e]))
+                .any(|i| *i >= len)
+            {
+                anyhow::bail!("modules.{}: segment index out of range", b.name);
+            }
+            b.check_unused(&["segments", "mode", "sustain", "loop_start", "loop_end"])?;
+            Ok(DynModule::Envelope(m))
+        }
+        "Delay" => {
+            let mut m = Delay::default();
+            if let Some(x) = b.in_f32("in_value")? {
+                m.in_value = x;
+            }
+            if let Some(x) = b.in_f32("in_time")? {
+                m.in_time = x;
+            }
+            if let Some(x) =
+                b.in_enum::<Division>("in_division", FieldKind::Division, "Division")?
+            {
+                m.in_division = x;
+            }
+            if let Some(x) = b.in_f32("in_bpm")? {
+                m.in_bpm = x;
+            }
+            if let Some(x) = b.in_f32("in_feedback")? {
+                m.in_feedback = x;
+            }
+            if let Some(x) = b.in_f32("in_mix")? {
+                m.in_mix = x;
+            }
+            if let Some(x) = b.in_f32("in_damping")? {
+                m.in_damping = x;
+            }
+            if let Some(x) = b.param_f32("max_time")? {
+                if x.is_nan() || x <= 0.0 {
+                    anyhow::bail!("modules.{}.max_time must be positive", b.name);
+                }
+                m.max_time = x;
+            }
+            if let Some(x) = b.params.get("interpolation") {
+                m.interpolation = match x.as_str() {
+                    Some("linear") => Interpolation::Linear,
+                    Some("allpass") => Interpolation::Allpass,
+                    Some("cubic") => Interpolation::Cubic,
+                    _ => anyhow::bail!("Invalid value at modules.{}.interpolation", b.name),
+                };
+            }
+            b.check_unused(&["max_time", "interpolation"])?;
+            Ok(DynModule::Delay(m))
+        }
+        "Reverb" => {
+            let mut m = Reverb::default();
+            if let Some(x) = b.in_f32("in_value")? {
+                m.in_value = x;
+            }
+            if let Some(x) = b.in_f32("in_size")? {
+                m.in_size = x;
+            }
+            if let Some(x) = b.in_f32("in_damping")? {
+                m.in_damping = x;
+            }
+            if let Some(x) = b.in_f32("in_predelay")? {
+                m.in_predelay = x;
+            }
+            if let Some(x) = b.in_f32("in_mix")? {
+                m.in_mix = x;
+            }
+            if let Some(x) = b.param_f32("max_predelay")? {
+                m.max_predelay = x;
+            }
+            b.check_unused(&["max_predelay"])?;
+            Ok(DynModule::Reverb(Box::new(m)))
+        }
+        "Buf" => {
+            let mut m = Buf::default();
+            if let Some(x) = b.in_f32("in_value")? {
+                m.in_value = x;
+            }
+            b.check_unused(&[])?;
+            Ok(DynModule::Buf(m))
+        }
+        "StereoBuf" => {
+            let mut m = StereoBuf::default();
+            if let Some(x) = b.in_f32("in_l")? {
+                m.in_l = x;
+            }
+            if let Some(x) = b.in_f32("in_r")? {
+                m.in_r = x;
+            }
+            b.check_unused(&[])?;
+            Ok(DynModule::StereoBuf(m))
+        }
+        "Pan" => {
+            let mut m = Pan::default();
+            if let Some(x) = b.in_f32("in_value")? {
+                m.in_value = x;
+            }
+            if let Some(x) = b.in_f32("in_pan")? {
+                m.in_pan = x;
+            }
+            b.check_unused(&[])?;
+            Ok(DynModule::Pan(m))
+        }
+        "Mixer" => {
+            let mut m = Mixer {
+                channels: b.mixer_channels()?,
+                ..Default::default()
+            };
+            if let Some(x) = b.in_f32("in_master")? {
+                m.in_master = x;
+            }
+            if let Some(x) = b.param_bool("soft_clip")? {
+                m.soft_clip = x;
+            }
+            b.check_unused(&["channels", "soft_clip"])?;
+            Ok(DynModule::Mixer(m))
+        }
+        "StepSequencer" => {
+            let mut m = StepSequencer {
+                steps: b.sequencer_steps()?,
+                ..Default::default()
+            };
+            if let Some(x) = b.in_bool("in_run")? {
+                m.in_run = x;
+            }
+            if let Some(x) = b.in_f32("in_bpm")? {
+                m.in_bpm = x;
+            }
+            if let Some(x) =
+                b.in_enum::<Division>("in_division", FieldKind::Division, "Division")?
+            {
+                m.in_division = x;
+            }
+            if let Some(x) = b.in_f32("in_clock")? {
+                m.in_clock = x;
+            }
+            if let Some(x) = b.in_f32("in_gate_length")? {
+                m.in_gate_length = x;
+            }
+            if let Some(x) = b.in_f32("in_slide_time")? {
+                m.in_slide_time = x;
+            }
+            b.check_unused(&["steps"])?;
+            Ok(DynModule::StepSequencer(m))
+        }
+        _ => anyhow::bail!("Undefined module type: {}", module_type),
+    }
+}
+
+/// Rack built at runtime from [inputs], [[modules]] and [patch] of the config.
+pub struct DynRack {
+    fields: Vec<FieldDef>,
+    names: Vec<String>,
+    modules: Vec<RefCell<DynModule>>,
+    out: Expr,
+    out_r: Option<Expr>,
+    out_value: Cell<(f32, f32)>,
+}
+impl DynRack {
+    pub fn new(config: &PatchConfig) -> Result<DynRack> {
+        let fields = config
+            .inputs
+            .iter()
+            .map(|(name, value)| FieldDef::parse(name, value))
+            .collect::<Result<Vec<_>>>()?;
+        let mut modules = Vec::new();
+        for (i, m) in config.modules.iter().enumerate() {
+            let name = m
+                .get("name")
+                .and_then(|x| x.as_str())
+                .with_context(|| format!("modules[{}].name required", i))?;
+            let module_type = m
+                .get("type")
+                .and_then(|x| x.as_str())
+                .with_context(|| format!("modules[{}].type required", i))?;
+            if modules.iter().any(|(n, _)| n == name) {
+                anyhow::bail!("Duplicated module name: {}", name);
+            }
+            modules.push((name.to_owned(), module_type.to_owned()));
+        }
+        for name in config.patch.keys() {
+            if !modules.iter().any(|(n, _)| n == name) {
+                anyhow::bail!("Undefined module: patch.{}", name);
+            }
+        }
+        let mut built = Vec::new();
+        for (i, ((name, module_type), params)) in
+            modules.iter().zip(config.modules.iter()).enumerate()
+        {
+            let patch = match config.patch.get(name) {
+                None => Table::new(),
+                Some(x) => x
+                    .as_table()
+                    .cloned()
+                    .with_context(|| format!("Type error at patch.{}", name))?,
+            };
+            let mut builder = ModuleBuilder {
+                name,
+                params,
+                patch,
+                scope: Scope {
+                    fields: &fields,
+                    modules: &modules,
+                    current: Some(i),
+                },
+            };
+            built.push(RefCell::new(build_module(module_type, &mut builder)?));
+        }
+        let scope = Scope {
+            fields: &fields,
+            modules: &modules,
+            current: None,
+        };
+        let out = config.out.as_deref().context("rack.out is not defined")?;
+        let out = Parser::parse(out, &scope).context("Invalid expression at rack.out")?;
+        let out_r = match &config.out_r {
+            Some(x) => Some(Parser::parse(x, &scope).context("Invalid expression at rack.out_r")?),
+            None => None,
+        };
+        Ok(DynRack {
+            fields,
+            names: modules.into_iter().map(|(n, _)| n).collect(),
+            modules: built,
+            out,
+            out_r,
+            out_value: Cell::new((0.0, 0.0)),
+        })
+    }
+    pub fn module(&self, name: &str) -> Option<&RefCell<DynModule>> {
+        self.names
+            .iter()
+            .position(|n| n == name)
+            .map(|i| &self.modules[i])
+    }
+    /// Value of rack.out after the last update
+    pub fn out(&self) -> f32 {
+        self.out_value.get().0
+    }
+    /// (rack.out, rack.out_r) after the last update. Both are rack.out if out_r is not defined.
+    pub fn out_stereo(&self) -> (f32, f32) {
+        self.out_value.get()
+    }
+}
+impl Rack for DynRack {
+    type Input = DynInput;
+    fn new_input(&self) -> DynInput {
+        DynInput {
+            values: self.fields.iter().map(|f| f.default).collect(),
+        }
+    }
+    fn state_definition(&self) -> StateDefinition<DynInput> {
+        let mut definition = StateDefinition::new();
+        for (i, f) in self.fields.iter().enumerate() {
+            definition.define_field(f.name.clone(), f.accessor(i));
+        }
+        definition
+    }
+    fn update(&self, input: &DynInput, ctx: &ProcessContext) {
+        for m in self.modules.iter() {
+            m.borrow_mut().update(self, input, ctx);
+        }
+        let out = self.out.eval(self, input);
+        let out_r = match &self.out_r {
+            Some(x) => x.eval(self, input),
+            None => out,
+        };
+        self.out_value.set((out, out_r));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::render::{render, Script};
+    use crate::{define_input, define_rack};
+
+    define_input! {
+        StaticInput {
+            vco_freq: f32 = 0.3,
+            vco_waveform: WaveForm = (WaveForm::Square),
+            eg_gate: bool = true,
+            lpf_freq: f32 = 0.4,
+        }
+    }
+    define_rack! {
+        StaticRack: Rack<StaticInput>(rack, input) {
+            vco: VCO {
+                in_freq: { input.vco_freq },
+                in_waveform: { input.vco_waveform },
+                freq_min: 100.0,
+                freq_max: 1000.0,
+            },
+            eg: EG {
+                in_gate: { input.eg_gate },
+                in_a: { 0.01 },
+                in_s: { 0.5 },
+                in_r: { 0.01 },
+            },
+            lpf: IIRLPF {
+                in_freq: { input.lpf_freq },
+                in_value: { rack.vco.borrow().out * rack.eg.borrow().out * 0.5 },
+            },
+        }
+    }
+
+    const PATCH: &str = r#"
+        [rack]
+        name = "Dynamic"
+        out = "lpf.out"
+
+        [inputs]
+        vco_freq = 0.3
+        vco_waveform = { type = "WaveForm", default = "Square" }
+        eg_gate = true
+        lpf_freq = 0.4
+
+        [[modules]]
+        name = "vco"
+        type = "VCO"
+        freq_min = 100
+        freq_max = 1000.0
+
+        [[modules]]
+        name = "eg"
+        type = "EG"
+
+        [[modules]]
+        name = "lpf"
+        type = "IIRLPF"
+
+        [patch]
+        vco.in_freq = "vco_freq"
+        vco.in_waveform = "vco_waveform"
+        eg.in_gate = "eg_gate"
+        eg.in_a = 0.01
+        eg.in_s = "1 / (4 - 2)"
+        eg.in_r = "0.01"
+        lpf.in_freq = "lpf_freq"
+        lpf.in_value = "vco.out * eg.out * 0.5"
+    "#;
+
+    #[test]
+    fn test_same_as_static_rack() {
+        let config = crate::config::parse_config(PATCH).unwrap();
+        let dyn_rack = DynRack::new(&config.patch).unwrap();
+        let dyn_out = render(
+            &dyn_rack,
+            |r| r.out(),
+            &Script::new(dyn_rack.new_input()),
+            44_100,
+            4410,
+        );
+        let static_rack = StaticRack::new();
+        let static_out = render(
+            &static_rack,
+            |r| r.lpf.borrow().out,
+            &Script::new(static_rack.new_input()),
+            44_100,
+            4410,
+        );
+        assert_eq!(dyn_out, static_out);
+    }
+
+    #[test]
+    fn test_poly_smoother_reuses_buffer() {
+        use crate::poly::{Poly, VoiceStealing};
+        use crate::smoothing::{Smoother, Smoothing};
+        let config = crate::config::parse_config(PATCH).unwrap();
+        let poly = Poly::new(
+            2,
+            || DynRack::new(&config.patch).unwrap(),
+            |r| r.out(),
+            Vec::new(),
+            VoiceStealing::Oldest,
+        );
+        let mut input = poly.new_input();
+        let smoothing = [("lpf_freq".to_owned(), Smoothing::OnePole(0.01))].into();
+        let mut smoother =
+            Smoother::new(poly.state_definition(), &smoothing, 1000, input.clone()).unwrap();
+        let values = smoother.next_sample().params.values.as_ptr();
+        match poly.state_definition().field("lpf_freq") {
+            FieldAccessor::F32(_, set) => set(&mut input, 0.8),
+            _ => panic!(),
+        }
+        smoother.set_input(&input);
+        assert_eq!(smoother.next_sample().params.values.as_ptr(), values);
+    }
+
+    #[test]
+    fn test_state_definition() {
+        let config = crate::config::parse_config(PATCH).unwrap();
+        let rack = DynRack::new(&config.patch).unwrap();
+        let definition = rack.state_definition();
+        let mut input = rack.new_input();
+        match definition.field("vco_waveform") {
+            FieldAccessor::Enum(get, set) => {
+                assert_eq!(get(&input), "Square");
+                set(&mut input, "Sawtooth");
+                assert_eq!(get(&input), "Sawtooth");
+                set(&mut input, "Unknown");
+                assert_eq!(get(&input), "Sawtooth");
+            }
+            _ => panic!(),
+        }
+        match definition.field("lpf_freq") {
+            FieldAccessor::F32(get, set) => {
+                set(&mut input, 0.8);
+                assert_eq!(get(&input), 0.8);
+            }
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_envelope() {
+        let config = crate::config::parse_config(
+            r#"
+            [rack]
+            name = "Dynamic"
+            out = "env.out"
+
+            [inputs]
+            gate = false
+            release = 0.001
+
+            [[modules]]
+            name = "env"
+            type = "Envelope"
+            segments = [
+                { level = 1, time = 0.001 },
+                { level = 0.5, time = 0.001, curve = 5 },
+                { level = 0, time = "release" },
+            ]
+            sustain = 1
+
+            [patch]
+            env.in_gate = "gate"
+            "#,
+        )
+        .unwrap();
+        let rack = DynRack::new(&config.patch).unwrap();
+        let definition = rack.state_definition();
+        let script = Script::new(rack.new_input()).at(0, move |i| match definition.field("gate") {
+            FieldAccessor::Bool(_, set) => set(i, true),
+            _ => panic!(),
+        });
+        let out = render(&rack, |r| r.out(), &script, 10_000, 100);
+        assert_eq!(out[50], 0.5);
+    }
+
+    #[test]
+    fn test_stereo() {
+        let config = crate::config::parse_config(
+            r#"
+            [rack]
+            name = "Dynamic"
+            out = "pan.out_l"
+            out_r = "pan.out_r + 0.5"
+
+            [inputs]
+            pan = 0.0
+
+            [[modules]]
+            name = "pan"
+            type = "Pan"
+
+            [patch]
+            pan.in_value = "1.0"
+            pan.in_pan = "pan"
+            "#,
+        )
+        .unwrap();
+        let rack = DynRack::new(&config.patch).unwrap();
+        let script = Script::new(rack.new_input());
+        let out = render(&rack, |r| r.out_stereo(), &script, 10_000, 1);
+        assert_eq!(out, &[(1.0, 0.5)]);
+        assert_eq!(rack.out(), 1.0);
+    }
+
+    #[test]
+    fn test_mixer() {
+        let config = crate::config::parse_config(
+            r#"
+            [rack]
+            name = "Dynamic"
+            out = "mixer.out"
+            out_r = "mixer.out_r"
+
+            [inputs]
+            gain = 0.5
+            mute = false
+
+            [[modules]]
+            name = "mixer"
+            type = "Mixer"
+            channels = [
+                { value = 1, gain = "gain", pan = 1 },
+                { value = "0.25", pan = 0, mute = "mute" },
+                { value = 4, solo = 0 },
+            ]
+
+            [patch]
+            mixer.in_master = "2 * gain"
+            "#,
+        )
+        .unwrap();
+        let rack = DynRack::new(&config.patch).unwrap();
+        let script = Script::new(rack.new_input());
+        let out = render(&rack, |r| r.out_stereo(), &script, 10_000, 1);
+        assert_eq!(out[0].0, 4.75);
+        assert!((out[0].1 - (0.5 + 4.0 * std::f32::consts::FRAC_1_SQRT_2)).abs() < 1e-5);
+
+        let config = crate::config::parse_config(
+            r#"
+            [rack]
+            name = "Dynamic"
+            out = "mixer.out"
+
+            [[modules]]
+            name = "mixer"
+            type = "Mixer"
+            channels = [{ gain = 1 }]
+            "#,
+        )
+        .unwrap();
+        let error = format!("{:#}", DynRack::new(&config.patch).err().unwrap());
+        assert_eq!(error, "modules.mixer.channels[0].value is not defined");
+    }
+
+    /// Each nanoKONTROL2 group drives a channel of the mixer
+    #[test]
+    fn test_mixer_nanokontrol2() {
+        use crate::input::Key;
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/mixer-nanokontrol2.toml");
+        let config = crate::config::load_config(path).unwrap();
+        let rack = DynRack::new(&config.patch).unwrap();
+        let (mut state_in, mut state_out) = rack.state_definition().into_io();
+        crate::config::setup_state_io(&config, &mut state_in, &mut state_out).unwrap();
+        let mut send = |input: &mut DynInput, key: u8, values: &[u8]| {
+            for value in values {
+                state_in.update_state(input, Key::ControlChange(key), *value);
+            }
+        };
+        let render_input = |input: &DynInput| {
+            let rack = DynRack::new(&config.patch).unwrap();
+            render(
+                &rack,
+                |r| r.out_stereo(),
+                &Script::new(input.clone()),
+                10_000,
+                1000,
+            )
+        };
+        let silent = |out: &[(f32, f32)]| out.iter().all(|(l, r)| *l == 0.0 && *r == 0.0);
+        let press = [127, 0];
+
+        let mut input = rack.new_input();
+        send(&mut input, 0x29, &press);
+        assert!(!silent(&render_input(&input)));
+        // M mutes
+        let mut muted = input.clone();
+        for i in 0..8 {
+            send(&mut muted, 0x30 + i, &press);
+        }
+        assert!(silent(&render_input(&muted)));
+        // S solos, same as the other sliders down
+        let mut soloed = input.clone();
+        send(&mut soloed, 0x20, &press);
+        let mut faded = input.clone();
+        for i in 1..8 {
+            send(&mut faded, i, &[0]);
+        }
+        let solo_out = render_input(&soloed);
+        assert!(!silent(&solo_out));
+        assert_eq!(solo_out, render_input(&faded));
+        // knob pans
+        send(&mut soloed, 0x10, &[0]);
+        let out = render_input(&soloed);
+        assert!(out.iter().all(|(_, r)| *r == 0.0) && out.iter().any(|(l, _)| *l != 0.0));
+    }
+
+    #[test]
+    fn test_step_sequencer() {
+        let config = crate::config::parse_config(
+            r#"
+            [rack]
+            name = "Dynamic"
+            out = "seq.out_pitch * seq.out_gate"
+
+            [inputs]
+            step2_gate = false
+            division = { type = "Division", default = "Eighth" }
+
+            [[modules]]
+            name = "seq"
+            type = "StepSequencer"
+            steps = [
+                { pitch = 0.25 },
+                { pitch = 0.5, gate = "step2_gate" },
+            ]
+
+            [patch]
+            seq.in_bpm = "150"
+            seq.in_division = "division"
+            seq.in_gate_length = "1"
+            "#,
+        )
+        .unwrap();
+        let rack = DynRack::new(&config.patch).unwrap();
+        let script = Script::new(rack.new_input());
+        // 200ms per step
+        let out = render(&rack, |r| r.out(), &script, 1000, 400);
+        assert!(out[100] == 0.25 && out[300] == 0.0);
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = |from: &str, to: &str| {
+            assert!(PATCH.contains(from));
+            let config = crate::config::parse_config(&PATCH.replace(from, to)).unwrap();
+            format!("{:#}", DynRack::new(&config.patch).err().unwrap())
+        };
+        assert_eq!(
+            error("\"vco_freq\"", "\"vco.out\""),
+            "Invalid expression at patch.vco.in_freq: Module can't refer to its own output: vco.out"
+        );
+        assert_eq!(
+            error("\"vco.out * eg.out * 0.5\"", "\"vco.out * (foo\""),
+            "Invalid expression at patch.lpf.in_value: Undefined input field: foo"
+        );
+        assert_eq!(
+            error("\"vco.out * eg.out * 0.5\"", "\"vco.out * (0.5\""),
+            "Invalid expression at patch.lpf.in_value: ')' expected at 14 in \"vco.out * (0.5\""
+        );
+        assert_eq!(
+            error("lpf.in_freq", "lpf.in_foo"),
+            "Unknown input: patch.lpf.in_foo"
+        );
+        assert_eq!(
+            error("eg.in_r", "xxx.in_value"),
+            "Undefined module: patch.xxx"
+        );
+        assert_eq!(
+            error(
+                "vco.in_waveform = \"vco_waveform\"",
+                "vco.in_waveform = \"vco_freq\""
+            ),
+            "WaveForm field or value required at patch.vco.in_waveform: vco_freq"
+        );
+        assert_eq!(
+            error(
+                "type = \"IIRLPF\"",
+                "type = \"Delay\"\n        max_time = 0"
+            ),
+            "modules.lpf.max_time must be positive"
+        );
+    }
+}
@@ -1,14 +1,25 @@
+use crate::smoothing::Smoothing;
 use crate::SimpleEnum;
 use anyhow::Result;
 
+/// State read by the modules of a rack. The fields are defined by `Rack::state_definition`.
 pub trait Input: Send + Sync + Clone + std::fmt::Debug
 where
     Self: Sized,
 {
+    /// Inputs that carry all held notes (for polyphonic racks) return them here
+    fn held_notes_mut(&mut self) -> Option<&mut crate::poly::HeldNotes> {
+        None
+    }
+}
+
+/// Input with fields known at compile time, defined by `define_input!`
+pub trait StaticInput: Input {
     fn new_state_definition() -> StateDefinition<Self>;
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum ButtonMode {
     Toggle,
     Momentary,
@@ -26,22 +37,180 @@ pub enum FieldType {
     Enum,
 }
 
+/// Taper of the value between min and max
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Curve {
+    #[default]
+    Linear,
+    /// Equal ratio per step. Both ends must be positive.
+    Exp,
+}
+
+/// Mapping of a 7-bit CC value to a f32 field
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ValueRange {
+    pub min: f32,
+    pub max: f32,
+    pub curve: Curve,
+    /// Map 127 to min and 0 to max
+    pub invert: bool,
+}
+impl Default for ValueRange {
+    fn default() -> Self {
+        ValueRange {
+            min: 0.0,
+            max: 1.0,
+            curve: Curve::Linear,
+            invert: false,
+        }
+    }
+}
+impl ValueRange {
+    pub fn map(&self, value: u8) -> f32 {
+        self.value(value.min(127) as f32 / 127.0)
+    }
+    /// Field value at the control position (0.0 - 1.0)
+    pub fn value(&self, position: f32) -> f32 {
+        let x = if self.invert {
+            1.0 - position
+        } else {
+            position
+        };
+        match self.curve {
+            Curve::Linear => self.min + (self.max - self.min) * x,
+            Curve::Exp => self.min * (self.max / self.min).powf(x),
+        }
+    }
+    /// Control position of the field value, clamped to 0.0 - 1.0
+    pub fn position(&self, value: f32) -> f32 {
+        let x = match self.curve {
+            Curve::Linear => (value - self.min) / (self.max - self.min),
+            Curve::Exp => (value / self.min).ln() / (self.max / self.min).ln(),
+        };
+        let x = if x.is_nan() { 0.0 } else { x.clamp(0.0, 1.0) };
+        if self.invert {
+            1.0 - x
+        } else {
+            x
+        }
+    }
+}
+
+/// How an absolute control takes over a field value that differs from its position,
+/// e.g. after loading a preset
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Takeover {
+    /// Set the value immediately
+    #[default]
+    Jump,
+    /// Ignore the control until it crosses the value
+    Pickup,
+    /// Move the value toward the end in the direction of the control, proportional to
+    /// the remaining range, until they meet
+    Scale,
+}
+
+/// Runtime state of a f32 control for `Takeover`
+#[derive(Debug, Default)]
+struct TakeoverState {
+    /// Last control position
+    position: Option<f32>,
+    /// Last value set by the control. The field is changed by others if it differs.
+    value: Option<f32>,
+    /// The value follows the control
+    synced: bool,
+}
+impl TakeoverState {
+    /// New value of the field, or None to ignore the control
+    fn update(&mut self, mode: Takeover, range: &ValueRange, current: f32, cc: u8) -> Option<f32> {
+        const TOLERANCE: f32 = 1.0 / 127.0;
+        let position = cc.min(127) as f32 / 127.0;
+        let last = self.position.replace(position);
+        if self.value != Some(current) {
+            self.synced = false;
+        }
+        let c = range.position(current);
+        let crossed = last.is_some_and(|l| (l - c) * (position - c) <= 0.0);
+        if mode == Takeover::Jump || self.synced || crossed || (position - c).abs() <= TOLERANCE {
+            self.synced = true;
+        }
+        let value = match (mode, last) {
+            _ if self.synced => range.value(position),
+            (Takeover::Scale, Some(l)) if position > l => {
+                range.value(c + (position - l) * (1.0 - c) / (1.0 - l))
+            }
+            (Takeover::Scale, Some(l)) if position < l => range.value(c - (l - position) * c / l),
+            _ => return None,
+        };
+        self.value = Some(value);
+        Some(value)
+    }
+}
+
 #[derive(Debug)]
 pub enum InputConfig {
-    F32 { name: String },
-    Bool { name: String, mode: ButtonMode },
-    Enum { name: String, values: Vec<String> },
+    F32 {
+        name: String,
+        range: ValueRange,
+        takeover: Takeover,
+    },
+    Bool {
+        name: String,
+        mode: ButtonMode,
+    },
+    Enum {
+        name: String,
+        values: Vec<String>,
+    },
 }
 impl InputConfig {
     fn name(&self) -> &str {
         match self {
-            Self::F32 { name } => name,
+            Self::F32 { name, .. } => name,
             Self::Bool { name, .. } => name,
             Self::Enum { name, .. } => name,
         }
     }
 }
 
+/// Which held note drives the note inputs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NotePriority {
+    #[default]
+    Last,
+    Low,
+    High,
+}
+
+#[derive(Debug, Clone)]
+pub enum NoteInputConfig {
+    /// Note frequency mapped to 0.0 - 1.0 with the same range as `restore_freq(freq_min, freq_max, _)`
+    Pitch {
+        name: String,
+        freq_min: f32,
+        freq_max: f32,
+    },
+    /// true while any note is held
+    Gate { name: String },
+    /// 0.0 - 1.0
+    Velocity { name: String },
+    /// Toggled whenever the envelope should restart (see `EG::in_trigger`)
+    Trigger { name: String },
+}
+impl NoteInputConfig {
+    pub fn name(&self) -> &str {
+        match self {
+            Self::Pitch { name, .. } => name,
+            Self::Gate { name } => name,
+            Self::Velocity { name } => name,
+            Self::Trigger { name } => name,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum OutputConfig {
     Bool {
@@ -66,11 +235,14 @@ impl OutputConfig {
 #[derive(Debug)]
 pub struct StateDefinition<S> {
     accessors: std::collections::HashMap<String, FieldAccessor<S>>,
+    /// Default smoothing of f32 fields
+    smoothing: std::collections::HashMap<String, Smoothing>,
 }
 impl<S> Default for StateDefinition<S> {
     fn default() -> Self {
         Self {
             accessors: std::collections::HashMap::new(),
+            smoothing: std::collections::HashMap::new(),
         }
     }
 }
@@ -78,6 +250,12 @@ impl<S> Default for StateDefinition<S> {
 pub struct StateInput<S> {
     state_definition: std::sync::Arc<StateDefinition<S>>,
     inputs: std::collections::HashMap<Key, InputConfig>,
+    takeover: std::collections::HashMap<Key, TakeoverState>,
+    note_inputs: Vec<NoteInputConfig>,
+    note_priority: NotePriority,
+    legato: bool,
+    /// (note, velocity) in the order of note on
+    held_notes: Vec<(u8, u8)>,
 }
 #[derive(Debug)]
 pub struct StateOutput<S> {
@@ -95,16 +273,92 @@ impl<S> StateDefinition<S> {
     pub fn define_field(&mut self, name: String, accessor: FieldAccessor<S>) {
         self.accessors.insert(name, accessor);
     }
+    pub fn set_smoothing(&mut self, name: &str, smoothing: Smoothing) {
+        self.smoothing.insert(name.to_owned(), smoothing);
+    }
+    pub fn smoothing(&self) -> impl Iterator<Item = (&str, Smoothing)> {
+        self.smoothing.iter().map(|(name, s)| (name.as_str(), *s))
+    }
+    pub fn into_fields(self) -> impl Iterator<Item = (String, FieldAccessor<S>)> {
+        self.accessors.into_iter()
+    }
     pub fn assert_has_field(&self, name: &str) {
         if !self.accessors.contains_key(name) {
             panic!("Undefined field: {}", name);
         }
     }
+    /// All fields in arbitrary order
+    pub fn fields(&self) -> impl Iterator<Item = (&str, &FieldAccessor<S>)> {
+        self.accessors.iter().map(|(name, a)| (name.as_str(), a))
+    }
     pub fn field(&self, name: &str) -> &FieldAccessor<S> {
         self.accessors
             .get(name)
             .unwrap_or_else(|| panic!("Undefined field: {}", name))
     }
+    /// Definition for a state that contains `S` as a part
+    pub fn map<T>(
+        self,
+        get: impl Fn(&T) -> &S + Copy + Send + Sync + 'static,
+        get_mut: impl Fn(&mut T) -> &mut S + Copy + Send + Sync + 'static,
+    ) -> StateDefinition<T>
+    where
+        S: 'static,
+    {
+        StateDefinition {
+            accessors: self
+                .accessors
+                .into_iter()
+                .map(|(name, accessor)| (name, accessor.map(get, get_mut)))
+                .collect(),
+            smoothing: self.smoothing,
+        }
+    }
+    /// Write (note, velocity) and gate to the note input fields. Trigger fields are toggled if retrigger == true.
+    pub fn apply_note(
+        &self,
+        inputs: &[NoteInputConfig],
+        state: &mut S,
+        note: Option<(u8, u8)>,
+        gate: bool,
+        retrigger: bool,
+    ) {
+        for input in inputs.iter() {
+            match (input, self.field(input.name())) {
+                (
+                    NoteInputConfig::Pitch {
+                        freq_min, freq_max, ..
+                    },
+                    FieldAccessor::F32(_, set),
+                ) => {
+                    if let Some((note, _)) = note {
+                        let freq = crate::module::note_to_freq(note);
+                        set(
+                            state,
+                            crate::module::normalize_freq(*freq_min, *freq_max, freq),
+                        );
+                    }
+                }
+                (NoteInputConfig::Velocity { .. }, FieldAccessor::F32(_, set)) => {
+                    if let Some((_, velocity)) = note {
+                        set(state, velocity as f32 / 127.0f32);
+                    }
+                }
+                (NoteInputConfig::Gate { .. }, FieldAccessor::Bool(_, set)) => {
+                    set(state, gate);
+                }
+                (NoteInputConfig::Trigger { .. }, FieldAccessor::Bool(get, set)) => {
+                    if retrigger {
+                        let current = get(state);
+                        set(state, !current);
+                    }
+                }
+                (input, _) => {
+                    panic!("assertion error: {}", input.name());
+                }
+            }
+        }
+    }
     pub fn field_type(&self, name: &str) -> Option<FieldType> {
         self.accessors.get(name).map(|a| match a {
             FieldAccessor::Bool(..) => FieldType::Bool,
@@ -118,16 +372,105 @@ impl<S> StateInput<S> {
         StateInput {
             state_definition,
             inputs: std::collections::HashMap::new(),
+            takeover: std::collections::HashMap::new(),
+            note_inputs: Vec::new(),
+            note_priority: NotePriority::default(),
+            legato: false,
+            held_notes: Vec::new(),
         }
     }
     pub fn field_type(&self, name: &str) -> Option<FieldType> {
         self.state_definition.field_type(name)
     }
+    pub fn state_definition(&self) -> &StateDefinition<S> {
+        &self.state_definition
+    }
     pub fn define_input(&mut self, key: Key, input: InputConfig) {
         self.state_definition.assert_has_field(input.name());
         self.inputs.insert(key, input);
     }
-    pub fn update_state(&self, state: &mut S, key: Key, value: u8) {
+    pub fn define_note_input(&mut self, input: NoteInputConfig) {
+        self.state_definition.assert_has_field(input.name());
+        self.note_inputs.push(input);
+    }
+    /// Take over the held notes from the input being replaced, so that note offs still work
+    pub fn take_held_notes(&mut self, prev: &mut StateInput<S>) {
+        self.held_notes = std::mem::take(&mut prev.held_notes);
+    }
+    /// Take over the takeover state of f32 controls whose field and range are unchanged,
+    /// so that picked up controls don't need to be picked up again after reloading
+    pub fn take_takeover(&mut self, prev: &mut StateInput<S>) {
+        for (key, takeover) in prev.takeover.drain() {
+            if let (
+                Some(InputConfig::F32 { name, range, .. }),
+                Some(InputConfig::F32 {
+                    name: prev_name,
+                    range: prev_range,
+                    ..
+                }),
+            ) = (self.inputs.get(&key), prev.inputs.get(&key))
+            {
+                if name == prev_name && range == prev_range {
+                    self.takeover.insert(key, takeover);
+                }
+            }
+        }
+    }
+    /// legato: Don't retrigger when the active note changes while other notes are held
+    pub fn set_note_mode(&mut self, priority: NotePriority, legato: bool) {
+        self.note_priority = priority;
+        self.legato = legato;
+    }
+    fn active_note(&self) -> Option<(u8, u8)> {
+        match self.note_priority {
+            NotePriority::Last => self.held_notes.last().copied(),
+            NotePriority::Low => self.held_notes.iter().min_by_key(|(n, _)| *n).copied(),
+            NotePriority::High => self.held_notes.iter().max_by_key(|(n, _)| *n).copied(),
+        }
+    }
+    pub fn note_on(&mut self, state: &mut S, note: u8, velocity: u8)
+    where
+        S: Input,
+    {
+        if let Some(notes) = state.held_notes_mut() {
+            notes.note_on(note, velocity);
+            return;
+        }
+        let prev = self.active_note();
+        self.held_notes.retain(|(n, _)| *n != note);
+        self.held_notes.push((note, velocity));
+        let active = self.active_note();
+        if prev.is_none() {
+            self.update_note_state(state, active, true);
+        } else if prev.map(|(n, _)| n) != active.map(|(n, _)| n) {
+            self.update_note_state(state, active, !self.legato);
+        }
+    }
+    pub fn note_off(&mut self, state: &mut S, note: u8)
+    where
+        S: Input,
+    {
+        if let Some(notes) = state.held_notes_mut() {
+            notes.note_off(note);
+            return;
+        }
+        let prev = self.active_note();
+        self.held_notes.retain(|(n, _)| *n != note);
+        let active = self.active_note();
+        if prev.map(|(n, _)| n) != active.map(|(n, _)| n) {
+            self.update_note_state(state, active, !self.legato);
+        }
+    }
+    fn update_note_state(&self, state: &mut S, active: Option<(u8, u8)>, retrigger: bool) {
+        self.state_definition.apply_note(
+            &self.note_inputs,
+            state,
+            active,
+            active.is_some(),
+            active.is_some() && retrigger,
+        );
+    }
+    pub fn update_state(&mut self, state: &mut S, key: Key, value: u8) {
         if let Some(input) = self.inputs.get(&key) {
             match input {
                 InputConfig::Bool { name, mode } => match self.state_definition.field(name) {
@@ -149,10 +492,21 @@ impl<S> StateInput<S> {
                         panic!("assertion error: {}", name);
                     }
                 },
-                InputConfig::F32 { name } => match self.state_definition.field(name) {
-                    FieldAccessor::F32(_, set) => {
-                        let value = value as f32 / 127.0f32;
-                        set(state, value);
+                InputConfig::F32 {
+                    name,
+                    range,
+                    takeover,
+                } => match self.state_definition.field(name) {
+                    FieldAccessor::F32(get, set) => {
+                        let current = get(state);
+                        let value = self
+                            .takeover
+                            .entry(key)
+                            .or_default()
+                            .update(*takeover, range, current, value);
+                        if let Some(value) = value {
+                            set(state, value);
+                        }
                     }
                     _ => {
                         panic!("assertion error: {}", name);
@@ -222,13 +576,33 @@ impl<S> StateOutput<S> {
 
 type Get<S, T> = Box<dyn Fn(&S) -> T + Send + Sync>;
 type Set<S, T> = Box<dyn Fn(&mut S, T) + Send + Sync>;
+type SetEnum<S> = Box<dyn Fn(&mut S, &str) + Send + Sync>;
 pub enum FieldAccessor<S> {
     F32(Get<S, f32>, Set<S, f32>),
     Bool(Get<S, bool>, Set<S, bool>),
-    Enum(
-        Get<S, &'static str>,
-        Box<dyn Fn(&mut S, &str) + Send + Sync>,
-    ),
+    Enum(Get<S, &'static str>, SetEnum<S>),
+}
+impl<S: 'static> FieldAccessor<S> {
+    fn map<T>(
+        self,
+        get: impl Fn(&T) -> &S + Copy + Send + Sync + 'static,
+        get_mut: impl Fn(&mut T) -> &mut S + Copy + Send + Sync + 'static,
+    ) -> FieldAccessor<T> {
+        match self {
+            FieldAccessor::F32(g, s) => FieldAccessor::F32(
+                Box::new(move |t| g(get(t))),
+                Box::new(move |t, v| s(get_mut(t), v)),
+            ),
+            FieldAccessor::Bool(g, s) => FieldAccessor::Bool(
+                Box::new(move |t| g(get(t))),
+                Box::new(move |t, v| s(get_mut(t), v)),
+            ),
+            FieldAccessor::Enum(g, s) => FieldAccessor::Enum(
+                Box::new(move |t| g(get(t))),
+                Box::new(move |t, v| s(get_mut(t), v)),
+            ),
+        }
+    }
 }
 impl<S> std::fmt::Debug for FieldAccessor<S> {
     fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::result::Result<(), std::fmt::Error> {
@@ -318,3 +692,226 @@ impl<S, E: SimpleEnum> DefineField<S, E> for StateDefinition<S> {
         )
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::define_input;
+
+    define_input! {
+        NoteTestInput {
+            pitch: f32,
+            velocity: f32,
+            gate: bool,
+            trigger: bool,
+        }
+    }
+
+    fn setup(priority: NotePriority, legato: bool) -> StateInput<NoteTestInput> {
+        let (mut state_in, _) = NoteTestInput::new_state_definition().into_io();
+        state_in.set_note_mode(priority, legato);
+        state_in.define_note_input(NoteInputConfig::Pitch {
+            name: "pitch".to_owned(),
+            freq_min: 110.0,
+            freq_max: 1760.0,
+        });
+        state_in.define_note_input(NoteInputConfig::Velocity {
+            name: "velocity".to_owned(),
+        });
+        state_in.define_note_input(NoteInputConfig::Gate {
+            name: "gate".to_owned(),
+        });
+        state_in.define_note_input(NoteInputConfig::Trigger {
+            name: "trigger".to_owned(),
+        });
+        state_in
+    }
+
+    #[test]
+    fn test_note_pitch() {
+        let mut state_in = setup(NotePriority::Last, false);
+        let mut state = NoteTestInput::default();
+        // A2 = 110Hz, A6 = 1760Hz: one octave = 0.25
+        state_in.note_on(&mut state, 45, 127);
+        assert!(state.pitch.abs() < 1e-5);
+        assert_eq!(state.velocity, 1.0);
+        assert!(state.gate);
+        state_in.note_on(&mut state, 57, 127);
+        assert!((state.pitch - 0.25).abs() < 1e-5);
+        state_in.note_off(&mut state, 57);
+        state_in.note_off(&mut state, 45);
+        assert!(!state.gate);
+        assert!(state.pitch.abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_note_priority() {
+        let notes = |priority| {
+            let mut state_in = setup(priority, false);
+            let mut state = NoteTestInput::default();
+            let mut pitches = vec![];
+            for note in [57, 45, 69] {
+                state_in.note_on(&mut state, note, 100);
+                pitches.push((state.pitch * 4.0).round() as i32);
+            }
+            state_in.note_off(&mut state, 69);
+            pitches.push((state.pitch * 4.0).round() as i32);
+            pitches
+        };
+        assert_eq!(notes(NotePriority::Last), &[1, 0, 2, 0]);
+        assert_eq!(notes(NotePriority::Low), &[1, 0, 0, 0]);
+        assert_eq!(notes(NotePriority::High), &[1, 1, 2, 1]);
+    }
+
+    #[test]
+    fn test_note_retrigger() {
+        let mut state_in = setup(NotePriority::Last, false);
+        let mut state = NoteTestInput::default();
+        state_in.note_on(&mut state, 60, 100);
+        assert!(state.trigger);
+        state_in.note_on(&mut state, 62, 100);
+        assert!(!state.trigger);
+        state_in.note_off(&mut state, 62);
+        assert!(state.trigger);
+
+        let mut state_in = setup(NotePriority::Last, true);
+        let mut state = NoteTestInput::default();
+        state_in.note_on(&mut state, 60, 100);
+        assert!(state.trigger);
+        state_in.note_on(&mut state, 62, 100);
+        state_in.note_off(&mut state, 62);
+        assert!(state.trigger);
+        state_in.note_off(&mut state, 60);
+        state_in.note_on(&mut state, 60, 100);
+        assert!(!state.trigger);
+    }
+
+    #[test]
+    fn test_take_held_notes() {
+        let mut state_in = setup(NotePriority::Last, false);
+        let mut state = NoteTestInput::default();
+        state_in.note_on(&mut state, 60, 100);
+        // reloaded mapping releases the note held before
+        let mut reloaded = setup(NotePriority::Last, false);
+        reloaded.take_held_notes(&mut state_in);
+        reloaded.note_off(&mut state, 60);
+        assert!(!state.gate);
+    }
+
+    #[test]
+    fn test_value_range() {
+        let range = ValueRange::default();
+        assert_eq!(range.map(0), 0.0);
+        assert_eq!(range.map(127), 1.0);
+
+        let range = ValueRange {
+            min: 0.001,
+            max: 4.0,
+            curve: Curve::Exp,
+            invert: false,
+        };
+        assert!((range.map(0) - 0.001).abs() < 1e-6);
+        assert!((range.map(127) - 4.0).abs() < 1e-4);
+        // geometric mean at the center
+        let center = (range.map(63) * range.map(64)).sqrt();
+        assert!((center - (0.001f32 * 4.0).sqrt()).abs() < 1e-3);
+
+        let range = ValueRange {
+            min: 0.2,
+            max: 0.8,
+            curve: Curve::Linear,
+            invert: true,
+        };
+        assert!((range.map(0) - 0.8).abs() < 1e-6);
+        assert!((range.map(127) - 0.2).abs() < 1e-6);
+        assert!((range.position(0.35) - 0.75).abs() < 1e-6);
+        assert_eq!(range.position(1.0), 0.0);
+    }
+
+    define_input! {
+        KnobTestInput {
+            level: f32 = 0.8,
+        }
+    }
+
+    #[test]
+    fn test_takeover() {
+        let setup = |takeover| {
+            let (mut state_in, _) = KnobTestInput::new_state_definition().into_io();
+            state_in.define_input(
+                Key::ControlChange(0),
+                InputConfig::F32 {
+                    name: "level".to_owned(),
+                    range: ValueRange::default(),
+                    takeover,
+                },
+            );
+            state_in
+        };
+        let cc = |value: u8| value as f32 / 127.0;
+
+        let mut state_in = setup(Takeover::Pickup);
+        let mut state = KnobTestInput::default();
+        for value in [0, 50, 100] {
+            state_in.update_state(&mut state, Key::ControlChange(0), value);
+            assert_eq!(state.level, 0.8);
+        }
+        // crossed
+        state_in.update_state(&mut state, Key::ControlChange(0), 102);
+        assert_eq!(state.level, cc(102));
+        state_in.update_state(&mut state, Key::ControlChange(0), 20);
+        assert_eq!(state.level, cc(20));
+        // changed by a preset
+        state.level = 0.5;
+        state_in.update_state(&mut state, Key::ControlChange(0), 30);
+        assert_eq!(state.level, 0.5);
+        state_in.update_state(&mut state, Key::ControlChange(0), 127);
+        assert_eq!(state.level, 1.0);
+
+        let mut state_in = setup(Takeover::Scale);
+        let mut state = KnobTestInput::default();
+        state_in.update_state(&mut state, Key::ControlChange(0), 0);
+        assert_eq!(state.level, 0.8);
+        // moves 1/5 of the control
+        state_in.update_state(&mut state, Key::ControlChange(0), 64);
+        assert!((state.level - (0.8 + cc(64) * 0.2)).abs() < 1e-6);
+        state_in.update_state(&mut state, Key::ControlChange(0), 127);
+        assert_eq!(state.level, 1.0);
+        state_in.update_state(&mut state, Key::ControlChange(0), 0);
+        assert_eq!(state.level, 0.0);
+
+        let mut state_in = setup(Takeover::Jump);
+        let mut state = KnobTestInput::default();
+        state_in.update_state(&mut state, Key::ControlChange(0), 0);
+        assert_eq!(state.level, 0.0);
+
+        // picked up control stays picked up after reloading the same mapping
+        let mut state_in = setup(Takeover::Pickup);
+        let mut state = KnobTestInput::default();
+        state_in.update_state(&mut state, Key::ControlChange(0), 102);
+        state_in.update_state(&mut state, Key::ControlChange(0), 100);
+        assert_eq!(state.level, cc(100));
+        let mut reloaded = setup(Takeover::Pickup);
+        reloaded.take_takeover(&mut state_in);
+        reloaded.update_state(&mut state, Key::ControlChange(0), 50);
+        assert_eq!(state.level, cc(50));
+        // but not if the range is changed
+        let mut remapped = KnobTestInput::new_state_definition().into_io().0;
+        remapped.define_input(
+            Key::ControlChange(0),
+            InputConfig::F32 {
+                name: "level".to_owned(),
+                range: ValueRange {
+                    invert: true,
+                    ..ValueRange::default()
+                },
+                takeover: Takeover::Pickup,
+            },
+        );
+        remapped.take_takeover(&mut reloaded);
+        remapped.update_state(&mut state, Key::ControlChange(0), 0);
+        assert_eq!(state.level, cc(50));
+        remapped.update_state(&mut state, Key::ControlChange(0), 77);
+        assert_eq!(state.level, 1.0 - cc(77));
+    }
+}
@@ -1,9 +1,15 @@
 pub mod config;
+pub mod dyn_rack;
 pub mod input;
 pub mod macros;
 pub mod midi_message;
 pub mod module;
 pub mod nanokontrol2;
+pub mod poly;
+pub mod preset;
+pub mod render;
+pub mod smoothing;
+pub mod transport;
 pub mod util;
 
 pub trait SimpleEnum
@@ -14,19 +20,15 @@ where
     fn to_name(&self) -> &'static str;
 }
 
-#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
 pub enum WaveForm {
+    #[default]
     Sine,
     Sawtooth,
     Triangle,
     Square,
     Noise,
 }
-impl Default for WaveForm {
-    fn default() -> WaveForm {
-        WaveForm::Sine
-    }
-}
 impl SimpleEnum for WaveForm {
     fn from_name(name: &str) -> Option<Self> {
         match name {
@@ -49,17 +51,13 @@ impl SimpleEnum for WaveForm {
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
 pub enum TriState {
+    #[default]
     State0,
     State1,
     State2,
 }
-impl Default for TriState {
-    fn default() -> Self {
-        Self::State0
-    }
-}
 impl SimpleEnum for TriState {
     fn from_name(name: &str) -> Option<Self> {
         match name {
@@ -77,3 +75,88 @@ impl SimpleEnum for TriState {
         }
     }
 }
+
+/// Note length for tempo sync
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum Division {
+    /// Not synced
+    #[default]
+    Free,
+    Whole,
+    Half,
+    Quarter,
+    Eighth,
+    Sixteenth,
+    ThirtySecond,
+    DottedHalf,
+    DottedQuarter,
+    DottedEighth,
+    DottedSixteenth,
+    TripletQuarter,
+    TripletEighth,
+    TripletSixteenth,
+}
+impl Division {
+    /// Length in quarter notes, None if Free
+    pub fn beats(&self) -> Option<f32> {
+        match self {
+            Division::Free => None,
+            Division::Whole => Some(4.0),
+            Division::Half => Some(2.0),
+            Division::Quarter => Some(1.0),
+            Division::Eighth => Some(0.5),
+            Division::Sixteenth => Some(0.25),
+            Division::ThirtySecond => Some(0.125),
+            Division::DottedHalf => Some(3.0),
+            Division::DottedQuarter => Some(1.5),
+            Division::DottedEighth => Some(0.75),
+            Division::DottedSixteenth => Some(0.375),
+            Division::TripletQuarter => Some(2.0 / 3.0),
+            Division::TripletEighth => Some(1.0 / 3.0),
+            Division::TripletSixteenth => Some(1.0 / 6.0),
+        }
+    }
+    /// Length in seconds at the tempo, None if Free
+    pub fn seconds(&self, bpm: f32) -> Option<f32> {
+        self.beats().map(|beats| beats * 60.0 / bpm)
+    }
+}
+impl SimpleEnum for Division {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "Free" => Some(Division::Free),
+            "Whole" => Some(Division::Whole),
+            "Half" => Some(Division::Half),
+            "Quarter" => Some(Division::Quarter),
+            "Eighth" => Some(Division::Eighth),
+            "Sixteenth" => Some(Division::Sixteenth),
+            "ThirtySecond" => Some(Division::ThirtySecond),
+            "DottedHalf" => Some(Division::DottedHalf),
+            "DottedQuarter" => Some(Division::DottedQuarter),
+            "DottedEighth" => Some(Division::DottedEighth),
+            "DottedSixteenth" => Some(Division::DottedSixteenth),
+            "TripletQuarter" => Some(Division::TripletQuarter),
+            "TripletEighth" => Some(Division::TripletEighth),
+            "TripletSixteenth" => Some(Division::TripletSixteenth),
+            _ => None,
+        }
+    }
+    fn to_name(&self) -> &'static str {
+        match self {
+            Division::Free => "Free",
+            Division::Whole => "Whole",
+            Division::Half => "Half",
+            Division::Quarter => "Quarter",
+            Division::Eighth => "Eighth",
+            Division::Sixteenth => "Sixteenth",
+            Division::ThirtySecond => "ThirtySecond",
+            Division::DottedHalf => "DottedHalf",
+            Division::DottedQuarter => "DottedQuarter",
+            Division::DottedEighth => "DottedEighth",
+            Division::DottedSixteenth => "DottedSixteenth",
+            Division::TripletQuarter => "TripletQuarter",
+            Division::TripletEighth => "TripletEighth",
+            Division::TripletSixteenth => "TripletSixteenth",
+        }
+    }
+}
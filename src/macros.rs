@@ -11,7 +11,7 @@ macro_rules! define_input_field_default {
 #[macro_export]
 macro_rules! define_input {
     ($name:ident {
-        $($field:ident : $ty:ty $(= $default_value:tt)?),*$(,)?
+        $($(#[smoothing = $smoothing:expr])? $field:ident : $ty:ty $(= $default_value:tt)?),*$(,)?
     }) => {
         #[derive(Clone, Debug)]
         pub struct $name {
@@ -19,7 +19,8 @@ macro_rules! define_input {
                 pub $field: $ty
             ),*
         }
-        impl $crate::input::Input for $name {
+        impl $crate::input::Input for $name {}
+        impl $crate::input::StaticInput for $name {
             fn new_state_definition() -> $crate::input::StateDefinition<Self> {
                 let mut key_mapping = $crate::input::StateDefinition::<$name>::new();
                 $(
@@ -29,6 +30,7 @@ macro_rules! define_input {
                         |input| &input.$field,
                         |input, value| input.$field = value
                     );
+                    $(key_mapping.set_smoothing(stringify!($field), $smoothing);)?
                 )*
                 key_mapping
             }
@@ -83,13 +85,16 @@ macro_rules! define_rack {
         }
         impl $crate::module::Rack for $rack_name {
             type Input = $input;
-            fn new_input() -> Self::Input {
+            fn new_input(&self) -> Self::Input {
                 ::std::default::Default::default()
             }
-            fn update(&self, input: &$input) {
+            fn state_definition(&self) -> $crate::input::StateDefinition<$input> {
+                <$input as $crate::input::StaticInput>::new_state_definition()
+            }
+            fn update(&self, input: &$input, ctx: &$crate::module::ProcessContext) {
                 $({
                     let mut module = ::std::cell::RefCell::borrow_mut(&self.$mod_name);
-                    $crate::module::Module::update(&mut *module, self, input);
+                    $crate::module::Module::update(&mut *module, self, input, ctx);
                 })*
             }
         }
@@ -2,15 +2,26 @@ use anyhow::Context;
 use anyhow::Result;
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
 
+use rustsynth::config::PresetConfig;
 use rustsynth::define_input;
 use rustsynth::define_rack;
+use rustsynth::dyn_rack::DynRack;
 use rustsynth::input::Key;
-use rustsynth::input::StateOutput;
-use rustsynth::midi_message::MidiMessage;
-use rustsynth::module::{Buf, Rack, EG, IIRLPF, VCO};
-use rustsynth::util::SyncError;
+use rustsynth::input::{Input, StateDefinition, StateInput, StateOutput};
+use rustsynth::midi_message::{MidiMessage, MidiParser};
+use rustsynth::module::{
+    Buf, Frame, LadderLPF, Mixer, MixerChannel, Pan, ProcessContext, Rack, Reverb, Step,
+    StepSequencer, EG, IIRLPF, VCO,
+};
+use rustsynth::poly::Poly;
+use rustsynth::preset::{load_preset, save_preset};
+use rustsynth::render::{render_to_wav, SampleFormat, Script};
+use rustsynth::smoothing::{Smoother, Smoothing};
+use rustsynth::transport::{MidiClock, Transport};
+use rustsynth::util::{triple_buffer, FileWatcher, SyncError, TripleBufferWriter};
 use rustsynth::TriState;
 use rustsynth::WaveForm;
+use std::sync::{Arc, Mutex};
 
 define_input! {
     Rack1Input {
@@ -24,10 +35,15 @@ define_input! {
         eg1_s: f32 = 0.8,
         eg1_r: f32 = 0.1,
         eg1_gate: bool = false,
+        eg1_trigger: bool = false,
         eg1_repeat: bool = false,
+        #[smoothing = Smoothing::OnePole(0.01)]
         lpf1_freq: f32 = 0.1,
         lpf1_resonance: f32 = 0.05,
         lpf1_lfo1_amount: f32 = 0.0,
+        reverb_size: f32 = 0.5,
+        reverb_mix: f32 = 0.0,
+        pan: f32 = 0.5,
     }
 }
 define_rack! {
@@ -43,10 +59,12 @@ define_rack! {
             in_waveform: { input.vco1_waveform } ,
             freq_min: 100.0,
             freq_max: 15000.0,
+            band_limited: true,
         },
         eg1: EG {
             in_gate: { input.eg1_gate },
             in_repeat: { input.eg1_repeat },
+            in_trigger: { input.eg1_trigger },
             in_a: { input.eg1_a },
             in_d: { input.eg1_d },
             in_s: { input.eg1_s },
@@ -62,6 +80,15 @@ define_rack! {
             freq_min: 100.0,
             freq_max: 20_000.0,
         },
+        reverb: Reverb {
+            in_value: { rack.lpf1.borrow().out },
+            in_size: { input.reverb_size },
+            in_mix: { input.reverb_mix },
+        },
+        pan: Pan {
+            in_value: { rack.reverb.borrow().out },
+            in_pan: { input.pan },
+        },
     }
 }
 
@@ -73,12 +100,13 @@ define_input! {
         areg_release: f32,
         areg_repeat: bool,
         areg_gate: bool,
+        areg_trigger: bool,
         vco_ar_mod: f32,
         vco_ar_mod_enable: bool,
         vco_lfo_mod: f32,
         vco_freq: f32,
         vco_waveform: WaveForm,
-        // vco_sync: bool,
+        vco_sync: bool,
         vcf_in_noise: bool,
         vcf_cof: f32,
         vcf_res: f32,
@@ -98,6 +126,7 @@ define_rack! {
         areg: EG {
             in_gate: { input.areg_gate },
             in_repeat: { input.areg_repeat },
+            in_trigger: { input.areg_trigger },
             in_a: { input.areg_attack },
             in_d: { 0.0 },
             in_s: { 1.0 },
@@ -116,10 +145,27 @@ define_rack! {
                     + rack.lfo.borrow().out * input.vco_lfo_mod
             },
             in_waveform: { input.vco_waveform },
+            in_sync: {
+                if input.vco_sync {
+                    rack.lfo.borrow().out_sync
+                } else {
+                    0.0
+                }
+            },
             freq_min: 100.0,
             freq_max: 15_000.0,
+            band_limited: true,
         },
-        vcf: IIRLPF {
+        vcf_in: Mixer {
+            channels: (vec![
+                MixerChannel::new(|rack: &NoiseToaster, _| rack.vco.borrow().out),
+                MixerChannel {
+                    in_mute: Box::new(|_, input: &NoiseToasterInput| !input.vcf_in_noise),
+                    ..MixerChannel::new(|rack: &NoiseToaster, _| rack.white_noise.borrow().out)
+                },
+            ]),
+        },
+        vcf: LadderLPF {
             in_freq: {
                 let x = input.vcf_cof;
                 let mod_source = match input.vcf_mod_select {
@@ -130,13 +176,7 @@ define_rack! {
                 x + mod_source * input.vcf_mod
             },
             in_resonance: { input.vcf_res },
-            in_value: {
-                let mut x = rack.vco.borrow().out;
-                if input.vcf_in_noise {
-                    x += rack.white_noise.borrow().out;
-                }
-                x
-            },
+            in_value: { rack.vcf_in.borrow().out },
             freq_min: 100.0,
             freq_max: 20_000.0,
         },
@@ -152,46 +192,347 @@ define_rack! {
     }
 }
 
-fn main() -> Result<()> {
-    let config = rustsynth::config::load_config("noisetoaster-nanokontrol2.toml")?;
-    let midi_out_con = setup_midi_output_connection(&config.midi_out_name)?;
-    let (midi_in, midi_in_port) = setup_midi_input(&config.midi_in_name)?;
+define_input! {
+    SequencerInput {
+        step1_pitch: f32 = 0.5,
+        step1_gate: bool = true,
+        step1_accent: bool,
+        step1_slide: bool,
+        step2_pitch: f32 = 0.5,
+        step2_gate: bool = true,
+        step2_accent: bool,
+        step2_slide: bool,
+        step3_pitch: f32 = 0.5,
+        step3_gate: bool = true,
+        step3_accent: bool,
+        step3_slide: bool,
+        step4_pitch: f32 = 0.5,
+        step4_gate: bool = true,
+        step4_accent: bool,
+        step4_slide: bool,
+        step5_pitch: f32 = 0.5,
+        step5_gate: bool = true,
+        step5_accent: bool,
+        step5_slide: bool,
+        step6_pitch: f32 = 0.5,
+        step6_gate: bool = true,
+        step6_accent: bool,
+        step6_slide: bool,
+        step7_pitch: f32 = 0.5,
+        step7_gate: bool = true,
+        step7_accent: bool,
+        step7_slide: bool,
+        step8_pitch: f32 = 0.5,
+        step8_gate: bool = true,
+        step8_accent: bool,
+        step8_slide: bool,
+        run: bool,
+        bpm: f32 = 0.33,
+        gate_length: f32 = 0.5,
+        waveform: WaveForm = (WaveForm::Sawtooth),
+        cutoff: f32 = 0.2,
+        resonance: f32 = 0.5,
+        env_mod: f32 = 0.4,
+        decay: f32 = 0.3,
+        accent: f32 = 0.5,
+        volume: f32 = 0.5,
+    }
+}
+define_rack! {
+    Sequencer: Rack<SequencerInput>(rack, input) {
+        seq: StepSequencer {
+            steps: (vec![
+                Step::new(
+                    |_, input: &SequencerInput| input.step1_pitch,
+                    |_, input: &SequencerInput| input.step1_gate,
+                    |_, input: &SequencerInput| input.step1_accent,
+                    |_, input: &SequencerInput| input.step1_slide,
+                ),
+                Step::new(
+                    |_, input: &SequencerInput| input.step2_pitch,
+                    |_, input: &SequencerInput| input.step2_gate,
+                    |_, input: &SequencerInput| input.step2_accent,
+                    |_, input: &SequencerInput| input.step2_slide,
+                ),
+                Step::new(
+                    |_, input: &SequencerInput| input.step3_pitch,
+                    |_, input: &SequencerInput| input.step3_gate,
+                    |_, input: &SequencerInput| input.step3_accent,
+                    |_, input: &SequencerInput| input.step3_slide,
+                ),
+                Step::new(
+                    |_, input: &SequencerInput| input.step4_pitch,
+                    |_, input: &SequencerInput| input.step4_gate,
+                    |_, input: &SequencerInput| input.step4_accent,
+                    |_, input: &SequencerInput| input.step4_slide,
+                ),
+                Step::new(
+                    |_, input: &SequencerInput| input.step5_pitch,
+                    |_, input: &SequencerInput| input.step5_gate,
+                    |_, input: &SequencerInput| input.step5_accent,
+                    |_, input: &SequencerInput| input.step5_slide,
+                ),
+                Step::new(
+                    |_, input: &SequencerInput| input.step6_pitch,
+                    |_, input: &SequencerInput| input.step6_gate,
+                    |_, input: &SequencerInput| input.step6_accent,
+                    |_, input: &SequencerInput| input.step6_slide,
+                ),
+                Step::new(
+                    |_, input: &SequencerInput| input.step7_pitch,
+                    |_, input: &SequencerInput| input.step7_gate,
+                    |_, input: &SequencerInput| input.step7_accent,
+                    |_, input: &SequencerInput| input.step7_slide,
+                ),
+                Step::new(
+                    |_, input: &SequencerInput| input.step8_pitch,
+                    |_, input: &SequencerInput| input.step8_gate,
+                    |_, input: &SequencerInput| input.step8_accent,
+                    |_, input: &SequencerInput| input.step8_slide,
+                ),
+            ]),
+            in_run: { input.run },
+            // 60 - 240 BPM
+            in_bpm: { 60.0 + input.bpm * 180.0 },
+            in_gate_length: { input.gate_length },
+        },
+        vco: VCO {
+            in_freq: { rack.seq.borrow().out_pitch },
+            in_waveform: { input.waveform },
+            freq_min: 55.0,
+            freq_max: 880.0,
+            band_limited: true,
+        },
+        eg: EG {
+            in_gate: { rack.seq.borrow().out_gate },
+            in_trigger: { rack.seq.borrow().out_trigger },
+            in_a: { 0.003 },
+            in_d: { input.decay },
+            in_s: { 0.0 },
+            in_r: { 0.01 },
+        },
+        vcf: LadderLPF {
+            in_freq: {
+                let accent = if rack.seq.borrow().out_accent { input.accent } else { 0.0 };
+                input.cutoff + input.env_mod * (1.0 + accent) * rack.eg.borrow().out
+            },
+            in_resonance: { input.resonance },
+            in_value: { rack.vco.borrow().out },
+            freq_min: 50.0,
+            freq_max: 10_000.0,
+        },
+        vca: Buf {
+            in_value: {
+                let accent = if rack.seq.borrow().out_accent { input.accent } else { 0.0 };
+                rack.vcf.borrow().out * rack.eg.borrow().out * (1.0 + accent) * input.volume
+            },
+        },
+    }
+}
+
+const USAGE: &str = "\
+Usage: rustsynth [OPTIONS] [CONFIG]
+       rustsynth list-devices [--host HOST]
+       rustsynth list-midi-ports
+
+CONFIG: Config file (default: noisetoaster-nanokontrol2.toml)
+
+Options:
+    --midi-in NAME        MIDI input port, overrides [device] of the config
+    --midi-out NAME       MIDI output port, overrides [device] of the config
+    --host NAME           Audio host such as ALSA or JACK (build with --features jack)
+    --device NAME         Audio output device
+    --sample-rate HZ
+    --buffer-size FRAMES
+    --render FILE         Render the startup state to a WAV file instead of playing
+    --duration SEC        Length of --render (default: 10)
+    -h, --help";
+
+#[derive(Debug, PartialEq, Eq)]
+enum Command {
+    Run,
+    ListDevices,
+    ListMidiPorts,
+    Help,
+}
 
-    let cpal_device = setup_cpal_device()?;
-    let cpal_config = setup_cpal_config(&cpal_device)?;
+#[derive(Debug, PartialEq)]
+struct Args {
+    command: Command,
+    config: String,
+    midi_in: Option<String>,
+    midi_out: Option<String>,
+    host: Option<String>,
+    device: Option<String>,
+    sample_rate: Option<u32>,
+    buffer_size: Option<u32>,
+    render: Option<String>,
+    /// sec
+    duration: f32,
+}
+impl Args {
+    fn parse(args: impl IntoIterator<Item = String>) -> Result<Args> {
+        fn number<T: std::str::FromStr>(name: &str, value: String) -> Result<T> {
+            value
+                .parse()
+                .map_err(|_| anyhow::anyhow!("Invalid value for {}: {}", name, value))
+        }
+        let mut parsed = Args {
+            command: Command::Run,
+            config: "noisetoaster-nanokontrol2.toml".to_owned(),
+            midi_in: None,
+            midi_out: None,
+            host: None,
+            device: None,
+            sample_rate: None,
+            buffer_size: None,
+            render: None,
+            duration: 10.0,
+        };
+        let mut positional = Vec::new();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .with_context(|| format!("{} requires a value", arg))
+            };
+            match arg.as_str() {
+                "-h" | "--help" => parsed.command = Command::Help,
+                "--midi-in" => parsed.midi_in = Some(value()?),
+                "--midi-out" => parsed.midi_out = Some(value()?),
+                "--host" => parsed.host = Some(value()?),
+                "--device" => parsed.device = Some(value()?),
+                "--sample-rate" => parsed.sample_rate = Some(number(&arg, value()?)?),
+                "--buffer-size" => parsed.buffer_size = Some(number(&arg, value()?)?),
+                "--render" => parsed.render = Some(value()?),
+                "--duration" => parsed.duration = number(&arg, value()?)?,
+                _ if arg.starts_with('-') => anyhow::bail!("Unknown option: {}", arg),
+                _ => positional.push(arg),
+            }
+        }
+        let mut positional = positional.into_iter();
+        match positional.next().as_deref() {
+            Some("list-devices") => parsed.command = Command::ListDevices,
+            Some("list-midi-ports") => parsed.command = Command::ListMidiPorts,
+            Some(config) => parsed.config = config.to_owned(),
+            None => {}
+        }
+        if let Some(arg) = positional.next() {
+            anyhow::bail!("Unexpected argument: {}", arg);
+        }
+        Ok(parsed)
+    }
+}
 
+fn main() -> Result<()> {
+    let args = match Args::parse(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("{}\n\n{}", err, USAGE);
+            std::process::exit(2);
+        }
+    };
+    match args.command {
+        Command::Help => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        Command::ListDevices => list_devices(&args.host),
+        Command::ListMidiPorts => {
+            list_available_midi_ports(&midir::MidiInput::new("midi_input")?, "input")?;
+            list_available_midi_ports(&midir::MidiOutput::new("midir")?, "output")
+        }
+        Command::Run => run(args),
+    }
+}
+
+fn run(args: Args) -> Result<()> {
+    let mut config = rustsynth::config::load_config(&args.config)?;
+    if args.midi_in.is_some() {
+        config.midi_in_name = args.midi_in.clone();
+    }
+    if args.midi_out.is_some() {
+        config.midi_out_name = args.midi_out.clone();
+    }
     match &*config.rack_name {
-        "Rack1" => {
-            let rack = Rack1::new();
-            run_synth(
-                rack,
-                |r| r.lpf1.borrow().out,
-                midi_in,
-                midi_in_port,
-                midi_out_con,
-                cpal_device,
-                cpal_config,
+        "Rack1" => run_rack(Rack1::new, |r| r.pan.borrow().out(), config, &args),
+        "NoiseToaster" => run_rack(NoiseToaster::new, |r| r.vca.borrow().out, config, &args),
+        "Sequencer" => run_rack(Sequencer::new, |r| r.vca.borrow().out, config, &args),
+        "Dynamic" => {
+            let patch = config.patch.clone();
+            // Report patch errors before starting
+            DynRack::new(&patch)?;
+            run_rack(
+                move || DynRack::new(&patch).unwrap(),
+                |r| r.out_stereo(),
                 config,
-            )?;
+                &args,
+            )
         }
-        "NoiseToaster" => {
-            let rack = NoiseToaster::new();
-            run_synth(
-                rack,
-                |r| r.vca.borrow().out,
-                midi_in,
-                midi_in_port,
-                midi_out_con,
-                cpal_device,
-                cpal_config,
-                config,
-            )?;
+        _ => Err(anyhow::anyhow!("Undefined rack name: {}", config.rack_name)),
+    }
+}
+
+/// Run the rack as is, or as voices of `Poly` if [poly] is configured
+fn run_rack<R: Rack + Send + 'static, F: Frame>(
+    new_rack: impl Fn() -> R,
+    rack_out: impl Fn(&R) -> F + Send + 'static,
+    config: rustsynth::config::Config,
+    args: &Args,
+) -> Result<()> {
+    match &config.poly {
+        None => start(new_rack(), rack_out, config, args),
+        Some(poly) => {
+            let note_inputs =
+                rustsynth::config::note_inputs(&config, &new_rack().state_definition())?;
+            let rack = Poly::new(poly.voices, new_rack, rack_out, note_inputs, poly.stealing);
+            start(rack, |p| p.out(), config, args)
         }
-        _ => {
-            return Err(anyhow::anyhow!("Undefined rack name: {}", config.rack_name));
+    }
+}
+
+/// Render offline if --render is given, otherwise play with MIDI and the audio device
+fn start<R: Rack + Send + 'static, F: Frame>(
+    rack: R,
+    rack_out: impl Fn(&R) -> F + Send + 'static,
+    config: rustsynth::config::Config,
+    args: &Args,
+) -> Result<()> {
+    if let Some(path) = &args.render {
+        let mut input = rack.new_input();
+        if let Some(preset) = &config.presets.startup {
+            load_preset(preset, &rack.state_definition(), &mut input)?;
         }
+        let sample_rate = args.sample_rate.unwrap_or(44_100);
+        let len = (args.duration * sample_rate as f32) as usize;
+        let script = Script::new(input);
+        render_to_wav(
+            path,
+            SampleFormat::I16,
+            &rack,
+            rack_out,
+            &script,
+            sample_rate,
+            len,
+        )?;
+        println!("Rendered {} samples to {}", len, path);
+        return Ok(());
     }
-    Ok(())
+    let midi_out_con = setup_midi_output_connection(&config.midi_out_name)?;
+    let (midi_in, midi_in_port) = setup_midi_input(&config.midi_in_name)?;
+    let cpal_device = setup_cpal_device(&args.host, &args.device)?;
+    let cpal_config = setup_cpal_config(&cpal_device, args.sample_rate, args.buffer_size)?;
+    run_synth(
+        rack,
+        rack_out,
+        midi_in,
+        midi_in_port,
+        midi_out_con,
+        cpal_device,
+        cpal_config,
+        config,
+        &args.config,
+    )
 }
 
 fn list_available_midi_ports<T: midir::MidiIO>(io: &T, kind: &str) -> Result<()> {
@@ -204,7 +545,6 @@ fn list_available_midi_ports<T: midir::MidiIO>(io: &T, kind: &str) -> Result<()>
 
 fn setup_midi_output_connection(name: &Option<String>) -> Result<midir::MidiOutputConnection> {
     let output = midir::MidiOutput::new("midir")?;
-    list_available_midi_ports(&output, "output")?;
     let ports = output.ports();
     let port = if let Some(name) = name {
         ports
@@ -224,7 +564,6 @@ fn setup_midi_output_connection(name: &Option<String>) -> Result<midir::MidiOutp
 
 fn setup_midi_input(name: &Option<String>) -> Result<(midir::MidiInput, midir::MidiInputPort)> {
     let mut input = midir::MidiInput::new("midi_input")?;
-    list_available_midi_ports(&input, "input")?;
     let ports = input.ports();
     let port = if let Some(name) = name {
         ports
@@ -241,43 +580,101 @@ fn setup_midi_input(name: &Option<String>) -> Result<(midir::MidiInput, midir::M
     Ok((input, port.clone()))
 }
 
-fn setup_cpal_device() -> Result<cpal::Device> {
-    let host = cpal::default_host();
-    println!("Avaliable devices:");
-    for device in host.output_devices()? {
-        println!("* {}", device.name()?);
+fn setup_cpal_host(name: &Option<String>) -> Result<cpal::Host> {
+    match name {
+        None => Ok(cpal::default_host()),
+        Some(name) => {
+            let id = cpal::available_hosts()
+                .into_iter()
+                .find(|id| id.name().eq_ignore_ascii_case(name))
+                .with_context(|| format!("Audio host not available: {}", name))?;
+            Ok(cpal::host_from_id(id)?)
+        }
     }
+}
 
-    let device = host
-        .default_output_device()
-        .context("Default output device not found")?;
+fn list_devices(host: &Option<String>) -> Result<()> {
+    let ids = match host {
+        None => cpal::available_hosts(),
+        Some(_) => vec![setup_cpal_host(host)?.id()],
+    };
+    for id in ids {
+        println!("Host {}:", id.name());
+        for device in cpal::host_from_id(id)?.output_devices()? {
+            println!("* {}", device.name()?);
+            for config in device.supported_output_configs()? {
+                println!("    {:?}", config);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn setup_cpal_device(host: &Option<String>, name: &Option<String>) -> Result<cpal::Device> {
+    let host = setup_cpal_host(host)?;
+    let device = match name {
+        None => host
+            .default_output_device()
+            .context("Default output device not found")?,
+        Some(name) => host
+            .output_devices()?
+            .find(|d| d.name().is_ok_and(|n| &n == name))
+            .with_context(|| format!("Output device not found: {}", name))?,
+    };
     println!("Using device {}", device.name()?);
     Ok(device)
 }
 
-fn setup_cpal_config(cpal_device: &cpal::Device) -> Result<cpal::StreamConfig> {
-    println!("Available output config:");
-    for config in cpal_device.supported_output_configs()? {
-        println!("* {:?}", config);
-    }
-    let output_available = cpal_device.supported_output_configs()?.any(|c| {
-        c.sample_format() == cpal::SampleFormat::F32
-            && c.channels() == 2
-            && c.min_sample_rate() <= cpal::SampleRate(44_100)
-            && c.max_sample_rate() >= cpal::SampleRate(44_100)
-            && match c.buffer_size() {
-                cpal::SupportedBufferSize::Range { min, max } => min <= &441 && &441 <= max,
-                _ => false,
+fn setup_cpal_config(
+    cpal_device: &cpal::Device,
+    sample_rate: Option<u32>,
+    buffer_size: Option<u32>,
+) -> Result<cpal::StreamConfig> {
+    let preferred_rate = match sample_rate {
+        Some(rate) => cpal::SampleRate(rate),
+        None => cpal_device.default_output_config()?.sample_rate(),
+    };
+    // Prefer stereo, but any channel count works
+    let mut candidates = cpal_device
+        .supported_output_configs()?
+        .filter(|c| c.sample_format() == cpal::SampleFormat::F32)
+        .collect::<Vec<_>>();
+    candidates.sort_by_key(|c| c.channels() != 2);
+    let supported = candidates
+        .iter()
+        .find(|c| c.min_sample_rate() <= preferred_rate && preferred_rate <= c.max_sample_rate())
+        .map(|c| c.clone().with_sample_rate(preferred_rate));
+    let supported = match (supported, sample_rate) {
+        (Some(x), _) => x,
+        (None, Some(rate)) => anyhow::bail!("Sample rate {} is not supported", rate),
+        (None, None) => candidates
+            .first()
+            .map(|c| c.clone().with_max_sample_rate())
+            .context("No suitable output available")?,
+    };
+    let sample_rate = supported.sample_rate();
+    let buffer_size = match (supported.buffer_size(), buffer_size) {
+        (cpal::SupportedBufferSize::Range { min, max }, Some(frames)) => {
+            if frames < *min || *max < frames {
+                anyhow::bail!("Buffer size must be {} - {}", min, max);
             }
-    });
-    if !output_available {
-        panic!("No suitable output available")
-    }
+            cpal::BufferSize::Fixed(frames)
+        }
+        (cpal::SupportedBufferSize::Unknown, Some(frames)) => cpal::BufferSize::Fixed(frames),
+        // 10ms buffer if the device allows it
+        (cpal::SupportedBufferSize::Range { min, max }, None)
+            if *min <= sample_rate.0 / 100 && sample_rate.0 / 100 <= *max =>
+        {
+            cpal::BufferSize::Fixed(sample_rate.0 / 100)
+        }
+        (_, None) => cpal::BufferSize::Default,
+    };
     let cpal_config = cpal::StreamConfig {
-        channels: 2,
-        sample_rate: cpal::SampleRate(44_100),
-        buffer_size: cpal::BufferSize::Fixed(441),
+        channels: supported.channels(),
+        sample_rate,
+        buffer_size,
     };
+    println!("Using output config {:?}", cpal_config);
     Ok(cpal_config)
 }
 
@@ -300,53 +697,208 @@ fn output<S>(
     })
 }
 
+/// Recall or save the preset if the key is assigned to a preset slot
+fn preset_key<S: Clone>(
+    presets: &PresetConfig,
+    key: &Key,
+    value: u8,
+    state_definition: &StateDefinition<S>,
+    input: &mut S,
+) -> bool {
+    let pressed = 0x40 <= value;
+    let mut assigned = false;
+    for slot in presets.slots.iter() {
+        if slot.recall.as_ref() == Some(key) {
+            assigned = true;
+            if pressed {
+                match load_preset(&slot.file, state_definition, input) {
+                    Ok(()) => println!("Preset loaded: {}", slot.file),
+                    Err(err) => println!("Error: {:#}", err),
+                }
+            }
+        }
+        if slot.save.as_ref() == Some(key) {
+            assigned = true;
+            if pressed {
+                match save_preset(&slot.file, state_definition, input) {
+                    Ok(()) => println!("Preset saved: {}", slot.file),
+                    Err(err) => println!("Error: {:#}", err),
+                }
+            }
+        }
+    }
+    assigned
+}
+
+/// Key mappings of the config
+fn setup_mapping<S>(
+    config: &rustsynth::config::Config,
+    state_definition: &Arc<StateDefinition<S>>,
+) -> Result<(StateInput<S>, StateOutput<S>)> {
+    let mut state_in = StateInput::new(state_definition.clone());
+    let mut state_out = StateOutput::new(state_definition.clone());
+    rustsynth::config::setup_state_io(config, &mut state_in, &mut state_out)?;
+    for warning in rustsynth::config::validate(config, state_definition) {
+        println!("Warning: {}", warning);
+    }
+    Ok((state_in, state_out))
+}
+
+/// Applies MIDI messages to the input.
+/// Shared with the main thread, which swaps the mappings when the config is reloaded.
+struct Controller<S> {
+    input: S,
+    input_writer: TripleBufferWriter<S>,
+    state_in: StateInput<S>,
+    state_out: StateOutput<S>,
+    presets: PresetConfig,
+    midi_out: midir::MidiOutputConnection,
+}
+impl<S: Input> Controller<S> {
+    fn process(&mut self, message: &MidiMessage) -> Result<()> {
+        match *message {
+            MidiMessage::ControlChange { ch: 0, num, value } => {
+                let key = Key::ControlChange(num);
+                if !preset_key(
+                    &self.presets,
+                    &key,
+                    value,
+                    self.state_in.state_definition(),
+                    &mut self.input,
+                ) {
+                    self.state_in.update_state(&mut self.input, key, value);
+                }
+            }
+            MidiMessage::NoteOn {
+                ch: 0,
+                note,
+                velocity,
+            } => {
+                self.state_in.note_on(&mut self.input, note, velocity);
+            }
+            MidiMessage::NoteOff { ch: 0, note, .. } => {
+                self.state_in.note_off(&mut self.input, note);
+            }
+            _ => {}
+        }
+        self.input_writer.publish(&self.input);
+        output(&self.state_out, &self.input, &mut self.midi_out)
+    }
+    /// Replace the mappings. Current input values and held notes are kept.
+    fn set_mapping(
+        &mut self,
+        mut state_in: StateInput<S>,
+        state_out: StateOutput<S>,
+        presets: PresetConfig,
+    ) -> Result<()> {
+        state_in.take_held_notes(&mut self.state_in);
+        state_in.take_takeover(&mut self.state_in);
+        self.state_in = state_in;
+        self.state_out = state_out;
+        self.presets = presets;
+        output(&self.state_out, &self.input, &mut self.midi_out)
+    }
+}
+
+/// Reload [keys], [notes] and [presets] of the config file on change.
+/// Other sections are applied on restart. With [poly], so are [notes] and the note
+/// mappings in [keys], since the voices are built from them.
+fn watch_config<S: Input>(
+    path: &str,
+    state_definition: &Arc<StateDefinition<S>>,
+    controller: &Mutex<Controller<S>>,
+    poly: bool,
+) -> ! {
+    let mut watcher = FileWatcher::new(path);
+    loop {
+        std::thread::sleep(std::time::Duration::from_millis(500));
+        if !watcher.changed() {
+            continue;
+        }
+        println!("Reloading {}", path);
+        let result = rustsynth::config::load_config(path).and_then(|config| {
+            let (state_in, state_out) = setup_mapping(&config, state_definition)?;
+            controller
+                .lock()
+                .unwrap()
+                .set_mapping(state_in, state_out, config.presets)
+        });
+        match result {
+            Ok(()) if poly => println!(
+                "Reloaded {} ([notes] and note mappings in [keys] need a restart with [poly])",
+                path
+            ),
+            Ok(()) => println!("Reloaded {}", path),
+            // Keep running with the previous mappings
+            Err(err) => println!("Error: {:#}", err),
+        }
+    }
+}
+
 #[allow(clippy::too_many_arguments)]
-fn run_synth<R: Rack + Send + 'static>(
+fn run_synth<R: Rack + Send + 'static, F: Frame>(
     rack: R,
-    rack_out: impl Fn(&R) -> f32 + Send + 'static,
+    rack_out: impl Fn(&R) -> F + Send + 'static,
     midi_in: midir::MidiInput,
     midi_in_port: midir::MidiInputPort,
     mut midi_out: midir::MidiOutputConnection,
     device: cpal::Device,
     stream_config: cpal::StreamConfig,
     config: rustsynth::config::Config,
+    config_path: &str,
 ) -> Result<()> {
-    let input = std::sync::Arc::new(std::sync::Mutex::new(R::new_input()));
-    let state_definition = {
-        use rustsynth::input::Input;
-        R::Input::new_state_definition()
-    };
-    let (mut state_in, mut state_out) = state_definition.into_io();
-    rustsynth::config::setup_state_io(&config, &mut state_in, &mut state_out)?;
-    dbg!(&state_in);
-    dbg!(&state_out);
-    // setup_state_io(&mut state_in, &mut state_out)?;
-    output(&state_out, &*input.lock().unwrap(), &mut midi_out)?;
+    // The MIDI thread owns the input and publishes a copy on each change.
+    let mut input = rack.new_input();
+    let (mut input_writer, mut input_reader) = triple_buffer(input.clone());
+    let state_definition = Arc::new(rack.state_definition());
+    let poly = config.poly.is_some();
+    let (state_in, state_out) = setup_mapping(&config, &state_definition)?;
+    if let Some(path) = &config.presets.startup {
+        load_preset(path, &state_definition, &mut input)?;
+    }
+    input_writer.publish(&input);
+    output(&state_out, &input, &mut midi_out)?;
+    let mut smoother = Smoother::new(
+        rack.state_definition(),
+        &config.smoothing,
+        stream_config.sample_rate.0,
+        input.clone(),
+    )?;
+    let controller = Arc::new(Mutex::new(Controller {
+        input,
+        input_writer,
+        state_in,
+        state_out,
+        presets: config.presets,
+        midi_out,
+    }));
+    let mut midi_clock = MidiClock::new();
+    let (mut transport_writer, mut transport_reader) = triple_buffer(Transport::default());
     let midi_in_port_name = midi_in.port_name(&midi_in_port)?;
     let _in_con = midi_in
         .connect(
             &midi_in_port,
             &midi_in_port_name,
             {
-                let input = std::sync::Arc::clone(&input);
+                let mut parser = MidiParser::new();
+                let controller = controller.clone();
                 move |stamp, message, _| {
-                    print!("{:10}", stamp);
-                    let message = MidiMessage::try_from(message);
+                    let message = parser.parse(message);
                     match message {
                         Ok(message) => {
-                            println!("Message: {:0X?}", message);
-                            let input = {
-                                let mut input = input.lock().unwrap();
-                                if let MidiMessage::ControlChange { ch: 0, num, value } = message {
-                                    state_in.update_state(
-                                        &mut input,
-                                        Key::ControlChange(num),
-                                        value,
-                                    );
+                            if midi_clock.process(stamp, &message) {
+                                transport_writer.publish(midi_clock.transport());
+                                if message == MidiMessage::TimingClock {
+                                    return;
                                 }
-                                input.clone()
-                            };
-                            output(&state_out, &input, &mut midi_out).expect("LED update failed");
+                            }
+                            if message == MidiMessage::ActiveSensing {
+                                return;
+                            }
+                            println!("{:10} Message: {:0X?}", stamp, message);
+                            if let Err(err) = controller.lock().unwrap().process(&message) {
+                                println!("Error: {:#}", err);
+                            }
                         }
                         Err(err) => println!("Error: {:?}", err),
                     };
@@ -356,20 +908,21 @@ fn run_synth<R: Rack + Send + 'static>(
         )
         .map_err(SyncError::new)?;
 
+    let mut ctx = ProcessContext::new(stream_config.sample_rate.0);
+    let channels = stream_config.channels as usize;
     let stream = device.build_output_stream(
         &stream_config,
-        {
-            let input = std::sync::Arc::clone(&input);
-            move |data: &mut [f32], _| {
-                let input = input.lock().unwrap();
-                let input = &*input;
-                for frame in data.chunks_mut(2) {
-                    rack.update(input);
-                    let value = rack_out(&rack);
-                    for sample in frame.iter_mut() {
-                        *sample = value;
-                    }
-                }
+        move |data: &mut [f32], _| {
+            if let Some(input) = input_reader.read_updated() {
+                smoother.set_input(input);
+            }
+            if let Some(transport) = transport_reader.read_updated() {
+                ctx.transport.sync(transport);
+            }
+            for frame in data.chunks_mut(channels) {
+                rack.update(smoother.next_sample(), &ctx);
+                rack_out(&rack).write_to(frame);
+                ctx.transport.advance(ctx.sample_duration());
             }
         },
         |err| {
@@ -378,8 +931,98 @@ fn run_synth<R: Rack + Send + 'static>(
     )?;
     stream.play()?;
 
-    loop {
-        std::thread::sleep(std::time::Duration::from_millis(2000));
-        let _lock = dbg!(input.lock());
+    watch_config(config_path, &state_definition, &controller, poly)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Args> {
+        Args::parse(args.iter().map(|s| s.to_string()))
+    }
+
+    /// Warnings of the config for the state definition
+    fn check_mapping<S>(
+        config: &rustsynth::config::Config,
+        state_definition: StateDefinition<S>,
+    ) -> Vec<String> {
+        let (state_in, _) = setup_mapping(config, &Arc::new(state_definition)).unwrap();
+        rustsynth::config::validate(config, state_in.state_definition())
+    }
+
+    /// All config files in the repository against their racks.
+    /// Unmapped fields are fine, but a CC number can't be shared.
+    #[test]
+    fn test_load_all() {
+        let dir = env!("CARGO_MANIFEST_DIR");
+        let mut count = 0;
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_none_or(|e| e != "toml") || path.ends_with("Cargo.toml") {
+                continue;
+            }
+            let config = rustsynth::config::load_config(path.to_str().unwrap()).unwrap();
+            let warnings = match &*config.rack_name {
+                "Rack1" => check_mapping(&config, Rack1::new().state_definition()),
+                "NoiseToaster" => check_mapping(&config, NoiseToaster::new().state_definition()),
+                "Sequencer" => check_mapping(&config, Sequencer::new().state_definition()),
+                "Dynamic" => check_mapping(
+                    &config,
+                    DynRack::new(&config.patch).unwrap().state_definition(),
+                ),
+                name => panic!("Undefined rack name: {}", name),
+            };
+            let duplicates = warnings
+                .iter()
+                .filter(|w| w.contains("is mapped to multiple"))
+                .collect::<Vec<_>>();
+            assert!(duplicates.is_empty(), "{:?}: {:?}", path, duplicates);
+            count += 1;
+        }
+        assert!(count > 0);
+    }
+
+    #[test]
+    fn test_args() {
+        let args = parse(&[]).unwrap();
+        assert_eq!(args.command, Command::Run);
+        assert_eq!(args.config, "noisetoaster-nanokontrol2.toml");
+
+        let args = parse(&[
+            "--render",
+            "out.wav",
+            "rack1-poly.toml",
+            "--duration",
+            "2.5",
+            "--sample-rate",
+            "48000",
+            "--midi-in",
+            "Keyboard",
+        ])
+        .unwrap();
+        assert_eq!(args.config, "rack1-poly.toml");
+        assert_eq!(args.render.as_deref(), Some("out.wav"));
+        assert_eq!(args.duration, 2.5);
+        assert_eq!(args.sample_rate, Some(48_000));
+        assert_eq!(args.midi_in.as_deref(), Some("Keyboard"));
+
+        assert_eq!(
+            parse(&["list-devices", "--host", "JACK"]).unwrap().command,
+            Command::ListDevices
+        );
+        assert_eq!(
+            parse(&["list-midi-ports"]).unwrap().command,
+            Command::ListMidiPorts
+        );
+
+        let error = |args: &[&str]| parse(args).err().unwrap().to_string();
+        assert_eq!(error(&["--render"]), "--render requires a value");
+        assert_eq!(
+            error(&["--buffer-size", "x"]),
+            "Invalid value for --buffer-size: x"
+        );
+        assert_eq!(error(&["--foo"]), "Unknown option: --foo");
+        assert_eq!(error(&["a.toml", "b.toml"]), "Unexpected argument: b.toml");
     }
 }
@@ -1,9 +1,73 @@
+#[derive(PartialEq, Eq, Clone)]
 pub enum MidiMessage {
     Unknown(Vec<u8>),
-    ControlChange { ch: u8, num: u8, value: u8 },
+    NoteOff {
+        ch: u8,
+        note: u8,
+        velocity: u8,
+    },
+    NoteOn {
+        ch: u8,
+        note: u8,
+        velocity: u8,
+    },
+    PolyPressure {
+        ch: u8,
+        note: u8,
+        value: u8,
+    },
+    ControlChange {
+        ch: u8,
+        num: u8,
+        value: u8,
+    },
+    ChannelMode {
+        ch: u8,
+        mode: ChannelMode,
+    },
+    ProgramChange {
+        ch: u8,
+        program: u8,
+    },
+    ChannelPressure {
+        ch: u8,
+        value: u8,
+    },
+    /// 0x0000 - 0x3FFF, center = 0x2000
+    PitchBend {
+        ch: u8,
+        value: u16,
+    },
     SysEx(Vec<u8>),
+    TimeCodeQuarterFrame(u8),
+    /// MIDI beats (= 6 MIDI clocks) since the start of the song
+    SongPosition(u16),
+    SongSelect(u8),
+    TuneRequest,
+    TimingClock,
+    Start,
+    Continue,
+    Stop,
+    ActiveSensing,
+    SystemReset,
 }
 
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ChannelMode {
+    AllSoundOff,
+    ResetAllControllers,
+    LocalControl(bool),
+    AllNotesOff,
+    OmniOff,
+    OmniOn,
+    /// Number of channels (0 = as many as the receiver can handle)
+    MonoOn(u8),
+    PolyOn,
+}
+
+/// Velocity reported for Note On messages with velocity 0
+const DEFAULT_NOTE_OFF_VELOCITY: u8 = 0x40;
+
 #[derive(Debug)]
 pub struct MidiMessageParseError {}
 fn get_at(value: &[u8], index: usize) -> std::result::Result<u8, MidiMessageParseError> {
@@ -13,19 +77,50 @@ fn get_at(value: &[u8], index: usize) -> std::result::Result<u8, MidiMessagePars
         Err(MidiMessageParseError {})
     }
 }
+fn get_u14_at(value: &[u8], index: usize) -> std::result::Result<u16, MidiMessageParseError> {
+    let lsb = get_at(value, index)? as u16;
+    let msb = get_at(value, index + 1)? as u16;
+    Ok((msb & 0x7F) << 7 | (lsb & 0x7F))
+}
 impl std::convert::TryFrom<&[u8]> for MidiMessage {
     type Error = MidiMessageParseError;
     fn try_from(value: &[u8]) -> std::result::Result<Self, Self::Error> {
         let status = get_at(value, 0)?;
+        if status < 0x80 {
+            // data byte without status (running status is handled by MidiParser)
+            return Err(MidiMessageParseError {});
+        }
         let kind = status & 0xF0;
         let ch = status & 0x0F;
         match kind {
-            #[allow(clippy::if_same_then_else)]
+            0x80 => Ok(MidiMessage::NoteOff {
+                ch,
+                note: get_at(value, 1)?,
+                velocity: get_at(value, 2)?,
+            }),
+            0x90 => {
+                let note = get_at(value, 1)?;
+                let velocity = get_at(value, 2)?;
+                if velocity == 0 {
+                    Ok(MidiMessage::NoteOff {
+                        ch,
+                        note,
+                        velocity: DEFAULT_NOTE_OFF_VELOCITY,
+                    })
+                } else {
+                    Ok(MidiMessage::NoteOn { ch, note, velocity })
+                }
+            }
+            0xA0 => Ok(MidiMessage::PolyPressure {
+                ch,
+                note: get_at(value, 1)?,
+                value: get_at(value, 2)?,
+            }),
             0xB0 => {
                 let control = get_at(value, 1)?;
+                let control_value = get_at(value, 2)?;
                 if control <= 0x77 {
                     // control change
-                    let control_value = get_at(value, 2)?;
                     Ok(MidiMessage::ControlChange {
                         ch,
                         num: control,
@@ -33,34 +128,324 @@ impl std::convert::TryFrom<&[u8]> for MidiMessage {
                     })
                 } else if control <= 0x7F {
                     // channel message
-                    Ok(MidiMessage::Unknown(value.to_vec()))
+                    let mode = match control {
+                        0x78 => ChannelMode::AllSoundOff,
+                        0x79 => ChannelMode::ResetAllControllers,
+                        0x7A => ChannelMode::LocalControl(0x40 <= control_value),
+                        0x7B => ChannelMode::AllNotesOff,
+                        0x7C => ChannelMode::OmniOff,
+                        0x7D => ChannelMode::OmniOn,
+                        0x7E => ChannelMode::MonoOn(control_value),
+                        _ => ChannelMode::PolyOn,
+                    };
+                    Ok(MidiMessage::ChannelMode { ch, mode })
                 } else {
                     // ???
                     Ok(MidiMessage::Unknown(value.to_vec()))
                 }
             }
-            0xF0 => {
-                if value[value.len() - 1] == 0xF7 {
-                    Ok(MidiMessage::SysEx(value[1..value.len() - 1].to_vec()))
-                } else {
-                    Ok(MidiMessage::SysEx(value[1..].to_vec()))
+            0xC0 => Ok(MidiMessage::ProgramChange {
+                ch,
+                program: get_at(value, 1)?,
+            }),
+            0xD0 => Ok(MidiMessage::ChannelPressure {
+                ch,
+                value: get_at(value, 1)?,
+            }),
+            0xE0 => Ok(MidiMessage::PitchBend {
+                ch,
+                value: get_u14_at(value, 1)?,
+            }),
+            _ => match status {
+                0xF0 => {
+                    if value[value.len() - 1] == 0xF7 {
+                        Ok(MidiMessage::SysEx(value[1..value.len() - 1].to_vec()))
+                    } else {
+                        Ok(MidiMessage::SysEx(value[1..].to_vec()))
+                    }
                 }
-            }
-            _ => Ok(MidiMessage::Unknown(value.to_vec())),
+                0xF1 => Ok(MidiMessage::TimeCodeQuarterFrame(get_at(value, 1)?)),
+                0xF2 => Ok(MidiMessage::SongPosition(get_u14_at(value, 1)?)),
+                0xF3 => Ok(MidiMessage::SongSelect(get_at(value, 1)?)),
+                0xF6 => Ok(MidiMessage::TuneRequest),
+                0xF8 => Ok(MidiMessage::TimingClock),
+                0xFA => Ok(MidiMessage::Start),
+                0xFB => Ok(MidiMessage::Continue),
+                0xFC => Ok(MidiMessage::Stop),
+                0xFE => Ok(MidiMessage::ActiveSensing),
+                0xFF => Ok(MidiMessage::SystemReset),
+                _ => Ok(MidiMessage::Unknown(value.to_vec())),
+            },
+        }
+    }
+}
+
+/// Stateful parser that supports running status.
+#[derive(Debug, Default)]
+pub struct MidiParser {
+    running_status: Option<u8>,
+    buf: Vec<u8>,
+}
+impl MidiParser {
+    pub fn new() -> MidiParser {
+        Default::default()
+    }
+    pub fn parse(
+        &mut self,
+        value: &[u8],
+    ) -> std::result::Result<MidiMessage, MidiMessageParseError> {
+        let status = get_at(value, 0)?;
+        if status < 0x80 {
+            let running_status = self.running_status.ok_or(MidiMessageParseError {})?;
+            self.buf.clear();
+            self.buf.push(running_status);
+            self.buf.extend_from_slice(value);
+            return MidiMessage::try_from(&self.buf[..]);
+        }
+        if status < 0xF0 {
+            self.running_status = Some(status);
+        } else if status < 0xF8 {
+            // system common messages cancel running status, real-time messages don't
+            self.running_status = None;
         }
+        MidiMessage::try_from(value)
     }
 }
+
 impl std::fmt::Debug for MidiMessage {
     fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::result::Result<(), std::fmt::Error> {
         match self {
             MidiMessage::Unknown(value) => fmt.write_fmt(format_args!("Unknown({:02X?})", value)),
             MidiMessage::SysEx(value) => fmt.write_fmt(format_args!("SysEx({:02X?})", value)),
+            MidiMessage::NoteOff { ch, note, velocity } => fmt
+                .debug_struct("NoteOff")
+                .field("ch", ch)
+                .field("note", note)
+                .field("velocity", velocity)
+                .finish(),
+            MidiMessage::NoteOn { ch, note, velocity } => fmt
+                .debug_struct("NoteOn")
+                .field("ch", ch)
+                .field("note", note)
+                .field("velocity", velocity)
+                .finish(),
+            MidiMessage::PolyPressure { ch, note, value } => fmt
+                .debug_struct("PolyPressure")
+                .field("ch", ch)
+                .field("note", note)
+                .field("value", value)
+                .finish(),
             MidiMessage::ControlChange { ch, num, value } => fmt
                 .debug_struct("ControlChange")
                 .field("ch", ch)
                 .field("num", num)
                 .field("value", value)
                 .finish(),
+            MidiMessage::ChannelMode { ch, mode } => fmt
+                .debug_struct("ChannelMode")
+                .field("ch", ch)
+                .field("mode", mode)
+                .finish(),
+            MidiMessage::ProgramChange { ch, program } => fmt
+                .debug_struct("ProgramChange")
+                .field("ch", ch)
+                .field("program", program)
+                .finish(),
+            MidiMessage::ChannelPressure { ch, value } => fmt
+                .debug_struct("ChannelPressure")
+                .field("ch", ch)
+                .field("value", value)
+                .finish(),
+            MidiMessage::PitchBend { ch, value } => fmt
+                .debug_struct("PitchBend")
+                .field("ch", ch)
+                .field("value", value)
+                .finish(),
+            MidiMessage::TimeCodeQuarterFrame(value) => fmt
+                .debug_tuple("TimeCodeQuarterFrame")
+                .field(value)
+                .finish(),
+            MidiMessage::SongPosition(value) => {
+                fmt.debug_tuple("SongPosition").field(value).finish()
+            }
+            MidiMessage::SongSelect(value) => fmt.debug_tuple("SongSelect").field(value).finish(),
+            MidiMessage::TuneRequest => fmt.write_str("TuneRequest"),
+            MidiMessage::TimingClock => fmt.write_str("TimingClock"),
+            MidiMessage::Start => fmt.write_str("Start"),
+            MidiMessage::Continue => fmt.write_str("Continue"),
+            MidiMessage::Stop => fmt.write_str("Stop"),
+            MidiMessage::ActiveSensing => fmt.write_str("ActiveSensing"),
+            MidiMessage::SystemReset => fmt.write_str("SystemReset"),
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(value: &[u8]) -> MidiMessage {
+        MidiMessage::try_from(value).unwrap()
+    }
+
+    #[test]
+    fn test_note() {
+        assert_eq!(
+            parse(&[0x91, 0x3C, 0x64]),
+            MidiMessage::NoteOn {
+                ch: 1,
+                note: 0x3C,
+                velocity: 0x64
+            }
+        );
+        assert_eq!(
+            parse(&[0x82, 0x3C, 0x10]),
+            MidiMessage::NoteOff {
+                ch: 2,
+                note: 0x3C,
+                velocity: 0x10
+            }
+        );
+        assert_eq!(
+            parse(&[0x90, 0x3C, 0x00]),
+            MidiMessage::NoteOff {
+                ch: 0,
+                note: 0x3C,
+                velocity: 0x40
+            }
+        );
+        assert!(MidiMessage::try_from(&[0x90, 0x3C][..]).is_err());
+    }
+
+    #[test]
+    fn test_pressure() {
+        assert_eq!(
+            parse(&[0xA3, 0x40, 0x7F]),
+            MidiMessage::PolyPressure {
+                ch: 3,
+                note: 0x40,
+                value: 0x7F
+            }
+        );
+        assert_eq!(
+            parse(&[0xD4, 0x20]),
+            MidiMessage::ChannelPressure { ch: 4, value: 0x20 }
+        );
+    }
+
+    #[test]
+    fn test_control_change() {
+        assert_eq!(
+            parse(&[0xB0, 0x10, 0x7F]),
+            MidiMessage::ControlChange {
+                ch: 0,
+                num: 0x10,
+                value: 0x7F
+            }
+        );
+    }
+
+    #[test]
+    fn test_channel_mode() {
+        let mode = |num, value| match parse(&[0xB5, num, value]) {
+            MidiMessage::ChannelMode { ch: 5, mode } => mode,
+            m => panic!("{:?}", m),
+        };
+        assert_eq!(mode(0x78, 0), ChannelMode::AllSoundOff);
+        assert_eq!(mode(0x79, 0), ChannelMode::ResetAllControllers);
+        assert_eq!(mode(0x7A, 0x7F), ChannelMode::LocalControl(true));
+        assert_eq!(mode(0x7A, 0x00), ChannelMode::LocalControl(false));
+        assert_eq!(mode(0x7B, 0), ChannelMode::AllNotesOff);
+        assert_eq!(mode(0x7C, 0), ChannelMode::OmniOff);
+        assert_eq!(mode(0x7D, 0), ChannelMode::OmniOn);
+        assert_eq!(mode(0x7E, 2), ChannelMode::MonoOn(2));
+        assert_eq!(mode(0x7F, 0), ChannelMode::PolyOn);
+    }
+
+    #[test]
+    fn test_program_change() {
+        assert_eq!(
+            parse(&[0xC6, 0x05]),
+            MidiMessage::ProgramChange { ch: 6, program: 5 }
+        );
+    }
+
+    #[test]
+    fn test_pitch_bend() {
+        assert_eq!(
+            parse(&[0xE7, 0x00, 0x40]),
+            MidiMessage::PitchBend {
+                ch: 7,
+                value: 0x2000
+            }
+        );
+        assert_eq!(
+            parse(&[0xE0, 0x7F, 0x7F]),
+            MidiMessage::PitchBend {
+                ch: 0,
+                value: 0x3FFF
+            }
+        );
+        assert_eq!(
+            parse(&[0xE0, 0x01, 0x00]),
+            MidiMessage::PitchBend { ch: 0, value: 1 }
+        );
+    }
+
+    #[test]
+    fn test_system() {
+        assert_eq!(
+            parse(&[0xF0, 0x42, 0x40, 0xF7]),
+            MidiMessage::SysEx(vec![0x42, 0x40])
+        );
+        assert_eq!(
+            parse(&[0xF1, 0x12]),
+            MidiMessage::TimeCodeQuarterFrame(0x12)
+        );
+        assert_eq!(parse(&[0xF2, 0x10, 0x01]), MidiMessage::SongPosition(0x90));
+        assert_eq!(parse(&[0xF3, 0x03]), MidiMessage::SongSelect(3));
+        assert_eq!(parse(&[0xF6]), MidiMessage::TuneRequest);
+        assert_eq!(parse(&[0xF8]), MidiMessage::TimingClock);
+        assert_eq!(parse(&[0xFA]), MidiMessage::Start);
+        assert_eq!(parse(&[0xFB]), MidiMessage::Continue);
+        assert_eq!(parse(&[0xFC]), MidiMessage::Stop);
+        assert_eq!(parse(&[0xFE]), MidiMessage::ActiveSensing);
+        assert_eq!(parse(&[0xFF]), MidiMessage::SystemReset);
+        assert_eq!(parse(&[0xF4]), MidiMessage::Unknown(vec![0xF4]));
+    }
+
+    #[test]
+    fn test_running_status() {
+        let mut parser = MidiParser::new();
+        assert!(parser.parse(&[0x3C, 0x64]).is_err());
+        assert_eq!(
+            parser.parse(&[0x90, 0x3C, 0x64]).unwrap(),
+            MidiMessage::NoteOn {
+                ch: 0,
+                note: 0x3C,
+                velocity: 0x64
+            }
+        );
+        assert_eq!(
+            parser.parse(&[0x3E, 0x64]).unwrap(),
+            MidiMessage::NoteOn {
+                ch: 0,
+                note: 0x3E,
+                velocity: 0x64
+            }
+        );
+        // real-time messages keep running status
+        assert_eq!(parser.parse(&[0xF8]).unwrap(), MidiMessage::TimingClock);
+        assert_eq!(
+            parser.parse(&[0x3C, 0x00]).unwrap(),
+            MidiMessage::NoteOff {
+                ch: 0,
+                note: 0x3C,
+                velocity: 0x40
+            }
+        );
+        // system common messages cancel it
+        assert_eq!(parser.parse(&[0xF6]).unwrap(), MidiMessage::TuneRequest);
+        assert!(parser.parse(&[0x3C, 0x64]).is_err());
+    }
+}
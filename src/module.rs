@@ -1,33 +1,202 @@
-use crate::WaveForm;
+use crate::transport::Transport;
+use crate::{Division, WaveForm};
 use std::marker::PhantomData;
 
 pub trait Rack {
     type Input: crate::input::Input + 'static;
-    fn new_input() -> Self::Input;
-    fn update(&self, input: &Self::Input);
+    fn new_input(&self) -> Self::Input;
+    fn state_definition(&self) -> crate::input::StateDefinition<Self::Input>;
+    fn update(&self, input: &Self::Input, ctx: &ProcessContext);
 }
 pub trait Module<R: Rack> {
-    fn update(&mut self, rack: &R, input: &R::Input);
+    fn update(&mut self, rack: &R, input: &R::Input, ctx: &ProcessContext);
+}
+
+/// Properties of the audio stream shared by all modules.
+#[derive(Debug, Clone)]
+pub struct ProcessContext {
+    pub sample_rate: f32,
+    /// Follows the MIDI clock
+    pub transport: Transport,
+}
+impl ProcessContext {
+    pub fn new(sample_rate: u32) -> ProcessContext {
+        ProcessContext {
+            sample_rate: sample_rate as f32,
+            transport: Transport::default(),
+        }
+    }
+    /// sec
+    pub fn sample_duration(&self) -> f32 {
+        1.0 / self.sample_rate
+    }
 }
 
 #[allow(type_alias_bounds)]
-type In<R: Rack, T> = Box<dyn Fn(&R, &R::Input) -> T + Send>;
+pub type In<R: Rack, T> = Box<dyn Fn(&R, &R::Input) -> T + Send>;
 
-const SAMPLES_PER_SEC: u32 = 44_100;
+/// Output of a rack for one sample: f32 for mono, (left, right) for stereo, [f32; N] for N channels
+pub trait Frame: Copy + Send + 'static {
+    const CHANNELS: usize;
+    const SILENCE: Self;
+    fn channel(&self, i: usize) -> f32;
+    fn mix(self, other: Self) -> Self;
+    /// Largest absolute value of the channels
+    fn peak(&self) -> f32 {
+        (0..Self::CHANNELS).fold(0.0, |p, i| p.max(self.channel(i).abs()))
+    }
+    /// Write to a device frame. Mono is copied to all channels, extra channels are silent.
+    /// If the device has fewer channels, all of them get the average of the frame.
+    fn write_to(&self, out: &mut [f32]) {
+        if out.len() < Self::CHANNELS {
+            let sum = (0..Self::CHANNELS).map(|i| self.channel(i)).sum::<f32>();
+            out.fill(sum / Self::CHANNELS as f32);
+            return;
+        }
+        for (i, sample) in out.iter_mut().enumerate() {
+            *sample = if Self::CHANNELS == 1 {
+                self.channel(0)
+            } else if i < Self::CHANNELS {
+                self.channel(i)
+            } else {
+                0.0
+            };
+        }
+    }
+}
+impl Frame for f32 {
+    const CHANNELS: usize = 1;
+    const SILENCE: f32 = 0.0;
+    fn channel(&self, _i: usize) -> f32 {
+        *self
+    }
+    fn mix(self, other: f32) -> f32 {
+        self + other
+    }
+}
+impl Frame for (f32, f32) {
+    const CHANNELS: usize = 2;
+    const SILENCE: (f32, f32) = (0.0, 0.0);
+    fn channel(&self, i: usize) -> f32 {
+        if i == 0 {
+            self.0
+        } else {
+            self.1
+        }
+    }
+    fn mix(self, other: (f32, f32)) -> (f32, f32) {
+        (self.0 + other.0, self.1 + other.1)
+    }
+}
+impl<const N: usize> Frame for [f32; N] {
+    const CHANNELS: usize = N;
+    const SILENCE: [f32; N] = [0.0; N];
+    fn channel(&self, i: usize) -> f32 {
+        self[i]
+    }
+    fn mix(mut self, other: [f32; N]) -> [f32; N] {
+        self.iter_mut().zip(other).for_each(|(a, b)| *a += b);
+        self
+    }
+}
 
 pub fn restore_freq(min: f32, max: f32, input: f32) -> f32 {
     (min.ln() + input * (max.ln() - min.ln())).exp()
 }
 
+/// Inverse of `restore_freq`
+pub fn normalize_freq(min: f32, max: f32, freq: f32) -> f32 {
+    (freq.ln() - min.ln()) / (max.ln() - min.ln())
+}
+
+/// Equal temperament, A4(69) = 440Hz
+pub fn note_to_freq(note: u8) -> f32 {
+    440.0 * 2.0f32.powf((note as f32 - 69.0) / 12.0)
+}
+
 pub struct VCO<R: Rack> {
     pub _rack: PhantomData<R>,
     // range: 0.0 - 1.0 ( freq_min Hz - freq_max Hz )
     pub in_freq: In<R, f32>,
     pub in_waveform: In<R, WaveForm>,
+    /// Reset the phase on rising edge(<= 0.0 to > 0.0). Connect out_sync of the master for hard sync.
+    /// A value below 1.0 places the reset within the last sample, see `out_sync`.
+    pub in_sync: In<R, f32>,
+    /// Duty cycle of Square, 0.0 - 1.0
+    pub in_pulse_width: In<R, f32>,
+    /// A cycle per division at the tempo of the MIDI clock instead of in_freq.
+    /// While playing, the phase follows the song position, so it's reset on start.
+    pub in_division: In<R, Division>,
     pub phase: f32,
     pub freq_min: f32,
     pub freq_max: f32,
+    /// Suppress aliasing of Sawtooth, Triangle, Square and hard sync with PolyBLEP/PolyBLAMP
+    pub band_limited: bool,
+    pub last_sync: f32,
     pub out: f32,
+    /// 1.0 when the phase wrapped or reset in this sample, 0.0 otherwise
+    pub out_wrap: f32,
+    /// Same as out_wrap, but 1.0 minus the fraction of the sample since the wrap,
+    /// so that a synced VCO resets at the exact time
+    pub out_sync: f32,
+}
+/// PolyBLEP residual of an upward step of 2.0 at t=0. t: phase(0.0 - 1.0), dt: phase increment
+fn poly_blep(t: f32, dt: f32) -> f32 {
+    if t < dt {
+        let x = t / dt;
+        x + x - x * x - 1.0
+    } else if t > 1.0 - dt {
+        let x = (t - 1.0) / dt;
+        x * x + x + x + 1.0
+    } else {
+        0.0
+    }
+}
+/// PolyBLAMP residual of a slope change of 1.0 at t=0, in units of dt
+fn poly_blamp(t: f32, dt: f32) -> f32 {
+    if t < dt {
+        let x = 1.0 - t / dt;
+        x * x * x / 6.0
+    } else if t > 1.0 - dt {
+        let x = (t - 1.0) / dt + 1.0;
+        x * x * x / 6.0
+    } else {
+        0.0
+    }
+}
+/// Waveform without band limiting. phase: 0.0 - 2PI. Noise has no fixed waveform and is 0.0.
+fn naive_wave(waveform: WaveForm, phase: f32, pulse_width: f32) -> f32 {
+    let pi: f32 = std::f32::consts::PI;
+    let pi2: f32 = pi * 2.0;
+    let pi12: f32 = pi / 2.0;
+    let pi32: f32 = pi12 * 3.0;
+    match waveform {
+        WaveForm::Sine => phase.sin(),
+        WaveForm::Sawtooth => {
+            if phase < pi {
+                phase / pi
+            } else {
+                (phase - pi) / pi - 1.0
+            }
+        }
+        WaveForm::Triangle => {
+            if phase < pi12 {
+                phase / pi12
+            } else if phase < pi32 {
+                1.0 - (phase - pi12) / pi12
+            } else {
+                (phase - pi32) / pi12 - 1.0
+            }
+        }
+        WaveForm::Square => {
+            if phase < pi2 * pulse_width {
+                1.0
+            } else {
+                -1.0
+            }
+        }
+        WaveForm::Noise => 0.0,
+    }
 }
 impl<R: Rack> Default for VCO<R> {
     fn default() -> Self {
@@ -35,57 +204,101 @@ impl<R: Rack> Default for VCO<R> {
             _rack: PhantomData,
             in_freq: Box::new(|_, _| 0.0),
             in_waveform: Box::new(|_, _| WaveForm::Sine),
+            in_sync: Box::new(|_, _| 0.0),
+            in_pulse_width: Box::new(|_, _| 0.5),
+            in_division: Box::new(|_, _| Division::Free),
             phase: 0.0,
             freq_min: 0.0,
             freq_max: 0.0,
+            band_limited: false,
+            last_sync: 0.0,
             out: 0.0,
+            out_wrap: 0.0,
+            out_sync: 0.0,
         }
     }
 }
 impl<R: Rack> Module<R> for VCO<R> {
-    fn update(&mut self, rack: &R, input: &R::Input) {
+    fn update(&mut self, rack: &R, input: &R::Input, ctx: &ProcessContext) {
         let in_freq = (self.in_freq)(rack, input);
-        let pi: f32 = std::f32::consts::PI;
-        let pi2: f32 = pi * 2.0;
-        let pi12: f32 = pi / 2.0;
-        let pi32: f32 = pi12 * 3.0;
-        let freq = restore_freq(self.freq_min, self.freq_max, in_freq);
-        self.phase += freq * pi2 / SAMPLES_PER_SEC as f32;
-        self.phase %= pi2;
+        let pi2: f32 = std::f32::consts::PI * 2.0;
         let wf = (self.in_waveform)(rack, input);
-        self.out = match wf {
-            WaveForm::Sine => (self.phase).sin(),
-            WaveForm::Sawtooth => {
-                if self.phase < pi {
-                    self.phase / pi
-                } else {
-                    (self.phase - pi) / pi - 1.0
-                }
-            }
-            WaveForm::Triangle => {
-                if self.phase < pi12 {
-                    self.phase / pi12
-                } else if self.phase < pi32 {
-                    1.0 - (self.phase - pi12) / pi12
-                } else {
-                    (self.phase - pi32) / pi12 - 1.0
-                }
+        let in_sync = (self.in_sync)(rack, input);
+        let in_pulse_width = (self.in_pulse_width)(rack, input).clamp(0.0, 1.0);
+        let transport = &ctx.transport;
+        // quarter notes per cycle if synced to the tempo
+        let beats = match transport.bpm {
+            Some(_) => (self.in_division)(rack, input).beats(),
+            None => None,
+        };
+        let freq = match (beats, transport.bpm) {
+            (Some(beats), Some(bpm)) => bpm / 60.0 / beats,
+            _ => restore_freq(self.freq_min, self.freq_max, in_freq),
+        };
+        let increment = freq * pi2 / ctx.sample_rate;
+        // (samples since the reset, step of the output) if hard synced in this sample
+        let mut sync_step = None;
+        if self.last_sync <= 0.0 && 0.0 < in_sync {
+            let elapsed = 1.0 - in_sync.min(1.0);
+            if wf != WaveForm::Noise {
+                let phase = (self.phase + (1.0 - elapsed) * increment) % pi2;
+                let from = naive_wave(wf, phase, in_pulse_width);
+                sync_step = Some((elapsed, naive_wave(wf, 0.0, in_pulse_width) - from));
             }
-            WaveForm::Square => {
-                if self.phase < pi {
-                    1.0
-                } else {
-                    -1.0
-                }
+            self.phase = elapsed * increment;
+            self.out_wrap = 1.0;
+            self.out_sync = 1.0 - elapsed;
+        } else if let (Some(beats), true) = (beats, transport.playing) {
+            let phase = (transport.position / beats as f64).fract() as f32 * pi2;
+            self.out_wrap = if phase < self.phase { 1.0 } else { 0.0 };
+            self.out_sync = self.out_wrap;
+            self.phase = phase;
+        } else {
+            self.phase += increment;
+            if pi2 <= self.phase {
+                self.phase %= pi2;
+                self.out_wrap = 1.0;
+                self.out_sync = (1.0 - self.phase / increment).clamp(f32::EPSILON, 1.0);
+            } else {
+                self.out_wrap = 0.0;
+                self.out_sync = 0.0;
             }
+        }
+        self.last_sync = in_sync;
+        self.out = match wf {
             WaveForm::Noise => {
-                if 0.0 <= self.phase && self.phase < freq * pi2 / SAMPLES_PER_SEC as f32 {
+                if 0.0 <= self.phase && self.phase < freq * pi2 / ctx.sample_rate {
                     let r: f32 = rand::random();
                     -1.0 + r * 2.0
                 } else {
                     self.out
                 }
             }
+            wf => naive_wave(wf, self.phase, in_pulse_width),
+        };
+        if self.band_limited {
+            let t = self.phase / pi2;
+            let dt = (freq / ctx.sample_rate).min(0.5);
+            if let Some((elapsed, step)) = sync_step {
+                // The reset replaces the usual edges of the waveform.
+                // The residual before the step is lost since the last sample is already out.
+                self.out += poly_blep(elapsed * dt, dt) * step / 2.0;
+            } else {
+                match wf {
+                    WaveForm::Sawtooth => {
+                        self.out -= poly_blep((t + 0.5).fract(), dt);
+                    }
+                    WaveForm::Triangle => {
+                        self.out += 8.0 * dt * poly_blamp((t + 0.25).fract(), dt);
+                        self.out -= 8.0 * dt * poly_blamp((t + 0.75).fract(), dt);
+                    }
+                    WaveForm::Square => {
+                        self.out += poly_blep(t, dt);
+                        self.out -= poly_blep((t + 1.0 - in_pulse_width).fract(), dt);
+                    }
+                    WaveForm::Sine | WaveForm::Noise => {}
+                }
+            }
         }
     }
 }
@@ -94,7 +307,9 @@ pub struct EG<R: Rack> {
     pub _rack: PhantomData<R>,
     pub in_gate: In<R, bool>,
     pub in_repeat: In<R, bool>,
-    /// sec
+    /// Restart attack whenever the value changes while the gate is on
+    pub in_trigger: In<R, bool>,
+    /// sec, from 0.0 to 1.0. Retriggered attack takes shorter time.
     pub in_a: In<R, f32>,
     /// sec
     pub in_d: In<R, f32>,
@@ -102,9 +317,15 @@ pub struct EG<R: Rack> {
     pub in_s: In<R, f32>,
     /// sec
     pub in_r: In<R, f32>,
+    /// Curvature of each segment, see `segment_curve`
+    pub in_a_curve: In<R, f32>,
+    pub in_d_curve: In<R, f32>,
+    pub in_r_curve: In<R, f32>,
     pub clock: f32,
     pub state: EGState,
+    /// Output level at the start of the current segment
     pub level: f32,
+    pub last_trigger: bool,
     /// 0.0 - 1.0
     pub out: f32,
 }
@@ -115,102 +336,342 @@ pub enum EGState {
     S,
     R,
 }
+/// Progress of an envelope segment, 0.0 - 1.0. p: elapsed time / segment time.
+/// curve = 0.0: linear.
+/// curve > 0.0: exponential, like a capacitor charging toward a target beyond the end. Fast start, slow end.
+/// curve < 0.0: logarithmic. Slow start, fast end.
+/// Around +-5.0 sounds natural.
+pub fn segment_curve(p: f32, curve: f32) -> f32 {
+    if curve.abs() < 1e-3 {
+        p
+    } else if curve > 0.0 {
+        (1.0 - (-curve * p).exp()) / (1.0 - (-curve).exp())
+    } else {
+        ((-curve * p).exp() - 1.0) / ((-curve).exp() - 1.0)
+    }
+}
 impl<R: Rack> Default for EG<R> {
     fn default() -> Self {
         EG {
             _rack: PhantomData,
             in_gate: Box::new(|_, _| false),
             in_repeat: Box::new(|_, _| false),
+            in_trigger: Box::new(|_, _| false),
             in_a: Box::new(|_, _| 0.0),
             in_d: Box::new(|_, _| 0.0),
             in_s: Box::new(|_, _| 1.0),
             in_r: Box::new(|_, _| 0.0),
+            in_a_curve: Box::new(|_, _| 0.0),
+            in_d_curve: Box::new(|_, _| 0.0),
+            in_r_curve: Box::new(|_, _| 0.0),
             state: EGState::Idle,
             clock: 0.0,
             level: 0.0,
+            last_trigger: false,
             out: 0.0,
         }
     }
 }
+impl<R: Rack> EG<R> {
+    /// Start a segment from the current output
+    fn enter(&mut self, state: EGState) {
+        self.state = state;
+        self.clock = 0.0;
+        self.level = self.out;
+    }
+}
 impl<R: Rack> Module<R> for EG<R> {
-    fn update(&mut self, rack: &R, input: &R::Input) {
+    fn update(&mut self, rack: &R, input: &R::Input, ctx: &ProcessContext) {
         let gate = (self.in_gate)(rack, input);
         let repeat = (self.in_repeat)(rack, input);
         let a = (self.in_a)(rack, input);
         let d = (self.in_d)(rack, input);
         let s = (self.in_s)(rack, input);
         let r = (self.in_r)(rack, input);
+        let trigger = (self.in_trigger)(rack, input);
+        // Attack rate is constant regardless of the start level
+        let attack_time = |level: f32| a * (1.0 - level).clamp(0.0, 1.0);
+        if trigger != self.last_trigger {
+            self.last_trigger = trigger;
+            if gate {
+                self.enter(EGState::A);
+            }
+        }
         match self.state {
             EGState::Idle => {
                 if gate || repeat {
-                    self.state = EGState::A;
-                    self.clock = 0.0;
+                    self.enter(EGState::A);
                 }
             }
             EGState::A => {
                 if !gate && !repeat {
-                    self.state = EGState::R;
-                    self.clock = 0.0;
-                    self.level = self.out;
-                } else if self.clock >= a {
-                    self.state = EGState::D;
-                    self.clock = 0.0;
+                    self.enter(EGState::R);
+                } else if self.clock >= attack_time(self.level) {
+                    self.enter(EGState::D);
                 }
             }
             EGState::D => {
                 if !gate && !repeat {
-                    self.state = EGState::R;
-                    self.clock = 0.0;
-                    self.level = self.out;
+                    self.enter(EGState::R);
                 } else if self.clock >= d {
-                    self.state = EGState::S;
-                    self.clock = 0.0;
+                    self.enter(EGState::S);
                 }
             }
             EGState::S => {
                 if !gate {
-                    self.state = EGState::R;
-                    self.clock = 0.0;
-                    self.level = self.out;
+                    self.enter(EGState::R);
                 }
             }
             EGState::R => {
                 if !gate && self.clock >= r {
-                    self.state = EGState::Idle;
-                    self.clock = 0.0;
-                    self.level = 0.0;
+                    self.enter(EGState::Idle);
                 } else if gate {
-                    self.state = EGState::A;
-                    self.clock = 0.0;
-                    self.level = self.out;
+                    self.enter(EGState::A);
                 }
             }
         }
-        match self.state {
-            EGState::Idle => {
-                self.clock = 0.0;
-                self.out = 0.0;
+        let (target, time, curve) = match self.state {
+            EGState::Idle => (0.0, 0.0, 0.0),
+            EGState::A => (1.0, attack_time(self.level), (self.in_a_curve)(rack, input)),
+            EGState::D => (s, d, (self.in_d_curve)(rack, input)),
+            EGState::S => (s, 0.0, 0.0),
+            EGState::R => (0.0, r, (self.in_r_curve)(rack, input)),
+        };
+        let p = if time > 0.0 {
+            (self.clock / time).min(1.0)
+        } else {
+            1.0
+        };
+        self.out = self.level + (target - self.level) * segment_curve(p, curve);
+        self.clock += ctx.sample_duration();
+    }
+}
+
+/// Breakpoint of `Envelope`: moves to in_level in in_time sec.
+pub struct Segment<R: Rack> {
+    /// 0.0 - 1.0
+    pub in_level: In<R, f32>,
+    /// sec
+    pub in_time: In<R, f32>,
+    /// see `segment_curve`
+    pub in_curve: In<R, f32>,
+}
+impl<R: Rack> Segment<R> {
+    pub fn new(
+        level: impl Fn(&R, &R::Input) -> f32 + Send + 'static,
+        time: impl Fn(&R, &R::Input) -> f32 + Send + 'static,
+        curve: impl Fn(&R, &R::Input) -> f32 + Send + 'static,
+    ) -> Segment<R> {
+        Segment {
+            in_level: Box::new(level),
+            in_time: Box::new(time),
+            in_curve: Box::new(curve),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EnvelopeMode {
+    /// Gate on starts the envelope and all segments are played regardless of the gate
+    OneShot,
+    /// Gate off jumps to the segment after the sustain point
+    Gated,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EnvelopeStage {
+    Idle,
+    Delay,
+    Segment(usize),
+    /// Holding the level of the segment while the gate is on
+    Sustain(usize),
+}
+
+/// Multi-segment envelope: delay, then segments in order.
+/// DAHDSR is segments of attack(1.0), hold(1.0), decay(s) and release(0.0) with sustain = Some(2).
+pub struct Envelope<R: Rack> {
+    pub _rack: PhantomData<R>,
+    pub in_gate: In<R, bool>,
+    /// Restart whenever the value changes (while the gate is on, if Gated)
+    pub in_trigger: In<R, bool>,
+    /// sec
+    pub in_delay: In<R, f32>,
+    pub segments: Vec<Segment<R>>,
+    pub mode: EnvelopeMode,
+    /// Index of the segment to hold at the end, while the gate is on
+    pub sustain: Option<usize>,
+    /// (start, end): Jump to the start of segment `start` at the end of segment `end`.
+    /// While the gate is on, if Gated.
+    pub loop_segments: Option<(usize, usize)>,
+    pub stage: EnvelopeStage,
+    pub clock: f32,
+    /// Output level at the start of the current stage
+    pub level: f32,
+    pub last_gate: bool,
+    pub last_trigger: bool,
+    /// 0.0 - 1.0
+    pub out: f32,
+    /// true for one sample when the last segment finished or looped
+    pub out_eoc: bool,
+}
+impl<R: Rack> Default for Envelope<R> {
+    fn default() -> Self {
+        Envelope {
+            _rack: PhantomData,
+            in_gate: Box::new(|_, _| false),
+            in_trigger: Box::new(|_, _| false),
+            in_delay: Box::new(|_, _| 0.0),
+            segments: Vec::new(),
+            mode: EnvelopeMode::Gated,
+            sustain: None,
+            loop_segments: None,
+            stage: EnvelopeStage::Idle,
+            clock: 0.0,
+            level: 0.0,
+            last_gate: false,
+            last_trigger: false,
+            out: 0.0,
+            out_eoc: false,
+        }
+    }
+}
+impl<R: Rack> Envelope<R> {
+    fn enter(&mut self, stage: EnvelopeStage) {
+        self.stage = if let EnvelopeStage::Segment(i) = stage {
+            if i < self.segments.len() {
+                stage
+            } else {
+                EnvelopeStage::Idle
             }
-            EGState::A => {
-                if a > 0.0 {
-                    self.out = self.level.max(1.0 / a * self.clock);
+        } else {
+            stage
+        };
+        self.clock = 0.0;
+        self.level = self.out;
+    }
+    /// First segment played after the gate off
+    fn release_start(&self) -> usize {
+        match (self.sustain, self.loop_segments) {
+            (Some(i), _) | (None, Some((_, i))) => i + 1,
+            (None, None) => self.segments.len().max(1) - 1,
+        }
+    }
+    fn end_segment(&mut self, i: usize, gate: bool) {
+        let held = gate || self.mode == EnvelopeMode::OneShot;
+        if self.mode == EnvelopeMode::Gated && gate && self.sustain == Some(i) {
+            self.enter(EnvelopeStage::Sustain(i));
+        } else if let Some((start, _)) = self.loop_segments.filter(|(_, end)| *end == i && held) {
+            self.out_eoc = true;
+            self.enter(EnvelopeStage::Segment(start));
+        } else {
+            self.enter(EnvelopeStage::Segment(i + 1));
+            if self.stage == EnvelopeStage::Idle {
+                self.out_eoc = true;
+            }
+        }
+    }
+}
+impl<R: Rack> Module<R> for Envelope<R> {
+    fn update(&mut self, rack: &R, input: &R::Input, ctx: &ProcessContext) {
+        let gate = (self.in_gate)(rack, input);
+        let trigger = (self.in_trigger)(rack, input);
+        let retrigger = trigger != self.last_trigger;
+        let gate_on = gate && !self.last_gate;
+        let gate_off = !gate && self.last_gate;
+        self.last_trigger = trigger;
+        self.last_gate = gate;
+        self.out_eoc = false;
+
+        if gate_on || (retrigger && (gate || self.mode == EnvelopeMode::OneShot)) {
+            self.enter(EnvelopeStage::Delay);
+        } else if gate_off && self.mode == EnvelopeMode::Gated {
+            let release = self.release_start();
+            let before_release = match self.stage {
+                EnvelopeStage::Idle => false,
+                EnvelopeStage::Delay | EnvelopeStage::Sustain(_) => true,
+                EnvelopeStage::Segment(i) => i < release,
+            };
+            if before_release {
+                self.enter(EnvelopeStage::Segment(release));
+            }
+        }
+
+        match self.stage {
+            EnvelopeStage::Idle | EnvelopeStage::Sustain(_) => {}
+            EnvelopeStage::Delay => {
+                if self.clock >= (self.in_delay)(rack, input) {
+                    self.enter(EnvelopeStage::Segment(0));
                 }
             }
-            EGState::D => {
-                if d > 0.0 {
-                    self.out = 1.0 - ((1.0 - s) / d * self.clock);
+            EnvelopeStage::Segment(i) => {
+                if self.clock >= (self.segments[i].in_time)(rack, input) {
+                    self.out = (self.segments[i].in_level)(rack, input);
+                    self.end_segment(i, gate);
                 }
             }
-            EGState::S => {
-                self.out = s;
+        }
+
+        match self.stage {
+            EnvelopeStage::Idle | EnvelopeStage::Delay => {}
+            EnvelopeStage::Sustain(i) => {
+                self.out = (self.segments[i].in_level)(rack, input);
             }
-            EGState::R => {
-                if r > 0.0 {
-                    self.out = (0.0f32).max(self.level - self.clock * self.level / r);
-                }
+            EnvelopeStage::Segment(i) => {
+                let segment = &self.segments[i];
+                let target = (segment.in_level)(rack, input);
+                let time = (segment.in_time)(rack, input);
+                let p = if time > 0.0 {
+                    (self.clock / time).min(1.0)
+                } else {
+                    1.0
+                };
+                let curve = segment_curve(p, (segment.in_curve)(rack, input));
+                self.out = self.level + (target - self.level) * curve;
             }
         }
-        self.clock += 1.0 / SAMPLES_PER_SEC as f32;
+        self.clock += ctx.sample_duration();
+    }
+}
+
+/// Fixed length history of samples
+#[derive(Debug, Clone, Default)]
+pub struct RingBuffer {
+    buf: Vec<f32>,
+    /// Next position to write
+    i: usize,
+}
+impl RingBuffer {
+    pub fn new(len: usize) -> RingBuffer {
+        RingBuffer {
+            buf: vec![0.0; len],
+            i: 0,
+        }
+    }
+    pub fn len(&self) -> usize {
+        self.buf.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+    /// Change the length. Allocates only if the length is changed.
+    pub fn resize(&mut self, len: usize) {
+        if self.buf.len() != len {
+            self.buf.resize(len, 0.0);
+            self.i %= len.max(1);
+        }
+    }
+    pub fn push(&mut self, value: f32) {
+        self.buf[self.i] = value;
+        self.i += 1;
+        self.i %= self.buf.len();
+    }
+    /// n=0 -> newest, n=1 -> z^-1, ..., n=len()-1
+    pub fn at(&self, n: usize) -> f32 {
+        if n < self.i {
+            self.buf[self.i - 1 - n]
+        } else {
+            self.buf[self.buf.len() + self.i - 1 - n]
+        }
     }
 }
 
@@ -223,20 +684,10 @@ pub struct IIRLPF<R: Rack> {
     pub in_value: In<R, f32>,
     pub freq_min: f32,
     pub freq_max: f32,
-    pub buf_a: Vec<f32>,
-    pub i_a: usize,
-    pub buf_b: Vec<f32>,
-    pub i_b: usize,
+    pub buf_a: RingBuffer,
+    pub buf_b: RingBuffer,
     pub out: f32,
 }
-/// n=0 -> newest, n=1 -> z^-1, ..., n=buf.len()-1
-fn buf_at(buf: &[f32], i: usize, n: usize) -> f32 {
-    if n < i {
-        buf[i - 1 - n]
-    } else {
-        buf[buf.len() + i - 1 - n]
-    }
-}
 impl<R: Rack> Default for IIRLPF<R> {
     fn default() -> Self {
         IIRLPF {
@@ -246,23 +697,21 @@ impl<R: Rack> Default for IIRLPF<R> {
             in_value: Box::new(|_, _| 0.0),
             freq_min: 100.0,
             freq_max: 10000.0,
-            buf_a: vec![],
-            buf_b: vec![],
-            i_a: 0,
-            i_b: 0,
+            buf_a: RingBuffer::default(),
+            buf_b: RingBuffer::default(),
             out: 0.0,
         }
     }
 }
 impl<R: Rack> Module<R> for IIRLPF<R> {
-    fn update(&mut self, rack: &R, input: &R::Input) {
+    fn update(&mut self, rack: &R, input: &R::Input, ctx: &ProcessContext) {
         let in_freq = (self.in_freq)(rack, input);
         let in_resonance = (self.in_resonance)(rack, input);
         let in_value = (self.in_value)(rack, input);
 
         let freq = restore_freq(self.freq_min, self.freq_max, in_freq);
 
-        let fc = freq / SAMPLES_PER_SEC as f32;
+        let fc = freq / ctx.sample_rate;
         let q = (0.025 + in_resonance * 9.975) / 2.0f32.sqrt();
         use std::f32::consts::PI;
         // reference: 青木直史. サウンドプログラミング入門. 技術評論社, 2018
@@ -278,31 +727,352 @@ impl<R: Rack> Module<R> for IIRLPF<R> {
             4.0 * PI * PI * fc * fc / a0,
         ];
 
-        self.buf_a.resize(a.len(), 0.0);
-        self.buf_b.resize(b.len(), 0.0);
+        self.buf_a.resize(a.len());
+        self.buf_b.resize(b.len());
 
-        self.buf_b[self.i_b] = in_value;
-        self.i_b += 1;
-        self.i_b %= self.buf_b.len();
+        self.buf_b.push(in_value);
 
         let mut b_value = 0.0;
         for (m, x) in b.iter().enumerate() {
-            b_value += x * buf_at(&self.buf_b, self.i_b, m);
+            b_value += x * self.buf_b.at(m);
         }
 
         let mut a_value = b_value;
         for (m, x) in a.iter().enumerate().skip(1) {
-            a_value += -x * buf_at(&self.buf_a, self.i_a, m - 1);
+            a_value += -x * self.buf_a.at(m - 1);
         }
 
-        self.buf_a[self.i_a] = a_value;
-        self.i_a += 1;
-        self.i_a %= self.buf_a.len();
+        self.buf_a.push(a_value);
 
         self.out = a_value;
     }
 }
 
+/// State variable filter (Simper's trapezoidal integration), stable under fast modulation of in_freq.
+pub struct SVF<R: Rack> {
+    pub _rack: PhantomData<R>,
+    /// 0.0 - 1.0
+    pub in_freq: In<R, f32>,
+    /// 0.0 - 1.0
+    pub in_resonance: In<R, f32>,
+    pub in_value: In<R, f32>,
+    pub freq_min: f32,
+    pub freq_max: f32,
+    pub ic1eq: f32,
+    pub ic2eq: f32,
+    pub out_lp: f32,
+    pub out_hp: f32,
+    pub out_bp: f32,
+    pub out_notch: f32,
+}
+impl<R: Rack> Default for SVF<R> {
+    fn default() -> Self {
+        SVF {
+            _rack: PhantomData,
+            in_freq: Box::new(|_, _| 0.0),
+            in_resonance: Box::new(|_, _| 0.0),
+            in_value: Box::new(|_, _| 0.0),
+            freq_min: 100.0,
+            freq_max: 10000.0,
+            ic1eq: 0.0,
+            ic2eq: 0.0,
+            out_lp: 0.0,
+            out_hp: 0.0,
+            out_bp: 0.0,
+            out_notch: 0.0,
+        }
+    }
+}
+impl<R: Rack> Module<R> for SVF<R> {
+    fn update(&mut self, rack: &R, input: &R::Input, ctx: &ProcessContext) {
+        let in_freq = (self.in_freq)(rack, input);
+        let in_resonance = (self.in_resonance)(rack, input);
+        let in_value = (self.in_value)(rack, input);
+
+        let freq = restore_freq(self.freq_min, self.freq_max, in_freq).min(ctx.sample_rate * 0.49);
+        // Q = 0.5 - 50
+        let k = 2.0 - 1.98 * in_resonance.clamp(0.0, 1.0);
+        // reference: Andrew Simper. Linear Trapezoidal Integrated SVF. Cytomic, 2013
+        let g = (std::f32::consts::PI * freq / ctx.sample_rate).tan();
+        let a1 = 1.0 / (1.0 + g * (g + k));
+        let a2 = g * a1;
+        let a3 = g * a2;
+
+        let v3 = in_value - self.ic2eq;
+        let v1 = a1 * self.ic1eq + a2 * v3;
+        let v2 = self.ic2eq + a2 * self.ic1eq + a3 * v3;
+        self.ic1eq = 2.0 * v1 - self.ic1eq;
+        self.ic2eq = 2.0 * v2 - self.ic2eq;
+
+        self.out_lp = v2;
+        self.out_bp = v1;
+        self.out_hp = in_value - k * v1 - v2;
+        self.out_notch = self.out_lp + self.out_hp;
+    }
+}
+
+/// 24dB/oct ladder low-pass filter with zero-delay feedback and tanh saturation at the input.
+/// Self-oscillates at in_resonance = 1.0.
+pub struct LadderLPF<R: Rack> {
+    pub _rack: PhantomData<R>,
+    /// 0.0 - 1.0
+    pub in_freq: In<R, f32>,
+    /// 0.0 - 1.0
+    pub in_resonance: In<R, f32>,
+    /// 0.0 - 1.0, input gain of 1x - 10x before the saturation
+    pub in_drive: In<R, f32>,
+    pub in_value: In<R, f32>,
+    pub freq_min: f32,
+    pub freq_max: f32,
+    /// State of each one-pole stage
+    pub stages: [f32; 4],
+    pub out: f32,
+}
+impl<R: Rack> Default for LadderLPF<R> {
+    fn default() -> Self {
+        LadderLPF {
+            _rack: PhantomData,
+            in_freq: Box::new(|_, _| 0.0),
+            in_resonance: Box::new(|_, _| 0.0),
+            in_drive: Box::new(|_, _| 0.0),
+            in_value: Box::new(|_, _| 0.0),
+            freq_min: 100.0,
+            freq_max: 10000.0,
+            stages: [0.0; 4],
+            out: 0.0,
+        }
+    }
+}
+impl<R: Rack> Module<R> for LadderLPF<R> {
+    fn update(&mut self, rack: &R, input: &R::Input, ctx: &ProcessContext) {
+        let in_freq = (self.in_freq)(rack, input);
+        let in_resonance = (self.in_resonance)(rack, input);
+        let in_drive = (self.in_drive)(rack, input);
+        let in_value = (self.in_value)(rack, input);
+
+        let freq = restore_freq(self.freq_min, self.freq_max, in_freq).min(ctx.sample_rate * 0.49);
+        // Slightly above 4.0 to keep oscillating against the loss in tanh
+        let k = 4.2 * in_resonance.clamp(0.0, 1.0);
+        let u = in_value * 10.0f32.powf(in_drive.clamp(0.0, 1.0));
+
+        // reference: Vadim Zavalishin. The Art of VA Filter Design. 2018
+        let g = (std::f32::consts::PI * freq / ctx.sample_rate).tan();
+        let big_g = g / (1.0 + g);
+        // y4 = G^4 * x + s
+        let s = self
+            .stages
+            .iter()
+            .fold(0.0, |acc, st| acc * big_g + st / (1.0 + g));
+        // Feedback of (y4 - u) instead of y4 compensates the passband gain:
+        // x = u - k * (y4 - u)
+        let g4 = big_g.powi(4);
+        let y4 = (g4 * u * (1.0 + k) + s) / (1.0 + k * g4);
+        let mut x = (u * (1.0 + k) - k * y4).tanh();
+        for st in self.stages.iter_mut() {
+            let v = (x - *st) * big_g;
+            x = v + *st;
+            *st = x + v;
+        }
+        self.out = x;
+    }
+}
+
+/// Fractional delay interpolation of `Delay`
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum Interpolation {
+    #[default]
+    Linear,
+    /// First order allpass. Flat frequency response, but the delay time can't be modulated quickly.
+    Allpass,
+    /// 4-point Hermite
+    Cubic,
+}
+
+/// Delay line with feedback
+pub struct Delay<R: Rack> {
+    pub _rack: PhantomData<R>,
+    pub in_value: In<R, f32>,
+    /// sec, used if in_division is Division::Free
+    pub in_time: In<R, f32>,
+    pub in_division: In<R, Division>,
+    pub in_bpm: In<R, f32>,
+    /// 0.0 - 1.0
+    pub in_feedback: In<R, f32>,
+    /// 0.0(dry) - 1.0(wet)
+    pub in_mix: In<R, f32>,
+    /// Low-pass filter in the feedback path. 0.0(off) - 1.0
+    pub in_damping: In<R, f32>,
+    /// sec
+    pub max_time: f32,
+    pub interpolation: Interpolation,
+    pub buf: RingBuffer,
+    pub allpass_last: f32,
+    pub damping_last: f32,
+    pub out: f32,
+}
+impl<R: Rack> Default for Delay<R> {
+    fn default() -> Self {
+        Delay {
+            _rack: PhantomData,
+            in_value: Box::new(|_, _| 0.0),
+            in_time: Box::new(|_, _| 0.5),
+            in_division: Box::new(|_, _| Division::Free),
+            in_bpm: Box::new(|_, _| 120.0),
+            in_feedback: Box::new(|_, _| 0.0),
+            in_mix: Box::new(|_, _| 0.5),
+            in_damping: Box::new(|_, _| 0.0),
+            max_time: 2.0,
+            interpolation: Interpolation::Linear,
+            buf: RingBuffer::default(),
+            allpass_last: 0.0,
+            damping_last: 0.0,
+            out: 0.0,
+        }
+    }
+}
+impl<R: Rack> Delay<R> {
+    /// Value of `delay` samples before the current one
+    fn read(&mut self, delay: f32) -> f32 {
+        // buf.at(0) is 1 sample before
+        let mut n = delay.floor() as usize;
+        let mut f = delay - n as f32;
+        match self.interpolation {
+            Interpolation::Linear => self.buf.at(n - 1) * (1.0 - f) + self.buf.at(n) * f,
+            Interpolation::Allpass => {
+                // Keep the coefficient away from -1
+                if f < 0.5 && n > 1 {
+                    n -= 1;
+                    f += 1.0;
+                }
+                let a = (1.0 - f) / (1.0 + f);
+                let y = a * self.buf.at(n - 1) + self.buf.at(n) - a * self.allpass_last;
+                self.allpass_last = y;
+                y
+            }
+            Interpolation::Cubic => {
+                let y0 = self.buf.at(n - 2);
+                let y1 = self.buf.at(n - 1);
+                let y2 = self.buf.at(n);
+                let y3 = self.buf.at(n + 1);
+                let c1 = 0.5 * (y2 - y0);
+                let c2 = y0 - 2.5 * y1 + 2.0 * y2 - 0.5 * y3;
+                let c3 = 0.5 * (y3 - y0) + 1.5 * (y1 - y2);
+                ((c3 * f + c2) * f + c1) * f + y1
+            }
+        }
+    }
+}
+impl<R: Rack> Module<R> for Delay<R> {
+    fn update(&mut self, rack: &R, input: &R::Input, ctx: &ProcessContext) {
+        let in_value = (self.in_value)(rack, input);
+        let in_feedback = (self.in_feedback)(rack, input).clamp(0.0, 1.0);
+        let in_mix = (self.in_mix)(rack, input).clamp(0.0, 1.0);
+        let in_damping = (self.in_damping)(rack, input).clamp(0.0, 1.0);
+        let time = match (self.in_division)(rack, input) {
+            Division::Free => (self.in_time)(rack, input),
+            division => division
+                .seconds((self.in_bpm)(rack, input))
+                .unwrap_or_default(),
+        };
+        // e.g. 0/0 in an expression, or 0 BPM
+        let time = if time.is_finite() { time } else { 0.0 };
+
+        // Margin for the interpolation
+        self.buf
+            .resize((self.max_time * ctx.sample_rate).ceil() as usize + 4);
+        let max_delay = ((self.buf.len() - 3) as f32).max(2.0);
+        let delay = (time * ctx.sample_rate).clamp(2.0, max_delay);
+        let delayed = self.read(delay);
+        self.damping_last += (delayed - self.damping_last) * (1.0 - in_damping);
+        self.buf.push(in_value + self.damping_last * in_feedback);
+        self.out = in_value * (1.0 - in_mix) + delayed * in_mix;
+    }
+}
+
+/// Delay lengths of Freeverb at 44100Hz
+const REVERB_COMBS: [usize; 8] = [1116, 1188, 1277, 1356, 1422, 1491, 1557, 1617];
+const REVERB_ALLPASSES: [usize; 4] = [556, 441, 341, 225];
+
+/// Mono Freeverb: parallel low-pass feedback comb filters followed by series allpass filters.
+pub struct Reverb<R: Rack> {
+    pub _rack: PhantomData<R>,
+    pub in_value: In<R, f32>,
+    /// 0.0 - 1.0
+    pub in_size: In<R, f32>,
+    /// 0.0 - 1.0
+    pub in_damping: In<R, f32>,
+    /// sec
+    pub in_predelay: In<R, f32>,
+    /// 0.0(dry) - 1.0(wet)
+    pub in_mix: In<R, f32>,
+    /// sec
+    pub max_predelay: f32,
+    pub predelay: RingBuffer,
+    pub combs: [RingBuffer; 8],
+    /// State of the low-pass filter in each comb
+    pub comb_filters: [f32; 8],
+    pub allpasses: [RingBuffer; 4],
+    pub out: f32,
+}
+impl<R: Rack> Default for Reverb<R> {
+    fn default() -> Self {
+        Reverb {
+            _rack: PhantomData,
+            in_value: Box::new(|_, _| 0.0),
+            in_size: Box::new(|_, _| 0.5),
+            in_damping: Box::new(|_, _| 0.5),
+            in_predelay: Box::new(|_, _| 0.0),
+            in_mix: Box::new(|_, _| 0.3),
+            max_predelay: 0.5,
+            predelay: RingBuffer::default(),
+            combs: Default::default(),
+            comb_filters: [0.0; 8],
+            allpasses: Default::default(),
+            out: 0.0,
+        }
+    }
+}
+impl<R: Rack> Module<R> for Reverb<R> {
+    fn update(&mut self, rack: &R, input: &R::Input, ctx: &ProcessContext) {
+        let in_value = (self.in_value)(rack, input);
+        let in_size = (self.in_size)(rack, input).clamp(0.0, 1.0);
+        let in_damping = (self.in_damping)(rack, input).clamp(0.0, 1.0);
+        let in_predelay = (self.in_predelay)(rack, input);
+        let in_mix = (self.in_mix)(rack, input).clamp(0.0, 1.0);
+
+        let scale = ctx.sample_rate / 44_100.0;
+        for (buf, len) in self.combs.iter_mut().zip(REVERB_COMBS) {
+            buf.resize(((len as f32 * scale) as usize).max(1));
+        }
+        for (buf, len) in self.allpasses.iter_mut().zip(REVERB_ALLPASSES) {
+            buf.resize(((len as f32 * scale) as usize).max(1));
+        }
+        self.predelay
+            .resize((self.max_predelay * ctx.sample_rate) as usize + 1);
+
+        // reference: Jezar at Dreampoint. Freeverb. 2000
+        let feedback = 0.7 + in_size * 0.28;
+        let damping = in_damping * 0.4;
+        self.predelay.push(in_value);
+        let predelay = ((in_predelay * ctx.sample_rate) as usize).min(self.predelay.len() - 1);
+        let x = self.predelay.at(predelay) * 0.015;
+
+        let mut wet = 0.0;
+        for (buf, filter) in self.combs.iter_mut().zip(self.comb_filters.iter_mut()) {
+            let y = buf.at(buf.len() - 1);
+            *filter = y * (1.0 - damping) + *filter * damping;
+            buf.push(x + *filter * feedback);
+            wet += y;
+        }
+        for buf in self.allpasses.iter_mut() {
+            let y = buf.at(buf.len() - 1);
+            buf.push(wet + y * 0.5);
+            wet = y - wet;
+        }
+        self.out = in_value * (1.0 - in_mix) + wet * 3.0 * in_mix;
+    }
+}
+
 pub struct Buf<R: Rack> {
     pub _rack: PhantomData<R>,
     pub in_value: In<R, f32>,
@@ -318,7 +1088,1440 @@ impl<R: Rack> Default for Buf<R> {
     }
 }
 impl<R: Rack> Module<R> for Buf<R> {
-    fn update(&mut self, rack: &R, input: &<R as Rack>::Input) {
+    fn update(&mut self, rack: &R, input: &<R as Rack>::Input, _ctx: &ProcessContext) {
         self.out = (self.in_value)(rack, input);
     }
 }
+
+/// Stereo version of `Buf`
+pub struct StereoBuf<R: Rack> {
+    pub _rack: PhantomData<R>,
+    pub in_l: In<R, f32>,
+    pub in_r: In<R, f32>,
+    pub out_l: f32,
+    pub out_r: f32,
+}
+impl<R: Rack> Default for StereoBuf<R> {
+    fn default() -> Self {
+        StereoBuf {
+            _rack: PhantomData,
+            in_l: Box::new(|_, _| 0.0),
+            in_r: Box::new(|_, _| 0.0),
+            out_l: 0.0,
+            out_r: 0.0,
+        }
+    }
+}
+impl<R: Rack> StereoBuf<R> {
+    pub fn out(&self) -> (f32, f32) {
+        (self.out_l, self.out_r)
+    }
+}
+impl<R: Rack> Module<R> for StereoBuf<R> {
+    fn update(&mut self, rack: &R, input: &<R as Rack>::Input, _ctx: &ProcessContext) {
+        self.out_l = (self.in_l)(rack, input);
+        self.out_r = (self.in_r)(rack, input);
+    }
+}
+
+/// Place a mono signal in the stereo field with the equal power (-3dB at center) law.
+pub struct Pan<R: Rack> {
+    pub _rack: PhantomData<R>,
+    pub in_value: In<R, f32>,
+    /// 0.0 = left, 0.5 = center, 1.0 = right
+    pub in_pan: In<R, f32>,
+    pub out_l: f32,
+    pub out_r: f32,
+}
+impl<R: Rack> Default for Pan<R> {
+    fn default() -> Self {
+        Pan {
+            _rack: PhantomData,
+            in_value: Box::new(|_, _| 0.0),
+            in_pan: Box::new(|_, _| 0.5),
+            out_l: 0.0,
+            out_r: 0.0,
+        }
+    }
+}
+impl<R: Rack> Pan<R> {
+    pub fn out(&self) -> (f32, f32) {
+        (self.out_l, self.out_r)
+    }
+}
+/// (left, right) gains of the equal power pan law. pan: 0.0 = left - 1.0 = right
+pub fn pan_gains(pan: f32) -> (f32, f32) {
+    let angle = pan.clamp(0.0, 1.0) * std::f32::consts::FRAC_PI_2;
+    (angle.cos(), angle.sin())
+}
+impl<R: Rack> Module<R> for Pan<R> {
+    fn update(&mut self, rack: &R, input: &<R as Rack>::Input, _ctx: &ProcessContext) {
+        let in_value = (self.in_value)(rack, input);
+        let (l, r) = pan_gains((self.in_pan)(rack, input));
+        self.out_l = in_value * l;
+        self.out_r = in_value * r;
+    }
+}
+
+/// Input channel of `Mixer`
+pub struct MixerChannel<R: Rack> {
+    pub in_value: In<R, f32>,
+    /// 0.0 - 1.0(unity)
+    pub in_gain: In<R, f32>,
+    /// 0.0 = left, 0.5 = center, 1.0 = right
+    pub in_pan: In<R, f32>,
+    pub in_mute: In<R, bool>,
+    pub in_solo: In<R, bool>,
+}
+impl<R: Rack> MixerChannel<R> {
+    /// Unity gain, centered, not muted nor soloed
+    pub fn new(value: impl Fn(&R, &R::Input) -> f32 + Send + 'static) -> MixerChannel<R> {
+        MixerChannel {
+            in_value: Box::new(value),
+            in_gain: Box::new(|_, _| 1.0),
+            in_pan: Box::new(|_, _| 0.5),
+            in_mute: Box::new(|_, _| false),
+            in_solo: Box::new(|_, _| false),
+        }
+    }
+}
+
+/// Sum of the channels. While any channel is soloed, only soloed channels are heard.
+/// A channel corresponds to a group of nanoKONTROL2: slider = gain, knob = pan, M = mute, S = solo.
+/// See mixer-nanokontrol2.toml.
+pub struct Mixer<R: Rack> {
+    pub _rack: PhantomData<R>,
+    pub channels: Vec<MixerChannel<R>>,
+    pub in_master: In<R, f32>,
+    /// Saturate the output with tanh instead of leaving it unbounded
+    pub soft_clip: bool,
+    /// Mono sum, pan is ignored
+    pub out: f32,
+    pub out_l: f32,
+    pub out_r: f32,
+}
+impl<R: Rack> Default for Mixer<R> {
+    fn default() -> Self {
+        Mixer {
+            _rack: PhantomData,
+            channels: Vec::new(),
+            in_master: Box::new(|_, _| 1.0),
+            soft_clip: false,
+            out: 0.0,
+            out_l: 0.0,
+            out_r: 0.0,
+        }
+    }
+}
+impl<R: Rack> Mixer<R> {
+    pub fn out_stereo(&self) -> (f32, f32) {
+        (self.out_l, self.out_r)
+    }
+}
+impl<R: Rack> Module<R> for Mixer<R> {
+    fn update(&mut self, rack: &R, input: &<R as Rack>::Input, _ctx: &ProcessContext) {
+        let any_solo = self.channels.iter().any(|c| (c.in_solo)(rack, input));
+        let (mut out, mut out_l, mut out_r) = (0.0, 0.0, 0.0);
+        for c in self.channels.iter() {
+            if (c.in_mute)(rack, input) || (any_solo && !(c.in_solo)(rack, input)) {
+                continue;
+            }
+            let value = (c.in_value)(rack, input) * (c.in_gain)(rack, input);
+            let (l, r) = pan_gains((c.in_pan)(rack, input));
+            out += value;
+            out_l += value * l;
+            out_r += value * r;
+        }
+        let master = (self.in_master)(rack, input);
+        let clip = |x: f32| if self.soft_clip { x.tanh() } else { x };
+        self.out = clip(out * master);
+        self.out_l = clip(out_l * master);
+        self.out_r = clip(out_r * master);
+    }
+}
+
+/// Step of `StepSequencer`
+pub struct Step<R: Rack> {
+    /// 0.0 - 1.0, same as in_freq of VCO
+    pub in_pitch: In<R, f32>,
+    pub in_gate: In<R, bool>,
+    pub in_accent: In<R, bool>,
+    /// Hold the gate and glide to the pitch of the next step
+    pub in_slide: In<R, bool>,
+}
+impl<R: Rack> Step<R> {
+    pub fn new(
+        pitch: impl Fn(&R, &R::Input) -> f32 + Send + 'static,
+        gate: impl Fn(&R, &R::Input) -> bool + Send + 'static,
+        accent: impl Fn(&R, &R::Input) -> bool + Send + 'static,
+        slide: impl Fn(&R, &R::Input) -> bool + Send + 'static,
+    ) -> Step<R> {
+        Step {
+            in_pitch: Box::new(pitch),
+            in_gate: Box::new(gate),
+            in_accent: Box::new(accent),
+            in_slide: Box::new(slide),
+        }
+    }
+}
+
+pub const MAX_STEPS: usize = 32;
+
+/// Plays the steps in order, advanced by the internal clock(in_bpm and in_division) or in_clock.
+/// Connect out_pitch to in_freq of VCO, out_gate and out_trigger to in_gate and in_trigger of EG.
+pub struct StepSequencer<R: Rack> {
+    pub _rack: PhantomData<R>,
+    /// Steps beyond MAX_STEPS are ignored
+    pub steps: Vec<Step<R>>,
+    /// Restart from the first step when turned on
+    pub in_run: In<R, bool>,
+    pub in_bpm: In<R, f32>,
+    /// Length of a step. Division::Free stops the internal clock.
+    pub in_division: In<R, Division>,
+    /// External clock, advance a step on rising edge(<= 0.0 to > 0.0)
+    pub in_clock: In<R, f32>,
+    /// Ratio of the gate on time to the step length, 0.0 - 1.0
+    pub in_gate_length: In<R, f32>,
+    /// sec, time constant of the glide after a slide step
+    pub in_slide_time: In<R, f32>,
+    /// Current step, None if stopped
+    pub step: Option<usize>,
+    /// sec, elapsed in the current step
+    pub clock: f32,
+    /// sec, the interval of in_clock when driven externally
+    pub step_length: f32,
+    pub last_clock: f32,
+    /// The previous step slides into the current step
+    pub sliding: bool,
+    pub out_pitch: f32,
+    pub out_gate: bool,
+    pub out_accent: bool,
+    /// Toggled on each gated step not slid into
+    pub out_trigger: bool,
+}
+impl<R: Rack> Default for StepSequencer<R> {
+    fn default() -> Self {
+        StepSequencer {
+            _rack: PhantomData,
+            steps: Vec::new(),
+            in_run: Box::new(|_, _| true),
+            in_bpm: Box::new(|_, _| 120.0),
+            in_division: Box::new(|_, _| Division::Sixteenth),
+            in_clock: Box::new(|_, _| 0.0),
+            in_gate_length: Box::new(|_, _| 0.5),
+            in_slide_time: Box::new(|_, _| 0.05),
+            step: None,
+            clock: 0.0,
+            step_length: f32::INFINITY,
+            last_clock: 0.0,
+            sliding: false,
+            out_pitch: 0.0,
+            out_gate: false,
+            out_accent: false,
+            out_trigger: false,
+        }
+    }
+}
+impl<R: Rack> Module<R> for StepSequencer<R> {
+    fn update(&mut self, rack: &R, input: &R::Input, ctx: &ProcessContext) {
+        let len = self.steps.len().min(MAX_STEPS);
+        let in_clock = (self.in_clock)(rack, input);
+        let edge = self.last_clock <= 0.0 && 0.0 < in_clock;
+        self.last_clock = in_clock;
+        if len == 0 || !(self.in_run)(rack, input) {
+            self.step = None;
+            self.out_gate = false;
+            self.out_accent = false;
+            return;
+        }
+        let advance = match (self.in_division)(rack, input).seconds((self.in_bpm)(rack, input)) {
+            Some(length) => {
+                self.step_length = length;
+                if self.step.is_none() {
+                    self.clock = 0.0;
+                    true
+                } else if length <= self.clock {
+                    self.clock -= length;
+                    true
+                } else {
+                    false
+                }
+            }
+            None if edge => {
+                if self.step.is_some() {
+                    self.step_length = self.clock;
+                } else {
+                    self.step_length = f32::INFINITY;
+                }
+                self.clock = 0.0;
+                true
+            }
+            None => false,
+        };
+        if advance {
+            let slide = match self.step {
+                Some(i) => {
+                    let prev = &self.steps[i];
+                    (prev.in_gate)(rack, input) && (prev.in_slide)(rack, input)
+                }
+                None => false,
+            };
+            let next = self.step.map(|i| (i + 1) % len).unwrap_or(0);
+            self.step = Some(next);
+            let gate = (self.steps[next].in_gate)(rack, input);
+            self.sliding = slide && gate;
+            if gate && !self.sliding {
+                self.out_trigger = !self.out_trigger;
+            }
+        }
+        let i = self.step.unwrap();
+        let step = &self.steps[i];
+        let gate = (step.in_gate)(rack, input);
+        let pitch = (step.in_pitch)(rack, input);
+        if self.sliding {
+            let slide_time = (self.in_slide_time)(rack, input);
+            self.out_pitch += (pitch - self.out_pitch)
+                * (1.0 - (-ctx.sample_duration() / slide_time.max(1e-6)).exp());
+        } else {
+            self.out_pitch = pitch;
+        }
+        let tie = (step.in_slide)(rack, input) && (self.steps[(i + 1) % len].in_gate)(rack, input);
+        let gate_length = (self.in_gate_length)(rack, input).clamp(0.0, 1.0);
+        self.out_gate = gate && (tie || self.clock < self.step_length * gate_length);
+        self.out_accent = gate && (step.in_accent)(rack, input);
+        self.clock += ctx.sample_duration();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::render::{render, Script};
+    use crate::{define_input, define_rack};
+
+    define_input! {
+        VCOTestInput {
+            waveform: WaveForm = (WaveForm::Sawtooth),
+        }
+    }
+    define_rack! {
+        VCOTestRack: Rack<VCOTestInput>(rack, input) {
+            naive: VCO {
+                in_freq: { 0.0 },
+                in_waveform: { input.waveform },
+                freq_min: 3130.0,
+                freq_max: 3130.0,
+            },
+            band_limited: VCO {
+                in_freq: { 0.0 },
+                in_waveform: { input.waveform },
+                freq_min: 3130.0,
+                freq_max: 3130.0,
+                band_limited: true,
+            },
+        }
+    }
+
+    define_input! {
+        SVFTestInput {
+            freq: f32 = 0.5,
+            modulation: f32,
+            resonance: f32 = 0.5,
+        }
+    }
+    define_rack! {
+        SVFTestRack: Rack<SVFTestInput>(rack, input) {
+            vco: VCO {
+                in_freq: { input.freq },
+                freq_min: 100.0,
+                freq_max: 10000.0,
+            },
+            svf: SVF {
+                in_freq: { 0.5 },
+                in_resonance: { input.resonance },
+                in_value: { rack.vco.borrow().out },
+                freq_min: 1000.0,
+                freq_max: 1000.0,
+            },
+        }
+    }
+    define_rack! {
+        SVFModTestRack: Rack<SVFTestInput>(rack, input) {
+            lfo: VCO {
+                in_freq: { 0.0 },
+                freq_min: 5000.0,
+                freq_max: 5000.0,
+            },
+            vco: VCO {
+                in_freq: { input.freq },
+                freq_min: 100.0,
+                freq_max: 10000.0,
+            },
+            svf: SVF {
+                in_freq: { 0.5 + rack.lfo.borrow().out * input.modulation },
+                in_resonance: { input.resonance },
+                in_value: { rack.vco.borrow().out },
+                freq_min: 20.0,
+                freq_max: 20000.0,
+            },
+        }
+    }
+
+    fn rms(samples: &[f32]) -> f32 {
+        (samples.iter().map(|x| x * x).sum::<f32>() / samples.len() as f32).sqrt()
+    }
+
+    #[test]
+    fn test_svf_response() {
+        let outputs = |freq: f32| {
+            let rack = SVFTestRack::new();
+            let mut lp = Vec::new();
+            let mut hp = Vec::new();
+            let mut bp = Vec::new();
+            let mut notch = Vec::new();
+            let ctx = ProcessContext::new(44_100);
+            let input = SVFTestInput {
+                freq: normalize_freq(100.0, 10000.0, freq),
+                resonance: 0.0,
+                ..Default::default()
+            };
+            for i in 0..44_100 {
+                rack.update(&input, &ctx);
+                if 22_050 <= i {
+                    let svf = rack.svf.borrow();
+                    lp.push(svf.out_lp);
+                    hp.push(svf.out_hp);
+                    bp.push(svf.out_bp);
+                    notch.push(svf.out_notch);
+                }
+            }
+            (rms(&lp), rms(&hp), rms(&bp), rms(&notch))
+        };
+        let sine = 1.0 / 2.0f32.sqrt();
+        // cutoff = 1000Hz
+        let (lp, hp, bp, notch) = outputs(100.0);
+        assert!(
+            lp > sine * 0.95 && hp < sine * 0.02 && bp < sine * 0.2,
+            "{} {} {}",
+            lp,
+            hp,
+            bp
+        );
+        assert!(notch > sine * 0.95);
+        let (lp, hp, bp, notch) = outputs(10000.0);
+        assert!(
+            lp < sine * 0.02 && hp > sine * 0.95 && bp < sine * 0.2,
+            "{} {} {}",
+            lp,
+            hp,
+            bp
+        );
+        assert!(notch > sine * 0.95);
+        // gain of out_bp at the cutoff is 1 / k = Q
+        let (_, _, bp, notch) = outputs(1000.0);
+        assert!(
+            (bp - sine * 0.5).abs() < 0.01 && notch < sine * 0.05,
+            "{} {}",
+            bp,
+            notch
+        );
+    }
+
+    #[test]
+    fn test_svf_modulation() {
+        let out = |modulation: f32| {
+            let script = Script::new(SVFTestInput {
+                modulation,
+                resonance: 1.0,
+                ..Default::default()
+            });
+            render(
+                &SVFModTestRack::new(),
+                |r| r.svf.borrow().out_lp,
+                &script,
+                44_100,
+                44_100,
+            )
+        };
+        // Sweep the whole range at audio rate with high resonance
+        let modulated = out(5.0);
+        assert!(modulated.iter().all(|x| x.is_finite() && x.abs() < 100.0));
+        // the cutoff actually moves
+        let fixed = out(0.0);
+        let diff = modulated
+            .iter()
+            .zip(fixed.iter())
+            .map(|(a, b)| a - b)
+            .collect::<Vec<_>>();
+        assert!(
+            rms(&diff) > rms(&fixed) * 0.1,
+            "{} {}",
+            rms(&diff),
+            rms(&fixed)
+        );
+    }
+
+    define_input! {
+        LadderTestInput {
+            freq: f32,
+            resonance: f32,
+            drive: f32,
+            value: f32,
+        }
+    }
+    define_rack! {
+        LadderTestRack: Rack<LadderTestInput>(rack, input) {
+            vco: VCO {
+                in_freq: { input.freq },
+                freq_min: 10.0,
+                freq_max: 10000.0,
+            },
+            lpf: LadderLPF {
+                in_freq: { 0.5 },
+                in_resonance: { input.resonance },
+                in_drive: { input.drive },
+                in_value: { rack.vco.borrow().out * input.value },
+                freq_min: 1000.0,
+                freq_max: 1000.0,
+            },
+        }
+    }
+
+    fn ladder_rms(input: LadderTestInput) -> f32 {
+        let out = render(
+            &LadderTestRack::new(),
+            |r| r.lpf.borrow().out,
+            &Script::new(input),
+            44_100,
+            44_100,
+        );
+        rms(&out[22_050..])
+    }
+
+    #[test]
+    fn test_ladder_response() {
+        let sine = |freq| LadderTestInput {
+            freq: normalize_freq(10.0, 10000.0, freq),
+            value: 0.01,
+            ..Default::default()
+        };
+        let rms_in = 0.01 / 2.0f32.sqrt();
+        // passband gain is compensated
+        for resonance in [0.0, 0.5, 0.9] {
+            let out = ladder_rms(LadderTestInput {
+                resonance,
+                ..sine(20.0)
+            });
+            assert!((out / rms_in - 1.0).abs() < 0.05, "{}: {}", resonance, out);
+        }
+        // -24dB/oct: 2 octaves above the cutoff is attenuated by ~48dB
+        let out = ladder_rms(sine(4000.0));
+        assert!(out / rms_in < 0.01, "{}", out);
+        // saturation
+        let out = ladder_rms(LadderTestInput {
+            value: 1.0,
+            drive: 1.0,
+            ..sine(20.0)
+        });
+        assert!(out < 1.0, "{}", out);
+    }
+
+    #[test]
+    fn test_ladder_self_oscillation() {
+        let script = Script::new(LadderTestInput {
+            resonance: 1.0,
+            value: 0.01,
+            ..Default::default()
+        })
+        .at(1, |i| i.value = 0.0);
+        let out = render(
+            &LadderTestRack::new(),
+            |r| r.lpf.borrow().out,
+            &script,
+            44_100,
+            44_100,
+        );
+        // keeps oscillating after the impulse, amplitude is limited by tanh
+        let out = rms(&out[22_050..]);
+        assert!(0.05 < out && out < 1.0, "{}", out);
+    }
+
+    define_input! {
+        SyncTestInput {
+            pulse_width: f32 = 0.5,
+        }
+    }
+    define_rack! {
+        SyncTestRack: Rack<SyncTestInput>(rack, input) {
+            master: VCO {
+                in_freq: { 0.0 },
+                in_waveform: { WaveForm::Square },
+                in_pulse_width: { input.pulse_width },
+                freq_min: 1000.0,
+                freq_max: 1000.0,
+            },
+            slave: VCO {
+                in_freq: { 0.0 },
+                in_waveform: { WaveForm::Square },
+                in_sync: { rack.master.borrow().out_wrap },
+                freq_min: 1500.0,
+                freq_max: 1500.0,
+            },
+        }
+    }
+
+    #[test]
+    fn test_vco_sync() {
+        let rack = SyncTestRack::new();
+        let ctx = ProcessContext::new(44_000);
+        let input = SyncTestInput::default();
+        let mut wraps = Vec::new();
+        let mut slave_phases = Vec::new();
+        for i in 0..440 {
+            rack.update(&input, &ctx);
+            if rack.master.borrow().out_wrap == 1.0 {
+                wraps.push(i);
+                slave_phases.push(rack.slave.borrow().phase);
+            }
+        }
+        // 1000Hz at 44000Hz: wraps every 44 samples
+        assert_eq!(wraps.len(), 10);
+        assert!(wraps.windows(2).all(|w| w[1] - w[0] == 44), "{:?}", wraps);
+        assert!(slave_phases.iter().all(|p| *p == 0.0));
+    }
+
+    #[test]
+    fn test_vco_pulse_width() {
+        for pulse_width in [0.1, 0.5, 0.75] {
+            let out = render(
+                &SyncTestRack::new(),
+                |r| r.master.borrow().out,
+                &Script::new(SyncTestInput { pulse_width }),
+                44_000,
+                44_000,
+            );
+            let high = out.iter().filter(|x| **x > 0.0).count() as f32 / out.len() as f32;
+            // resolution is 1 sample in a 44 samples cycle
+            assert!(
+                (high - pulse_width).abs() < 1.0 / 44.0,
+                "{}: {}",
+                pulse_width,
+                high
+            );
+        }
+    }
+
+    define_input! {
+        TempoTestInput {
+            division: Division = (Division::Quarter),
+        }
+    }
+    define_rack! {
+        TempoTestRack: Rack<TempoTestInput>(rack, input) {
+            lfo: VCO {
+                in_freq: { 0.0 },
+                in_division: { input.division },
+                freq_min: 1.0,
+                freq_max: 10.0,
+            },
+        }
+    }
+
+    #[test]
+    fn test_vco_tempo_sync() {
+        let wraps = |ctx: &mut ProcessContext, input: &TempoTestInput| {
+            let rack = TempoTestRack::new();
+            let mut wraps = Vec::new();
+            for i in 0..1000 {
+                rack.update(input, ctx);
+                ctx.transport.advance(ctx.sample_duration());
+                if rack.lfo.borrow().out_wrap == 1.0 {
+                    wraps.push(i);
+                }
+            }
+            wraps
+        };
+        let mut ctx = ProcessContext::new(1000);
+        let input = TempoTestInput::default();
+        // no clock: in_freq
+        assert_eq!(wraps(&mut ctx, &input).len(), 1);
+        // stopped: free running at the tempo
+        ctx.transport.bpm = Some(120.0);
+        assert_eq!(wraps(&mut ctx, &input).len(), 2);
+        assert_eq!(
+            wraps(
+                &mut ctx,
+                &TempoTestInput {
+                    division: Division::TripletEighth,
+                }
+            )
+            .len(),
+            6
+        );
+        // 187.5 samples per cycle
+        let w = wraps(
+            &mut ctx,
+            &TempoTestInput {
+                division: Division::DottedSixteenth,
+            },
+        );
+        assert!(
+            w.len() == 5 && w.windows(2).all(|w| (187..=188).contains(&(w[1] - w[0]))),
+            "{:?}",
+            w
+        );
+        // playing: follows the position
+        ctx.transport.playing = true;
+        ctx.transport.position = 0.75;
+        let w = wraps(&mut ctx, &input);
+        assert!(w.len() == 2 && (125..=126).contains(&w[0]) && w[1] - w[0] == 500);
+        assert!((ctx.transport.position - 2.75).abs() < 1e-3);
+        // same long after the start
+        ctx.transport.position = 10_000.75;
+        let w = wraps(&mut ctx, &input);
+        assert!(w.len() == 2 && (125..=126).contains(&w[0]) && w[1] - w[0] == 500);
+        // Free ignores the tempo
+        ctx.transport.position = 0.0;
+        let w = wraps(
+            &mut ctx,
+            &TempoTestInput {
+                division: Division::Free,
+            },
+        );
+        assert_eq!(w.len(), 1);
+    }
+
+    define_input! {
+        EGTestInput {
+            gate: bool = true,
+            trigger: bool,
+            curve: f32,
+        }
+    }
+    define_rack! {
+        EGTestRack: Rack<EGTestInput>(rack, input) {
+            eg: EG {
+                in_gate: { input.gate },
+                in_trigger: { input.trigger },
+                in_a: { 0.01 },
+                in_d: { 0.01 },
+                in_s: { 0.5 },
+                in_r: { 0.01 },
+                in_a_curve: { input.curve },
+                in_d_curve: { input.curve },
+                in_r_curve: { input.curve },
+            },
+        }
+    }
+
+    #[test]
+    fn test_eg_curve() {
+        let render_eg = |curve: f32| {
+            render(
+                &EGTestRack::new(),
+                |r| r.eg.borrow().out,
+                &Script::new(EGTestInput {
+                    curve,
+                    ..Default::default()
+                })
+                .at(2000, |i| i.gate = false),
+                10_000,
+                3000,
+            )
+        };
+        let linear = render_eg(0.0);
+        let exp = render_eg(5.0);
+        let log = render_eg(-5.0);
+        // attack: 0 - 100, decay: 101 - 201, release: 2000 - 2100
+        assert!((linear[50] - 0.5).abs() < 0.02, "{}", linear[50]);
+        assert!(exp[50] > 0.85 && log[50] < 0.15, "{} {}", exp[50], log[50]);
+        for out in [&linear, &exp, &log] {
+            let peak = out.iter().fold(0.0, |a: f32, b| a.max(*b));
+            assert!((peak - 1.0).abs() < 1e-3);
+            assert_eq!(out[1000], 0.5);
+            assert_eq!(out[2200], 0.0);
+        }
+        assert!(exp[2050] < linear[2050] && linear[2050] < log[2050]);
+    }
+
+    #[test]
+    fn test_eg_retrigger() {
+        // retrigger in attack, decay and release
+        let script = Script::new(EGTestInput {
+            curve: 5.0,
+            ..Default::default()
+        })
+        .at(50, |i| i.trigger = true)
+        .at(150, |i| i.trigger = false)
+        .at(1000, |i| i.gate = false)
+        .at(1050, |i| i.gate = true);
+        let out = render(
+            &EGTestRack::new(),
+            |r| r.eg.borrow().out,
+            &script,
+            10_000,
+            2000,
+        );
+        let max_step = out
+            .windows(2)
+            .map(|w| (w[1] - w[0]).abs())
+            .fold(0.0, f32::max);
+        // no jumps larger than the steepest part of the segments
+        assert!(max_step < 0.06, "{}", max_step);
+        // release is interrupted before reaching 0
+        assert!(out[1049] > 0.0);
+    }
+
+    define_input! {
+        EnvelopeTestInput {
+            gate: bool,
+            hold: f32 = 0.002,
+        }
+    }
+    define_rack! {
+        EnvelopeTestRack: Rack<EnvelopeTestInput>(rack, input) {
+            dahdsr: Envelope {
+                in_gate: { input.gate },
+                in_delay: { 0.001 },
+                segments: (vec![
+                    Segment::new(|_, _| 1.0, |_, _| 0.001, |_, _| 0.0),
+                    Segment::new(|_, _| 1.0, |_, i: &EnvelopeTestInput| i.hold, |_, _| 0.0),
+                    Segment::new(|_, _| 0.5, |_, _| 0.001, |_, _| 0.0),
+                    Segment::new(|_, _| 0.0, |_, _| 0.001, |_, _| 0.0),
+                ]),
+                sustain: (Some(2)),
+            },
+            looping: Envelope {
+                in_gate: { input.gate },
+                segments: (vec![
+                    Segment::new(|_, _| 1.0, |_, _| 0.001, |_, _| 0.0),
+                    Segment::new(|_, _| 0.0, |_, _| 0.001, |_, _| 0.0),
+                    Segment::new(|_, _| 0.5, |_, _| 0.001, |_, _| 0.0),
+                ]),
+                loop_segments: (Some((0, 1))),
+            },
+            one_shot: Envelope {
+                in_gate: { input.gate },
+                segments: (vec![
+                    Segment::new(|_, _| 1.0, |_, _| 0.001, |_, _| 0.0),
+                    Segment::new(|_, _| 0.0, |_, _| 0.001, |_, _| 0.0),
+                ]),
+                mode: (EnvelopeMode::OneShot),
+                loop_segments: (Some((0, 1))),
+            },
+            // gated by the end of cycle of one_shot
+            eg: EG {
+                in_gate: { rack.one_shot.borrow().out_eoc },
+                in_a: { 0.0 },
+                in_r: { 0.01 },
+            },
+        }
+    }
+
+    #[test]
+    fn test_envelope() {
+        // 10 samples per ms
+        let script = Script::new(EnvelopeTestInput::default())
+            .at(10, |i| i.gate = true)
+            .at(35, |i| i.gate = false)
+            .at(100, |i| i.gate = true)
+            .at(200, |i| i.gate = false);
+        let run = |out: fn(&EnvelopeTestRack) -> f32| {
+            render(&EnvelopeTestRack::new(), out, &script, 10_000, 300)
+        };
+        let dahdsr = run(|r| r.dahdsr.borrow().out);
+        let looping = run(|r| r.looping.borrow().out);
+        let eoc = run(|r| r.one_shot.borrow().out_eoc as u8 as f32);
+        let eg = run(|r| r.eg.borrow().out);
+        let approx = |a: f32, b: f32| (a - b).abs() < 0.11;
+        // gate off in the hold stage jumps to release
+        assert!(dahdsr[21] == 0.0 && approx(dahdsr[26], 0.5) && approx(dahdsr[33], 1.0));
+        assert!(approx(dahdsr[40], 0.5) && dahdsr[50] == 0.0);
+        // delay, attack, hold, decay, sustain, release
+        assert!(dahdsr[110] == 0.0 && approx(dahdsr[115], 0.4));
+        assert!(approx(dahdsr[125], 1.0) && approx(dahdsr[140], 1.0));
+        assert!(approx(dahdsr[148], 0.75) && dahdsr[199] == 0.5);
+        assert!(approx(dahdsr[205], 0.25) && dahdsr[220] == 0.0);
+
+        // loops while gated, then plays the rest
+        assert!(approx(looping[16], 0.5) && approx(looping[26], 0.5));
+        let cycle = &looping[150..200];
+        assert!(cycle.iter().any(|x| *x < 0.1) && cycle.iter().any(|x| *x > 0.9));
+        assert!(approx(looping[205], 0.75) && looping[250] == 0.5);
+
+        // one shot loops forever, eoc on each loop of about 2ms
+        let count = eoc.iter().filter(|x| **x == 1.0).count();
+        assert!((12..=14).contains(&count), "{}", count);
+        assert!(eg[295] > 0.0);
+    }
+
+    define_input! {
+        DelayTestInput {
+            value: f32,
+            time: f32 = 0.01,
+            division: Division,
+            feedback: f32 = 0.5,
+            damping: f32,
+        }
+    }
+    define_rack! {
+        DelayTestRack: Rack<DelayTestInput>(rack, input) {
+            linear: Delay {
+                in_value: { input.value },
+                in_time: { input.time },
+                in_division: { input.division },
+                in_feedback: { input.feedback },
+                in_damping: { input.damping },
+                in_mix: { 1.0 },
+                max_time: 1.0,
+            },
+            allpass: Delay {
+                in_value: { input.value },
+                in_time: { input.time },
+                in_feedback: { input.feedback },
+                in_mix: { 1.0 },
+                max_time: 1.0,
+                interpolation: (Interpolation::Allpass),
+            },
+            cubic: Delay {
+                in_value: { input.value },
+                in_time: { input.time },
+                in_feedback: { input.feedback },
+                in_mix: { 1.0 },
+                max_time: 1.0,
+                interpolation: (Interpolation::Cubic),
+            },
+        }
+    }
+
+    fn impulse_response(
+        input: DelayTestInput,
+        out: fn(&DelayTestRack) -> f32,
+        len: usize,
+    ) -> Vec<f32> {
+        let script = Script::new(input).at(1, |i| i.value = 0.0);
+        render(&DelayTestRack::new(), out, &script, 1000, len)
+    }
+
+    #[test]
+    fn test_delay() {
+        let input = DelayTestInput {
+            value: 1.0,
+            ..Default::default()
+        };
+        for out in [
+            |r: &DelayTestRack| r.linear.borrow().out,
+            |r: &DelayTestRack| r.allpass.borrow().out,
+            |r: &DelayTestRack| r.cubic.borrow().out,
+        ] {
+            let ir = impulse_response(input.clone(), out, 40);
+            let mut expected = [0.0; 40];
+            expected[10] = 1.0;
+            expected[20] = 0.5;
+            expected[30] = 0.25;
+            let error = ir.iter().zip(expected.iter()).map(|(a, b)| (a - b).abs());
+            assert!(error.fold(0.0, f32::max) < 1e-6, "{:?}", ir);
+        }
+
+        // damping spreads the echo
+        let ir = impulse_response(
+            DelayTestInput {
+                damping: 0.5,
+                ..input.clone()
+            },
+            |r| r.linear.borrow().out,
+            40,
+        );
+        assert!(ir[20] == 0.25 && ir[21] == 0.125);
+
+        // 1/8 at 120 BPM = 0.25 sec
+        let ir = impulse_response(
+            DelayTestInput {
+                division: Division::Eighth,
+                ..input.clone()
+            },
+            |r| r.linear.borrow().out,
+            300,
+        );
+        assert_eq!(ir[250], 1.0);
+
+        // invalid times fall back to the minimum delay
+        for time in [f32::NAN, f32::INFINITY] {
+            let ir = impulse_response(
+                DelayTestInput {
+                    time,
+                    feedback: 0.0,
+                    ..input.clone()
+                },
+                |r| r.cubic.borrow().out,
+                10,
+            );
+            assert!(ir.iter().all(|x| x.is_finite()), "{:?}", ir);
+        }
+        let rack = DelayTestRack::new();
+        rack.cubic.borrow_mut().max_time = 0.0;
+        let script = Script::new(input.clone()).at(1, |i| i.value = 0.0);
+        let ir = render(&rack, |r| r.cubic.borrow().out, &script, 1000, 10);
+        assert!(ir.iter().all(|x| x.is_finite()), "{:?}", ir);
+    }
+
+    #[test]
+    fn test_delay_fractional() {
+        let input = DelayTestInput {
+            value: 1.0,
+            time: 0.0105,
+            feedback: 0.0,
+            ..Default::default()
+        };
+        let ir = impulse_response(input.clone(), |r| r.linear.borrow().out, 20);
+        assert!(ir[10] == 0.5 && ir[11] == 0.5);
+        for out in [
+            |r: &DelayTestRack| r.allpass.borrow().out,
+            |r: &DelayTestRack| r.cubic.borrow().out,
+        ] {
+            let ir = impulse_response(input.clone(), out, 100);
+            // centered between 10 and 11
+            let center = ir
+                .iter()
+                .enumerate()
+                .map(|(i, x)| i as f32 * x)
+                .sum::<f32>()
+                / ir.iter().sum::<f32>();
+            assert!((center - 10.5).abs() < 0.05, "{} {:?}", center, ir);
+        }
+    }
+
+    define_input! {
+        ReverbTestInput {
+            value: f32 = 1.0,
+            size: f32 = 0.5,
+            predelay: f32,
+            mix: f32 = 1.0,
+        }
+    }
+    define_rack! {
+        ReverbTestRack: Rack<ReverbTestInput>(rack, input) {
+            reverb: Reverb {
+                in_value: { input.value },
+                in_size: { input.size },
+                in_predelay: { input.predelay },
+                in_mix: { input.mix },
+            },
+        }
+    }
+
+    fn reverb_ir(input: ReverbTestInput) -> Vec<f32> {
+        let script = Script::new(input).at(1, |i| i.value = 0.0);
+        render(
+            &ReverbTestRack::new(),
+            |r| r.reverb.borrow().out,
+            &script,
+            44_100,
+            88_200,
+        )
+    }
+
+    #[test]
+    fn test_reverb_impulse_response() {
+        let ir = reverb_ir(ReverbTestInput::default());
+        assert_eq!(ir, reverb_ir(ReverbTestInput::default()));
+        // nothing until the shortest comb
+        let first = ir.iter().position(|x| *x != 0.0).unwrap();
+        assert_eq!(first, 1116);
+        assert!(ir.iter().all(|x| x.is_finite() && x.abs() < 1.0));
+        // decays
+        let energy = ir.chunks(11_025).map(rms).collect::<Vec<_>>();
+        assert!(energy.windows(2).all(|w| w[1] < w[0]), "{:?}", energy);
+
+        // larger room rings longer
+        let large = reverb_ir(ReverbTestInput {
+            size: 1.0,
+            ..Default::default()
+        });
+        assert!(rms(&large[66_150..]) > rms(&ir[66_150..]) * 2.0);
+
+        let predelayed = reverb_ir(ReverbTestInput {
+            predelay: 0.1,
+            ..Default::default()
+        });
+        assert_eq!(&predelayed[4410..10_000], &ir[..10_000 - 4410]);
+
+        let dry = reverb_ir(ReverbTestInput {
+            mix: 0.0,
+            ..Default::default()
+        });
+        assert!(dry[0] == 1.0 && dry[1..].iter().all(|x| *x == 0.0));
+    }
+
+    define_input! {
+        PanTestInput {
+            pan: f32,
+        }
+    }
+    define_rack! {
+        PanTestRack: Rack<PanTestInput>(rack, input) {
+            pan: Pan {
+                in_value: { 1.0 },
+                in_pan: { input.pan },
+            },
+            buf: StereoBuf {
+                in_l: { rack.pan.borrow().out_r },
+                in_r: { rack.pan.borrow().out_l },
+            },
+        }
+    }
+
+    #[test]
+    fn test_pan() {
+        let pan = |pan: f32| {
+            let script = Script::new(PanTestInput { pan });
+            render(
+                &PanTestRack::new(),
+                |r| r.pan.borrow().out(),
+                &script,
+                44_100,
+                1,
+            )[0]
+        };
+        assert_eq!(pan(0.0), (1.0, 0.0));
+        let (l, r) = pan(1.0);
+        assert!(l.abs() < 1e-6 && r == 1.0);
+        // equal power
+        for p in [0.0, 0.25, 0.5, 0.8] {
+            let (l, r) = pan(p);
+            assert!((l * l + r * r - 1.0).abs() < 1e-6, "{}", p);
+        }
+        let (l, r) = pan(0.5);
+        assert!((l - r).abs() < 1e-6);
+        assert_eq!(pan(-1.0), pan(0.0));
+
+        let script = Script::new(PanTestInput { pan: 0.0 });
+        let out = render(
+            &PanTestRack::new(),
+            |r| r.buf.borrow().out(),
+            &script,
+            44_100,
+            1,
+        );
+        assert_eq!(out, &[(0.0, 1.0)]);
+    }
+
+    define_input! {
+        MixerTestInput {
+            gain: f32 = 1.0,
+            mute: bool,
+            solo: bool,
+            master: f32 = 1.0,
+        }
+    }
+    define_rack! {
+        MixerTestRack: Rack<MixerTestInput>(rack, input) {
+            mixer: Mixer {
+                channels: (vec![
+                    MixerChannel {
+                        in_gain: Box::new(|_, i: &MixerTestInput| i.gain),
+                        in_pan: Box::new(|_, _| 0.0),
+                        in_mute: Box::new(|_, i: &MixerTestInput| i.mute),
+                        ..MixerChannel::new(|_, _| 0.5)
+                    },
+                    MixerChannel {
+                        in_pan: Box::new(|_, _| 1.0),
+                        in_solo: Box::new(|_, i: &MixerTestInput| i.solo),
+                        ..MixerChannel::new(|_, _| 0.25)
+                    },
+                ]),
+                in_master: { input.master },
+            },
+            clipped: Mixer {
+                channels: (vec![MixerChannel::new(|_, i: &MixerTestInput| i.master)]),
+                soft_clip: true,
+            },
+        }
+    }
+
+    #[test]
+    fn test_mixer() {
+        let mix = |input: MixerTestInput| {
+            let rack = MixerTestRack::new();
+            render(
+                &rack,
+                |r| r.mixer.borrow().out,
+                &Script::new(input),
+                44_100,
+                1,
+            );
+            let mixer = rack.mixer.borrow();
+            (mixer.out, mixer.out_l, mixer.out_r)
+        };
+        let (out, l, r) = mix(MixerTestInput::default());
+        assert_eq!(out, 0.75);
+        assert!((l - 0.5).abs() < 1e-6 && (r - 0.25).abs() < 1e-6);
+        let (out, _, _) = mix(MixerTestInput {
+            gain: 0.5,
+            master: 2.0,
+            ..Default::default()
+        });
+        assert_eq!(out, 1.0);
+        let (out, l, _) = mix(MixerTestInput {
+            mute: true,
+            ..Default::default()
+        });
+        assert!(out == 0.25 && l.abs() < 1e-6);
+        // solo silences the others
+        let (out, _, _) = mix(MixerTestInput {
+            solo: true,
+            ..Default::default()
+        });
+        assert_eq!(out, 0.25);
+
+        let clipped = |master: f32| {
+            let script = Script::new(MixerTestInput {
+                master,
+                ..Default::default()
+            });
+            render(
+                &MixerTestRack::new(),
+                |r| r.clipped.borrow().out_stereo(),
+                &script,
+                44_100,
+                1,
+            )[0]
+        };
+        let (l, r) = clipped(2.0);
+        assert!(l < 1.0 && l == r && l > 0.8);
+        assert_eq!(clipped(100.0), (1.0, 1.0));
+        assert!((clipped(0.01).0 - 0.01 * std::f32::consts::FRAC_1_SQRT_2).abs() < 1e-4);
+    }
+
+    define_input! {
+        SequencerTestInput {
+            run: bool = true,
+            division: Division = (Division::Sixteenth),
+            clock: f32,
+        }
+    }
+    define_rack! {
+        SequencerTestRack: Rack<SequencerTestInput>(rack, input) {
+            seq: StepSequencer {
+                steps: (vec![
+                    Step::new(|_, _| 0.1, |_, _| true, |_, _| false, |_, _| false),
+                    Step::new(|_, _| 0.2, |_, _| false, |_, _| true, |_, _| true),
+                    Step::new(|_, _| 0.3, |_, _| true, |_, _| true, |_, _| true),
+                    Step::new(|_, _| 0.4, |_, _| true, |_, _| false, |_, _| false),
+                ]),
+                in_run: { input.run },
+                in_bpm: { 150.0 },
+                in_division: { input.division },
+                in_clock: { input.clock },
+            },
+        }
+    }
+
+    #[test]
+    fn test_step_sequencer() {
+        // 100 samples per step
+        let run = |script: &Script<SequencerTestInput>| {
+            let rack = SequencerTestRack::new();
+            render(
+                &rack,
+                |r| {
+                    let seq = r.seq.borrow();
+                    [
+                        seq.out_pitch,
+                        seq.out_gate as u8 as f32,
+                        seq.out_accent as u8 as f32,
+                        seq.out_trigger as u8 as f32,
+                    ]
+                },
+                script,
+                1000,
+                600,
+            )
+        };
+        let out = run(&Script::new(SequencerTestInput::default()));
+        let pitch = |i: usize| out[i][0];
+        let gate = |i: usize| out[i][1] == 1.0;
+        assert!(pitch(10) == 0.1 && gate(10) && !gate(60));
+        // rest, accent and slide are ignored without gate
+        assert!(pitch(150) == 0.2 && !gate(110) && out[150][2] == 0.0);
+        assert!(pitch(210) == 0.3 && gate(210) && out[210][2] == 1.0);
+        // slide holds the gate and glides
+        assert!((200..310).all(gate) && !gate(360));
+        assert!(0.3 < pitch(305) && pitch(305) < 0.32);
+        assert!(0.38 < pitch(399) && pitch(399) < 0.4);
+        assert!(pitch(410) == 0.1 && gate(410));
+        // triggered on step 0, 2 and 0, not by the slide into step 3
+        let triggers = out.windows(2).filter(|w| w[0][3] != w[1][3]).count();
+        assert!(out[0][3] == 1.0 && triggers == 2);
+
+        let stopped = run(&Script::new(SequencerTestInput::default())
+            .at(150, |i| i.run = false)
+            .at(250, |i| i.run = true));
+        assert!((150..250).all(|i| stopped[i][1] == 0.0));
+        assert!(stopped[260] == [0.1, 1.0, 0.0, 0.0]);
+
+        // external clock of 40 samples
+        let mut script = Script::new(SequencerTestInput {
+            division: Division::Free,
+            ..Default::default()
+        });
+        for t in (0..600).step_by(40) {
+            script = script.at(t, |i| i.clock = 1.0).at(t + 1, |i| i.clock = 0.0);
+        }
+        let out = run(&script);
+        assert!(out[10][0] == 0.1 && out[10][1] == 1.0);
+        // gate length is half of the measured interval
+        assert!(out[50][0] == 0.2 && out[90][0] == 0.3);
+        assert!(out[490][1] == 1.0 && out[510][1] == 0.0);
+
+        // steps beyond MAX_STEPS are ignored
+        let rack = SequencerTestRack::new();
+        rack.seq.borrow_mut().steps = (0..MAX_STEPS + 2)
+            .map(|i| {
+                Step::new(
+                    move |_, _| i as f32,
+                    |_, _| true,
+                    |_, _| false,
+                    |_, _| false,
+                )
+            })
+            .collect();
+        let out = render(
+            &rack,
+            |r| r.seq.borrow().out_pitch,
+            &Script::new(SequencerTestInput::default()),
+            1000,
+            MAX_STEPS * 100 + 100,
+        );
+        assert_eq!(out[(MAX_STEPS - 1) * 100 + 50], (MAX_STEPS - 1) as f32);
+        assert_eq!(out[MAX_STEPS * 100 + 50], 0.0);
+    }
+
+    #[test]
+    fn test_frame() {
+        let mut out = [9.0; 4];
+        0.5.write_to(&mut out);
+        assert_eq!(out, [0.5; 4]);
+        (0.25, 0.5).write_to(&mut out);
+        assert_eq!(out, [0.25, 0.5, 0.0, 0.0]);
+        // mixed down to mono
+        (0.25, 0.5).write_to(&mut out[..1]);
+        assert_eq!(out[0], 0.375);
+        (0.0, 1.0).write_to(&mut out[..1]);
+        assert_eq!(out[0], 0.5);
+        [1.0, 2.0, 3.0].mix([1.0; 3]).write_to(&mut out);
+        assert_eq!(out, [2.0, 3.0, 4.0, 0.0]);
+        assert_eq!((0.5, -0.75).peak(), 0.75);
+    }
+
+    /// Ratio of spectral energy outside the harmonics of `f0` to the total energy
+    fn aliasing_ratio(samples: &[f32], sample_rate: f32, f0: f32) -> f64 {
+        use std::f64::consts::PI;
+        let n = samples.len();
+        let windowed = samples
+            .iter()
+            .enumerate()
+            .map(|(i, x)| *x as f64 * (0.5 - 0.5 * (2.0 * PI * i as f64 / n as f64).cos()))
+            .collect::<Vec<_>>();
+        let table = (0..n)
+            .map(|i| (2.0 * PI * i as f64 / n as f64).sin_cos())
+            .collect::<Vec<_>>();
+        let bin_width = sample_rate as f64 / n as f64;
+        let mut total = 0.0;
+        let mut aliased = 0.0;
+        for k in 1..n / 2 {
+            let (mut re, mut im) = (0.0, 0.0);
+            for (i, x) in windowed.iter().enumerate() {
+                let (sin, cos) = table[i * k % n];
+                re += x * cos;
+                im -= x * sin;
+            }
+            let energy = re * re + im * im;
+            let freq = k as f64 * bin_width;
+            let harmonic = (freq / f0 as f64).round() * f0 as f64;
+            total += energy;
+            if (freq - harmonic).abs() > 3.0 * bin_width {
+                aliased += energy;
+            }
+        }
+        aliased / total
+    }
+
+    define_input! {
+        HardSyncTestInput {
+            waveform: WaveForm = (WaveForm::Sawtooth),
+        }
+    }
+    define_rack! {
+        HardSyncTestRack: Rack<HardSyncTestInput>(rack, input) {
+            master: VCO {
+                in_freq: { 0.0 },
+                freq_min: 1130.0,
+                freq_max: 1130.0,
+            },
+            naive: VCO {
+                in_freq: { 0.0 },
+                in_waveform: { input.waveform },
+                in_sync: { rack.master.borrow().out_sync },
+                freq_min: 2770.0,
+                freq_max: 2770.0,
+            },
+            band_limited: VCO {
+                in_freq: { 0.0 },
+                in_waveform: { input.waveform },
+                in_sync: { rack.master.borrow().out_sync },
+                freq_min: 2770.0,
+                freq_max: 2770.0,
+                band_limited: true,
+            },
+        }
+    }
+
+    #[test]
+    fn test_band_limited_hard_sync() {
+        for waveform in [WaveForm::Sawtooth, WaveForm::Square] {
+            let script = Script::new(HardSyncTestInput { waveform });
+            let naive = render(
+                &HardSyncTestRack::new(),
+                |r| r.naive.borrow().out,
+                &script,
+                44_100,
+                4410,
+            );
+            let band_limited = render(
+                &HardSyncTestRack::new(),
+                |r| r.band_limited.borrow().out,
+                &script,
+                44_100,
+                4410,
+            );
+            let naive = aliasing_ratio(&naive, 44_100.0, 1130.0);
+            let band_limited = aliasing_ratio(&band_limited, 44_100.0, 1130.0);
+            assert!(band_limited * 10.0 < naive, "{:?}", waveform);
+        }
+    }
+
+    #[test]
+    fn test_band_limited_vco() {
+        for waveform in [WaveForm::Sawtooth, WaveForm::Square, WaveForm::Triangle] {
+            let script = Script::new(VCOTestInput { waveform });
+            let naive = render(
+                &VCOTestRack::new(),
+                |r| r.naive.borrow().out,
+                &script,
+                44_100,
+                4410,
+            );
+            let band_limited = render(
+                &VCOTestRack::new(),
+                |r| r.band_limited.borrow().out,
+                &script,
+                44_100,
+                4410,
+            );
+            let naive = aliasing_ratio(&naive, 44_100.0, 3130.0);
+            let band_limited = aliasing_ratio(&band_limited, 44_100.0, 3130.0);
+            assert!(band_limited * 10.0 < naive, "{:?}", waveform);
+        }
+    }
+}
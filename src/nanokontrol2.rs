@@ -92,7 +92,7 @@ fn decode_block(data: &[u8], buf: &mut Vec<u8>) {
     }
 }
 fn decode(data: &[u8]) -> Vec<u8> {
-    let mut buf = Vec::with_capacity((data.len() + 7) / 8 * 7);
+    let mut buf = Vec::with_capacity(data.len().div_ceil(8) * 7);
     let mut i = 0;
     while i < data.len() / 8 {
         decode_block(&data[(i * 8)..(i * 8 + 8)], &mut buf);
@@ -0,0 +1,374 @@
+use crate::input::{Input, NoteInputConfig, StateDefinition};
+use crate::module::{Frame, ProcessContext, Rack};
+use std::cell::RefCell;
+
+/// Notes currently held on the keyboard. Voices are allocated from this on the audio thread.
+#[derive(Clone)]
+pub struct HeldNotes {
+    /// 0 = not held
+    velocity: [u8; 128],
+    /// Serial number of the note on event
+    serial: [u32; 128],
+    next_serial: u32,
+}
+impl Default for HeldNotes {
+    fn default() -> Self {
+        HeldNotes {
+            velocity: [0; 128],
+            serial: [0; 128],
+            next_serial: 1,
+        }
+    }
+}
+impl std::fmt::Debug for HeldNotes {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::result::Result<(), std::fmt::Error> {
+        fmt.debug_map()
+            .entries(self.iter().map(|(note, velocity, _)| (note, velocity)))
+            .finish()
+    }
+}
+impl HeldNotes {
+    pub fn note_on(&mut self, note: u8, velocity: u8) {
+        let note = (note & 0x7F) as usize;
+        self.velocity[note] = velocity.max(1);
+        self.serial[note] = self.next_serial;
+        self.next_serial += 1;
+    }
+    pub fn note_off(&mut self, note: u8) {
+        self.velocity[(note & 0x7F) as usize] = 0;
+    }
+    /// Serial number of the latest note on event
+    pub fn latest_serial(&self) -> u32 {
+        self.next_serial - 1
+    }
+    /// (note, velocity, serial) of held notes
+    pub fn iter(&self) -> impl Iterator<Item = (u8, u8, u32)> + '_ {
+        (0..128)
+            .filter(|i| self.velocity[*i] != 0)
+            .map(|i| (i as u8, self.velocity[i], self.serial[i]))
+    }
+    fn is_held(&self, note: u8, serial: u32) -> bool {
+        self.velocity[note as usize] != 0 && self.serial[note as usize] == serial
+    }
+}
+
+/// Input of `Poly`: parameters shared by all voices and notes to be allocated.
+#[derive(Debug)]
+pub struct PolyInput<I> {
+    pub params: I,
+    pub notes: HeldNotes,
+}
+impl<I: Clone> Clone for PolyInput<I> {
+    fn clone(&self) -> Self {
+        PolyInput {
+            params: self.params.clone(),
+            notes: self.notes.clone(),
+        }
+    }
+    /// Reuses the buffers of the params, so that the audio thread doesn't allocate
+    fn clone_from(&mut self, source: &Self) {
+        self.params.clone_from(&source.params);
+        self.notes.clone_from(&source.notes);
+    }
+}
+impl<I: Input> Input for PolyInput<I> {
+    fn held_notes_mut(&mut self) -> Option<&mut HeldNotes> {
+        Some(&mut self.notes)
+    }
+}
+
+/// Which voice to reuse when all voices are busy
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VoiceStealing {
+    /// The voice playing the oldest note
+    #[default]
+    Oldest,
+    /// The voice with the lowest output level
+    Quietest,
+    /// The voice that played the same note last, falls back to Oldest
+    SameNote,
+}
+
+#[derive(Debug, Default)]
+struct Voice {
+    /// (note, velocity) currently or last played
+    note: Option<(u8, u8)>,
+    /// Serial of the note while it's held
+    serial: Option<u32>,
+    /// Toggled on each note on
+    trigger: bool,
+    /// Order of note on/off for this voice
+    last_event: u32,
+    /// Peak level of the output
+    level: f32,
+}
+
+struct PolyState<F> {
+    voices: Vec<Voice>,
+    last_serial: u32,
+    events: u32,
+    out: F,
+}
+
+/// Runs `voices` copies of a rack and allocates held notes to them.
+/// Note input fields (pitch, gate, velocity, trigger) are set per voice, other fields are shared.
+pub struct Poly<R: Rack, F: Frame = f32> {
+    voices: Vec<R>,
+    voice_out: Box<dyn Fn(&R) -> F + Send>,
+    note_inputs: Vec<NoteInputConfig>,
+    state_definition: StateDefinition<R::Input>,
+    stealing: VoiceStealing,
+    state: RefCell<PolyState<F>>,
+    /// Per voice copy of the input, reused to avoid allocation
+    voice_inputs: RefCell<Vec<R::Input>>,
+}
+/// Per-sample decay of the level used by VoiceStealing::Quietest
+const LEVEL_DECAY: f32 = 0.999;
+impl<R: Rack, F: Frame> Poly<R, F> {
+    pub fn new(
+        voices: usize,
+        new_voice: impl Fn() -> R,
+        voice_out: impl Fn(&R) -> F + Send + 'static,
+        note_inputs: Vec<NoteInputConfig>,
+        stealing: VoiceStealing,
+    ) -> Poly<R, F> {
+        let voices = (0..voices.max(1)).map(|_| new_voice()).collect::<Vec<_>>();
+        let state_definition = voices[0].state_definition();
+        for input in note_inputs.iter() {
+            state_definition.assert_has_field(input.name());
+        }
+        Poly {
+            state: RefCell::new(PolyState {
+                voices: voices.iter().map(|_| Voice::default()).collect(),
+                last_serial: 0,
+                events: 0,
+                out: F::SILENCE,
+            }),
+            voice_inputs: RefCell::new(voices.iter().map(|v| v.new_input()).collect()),
+            voices,
+            voice_out: Box::new(voice_out),
+            note_inputs,
+            state_definition,
+            stealing,
+        }
+    }
+    pub fn voices(&self) -> &[R] {
+        &self.voices
+    }
+    /// Sum of all voices
+    pub fn out(&self) -> F {
+        self.state.borrow().out
+    }
+    /// Note currently held by each voice
+    pub fn voice_notes(&self) -> Vec<Option<u8>> {
+        self.state
+            .borrow()
+            .voices
+            .iter()
+            .map(|v| v.serial.and(v.note).map(|(n, _)| n))
+            .collect()
+    }
+    fn allocate(&self, state: &mut PolyState<F>, notes: &HeldNotes) {
+        if state.last_serial == notes.latest_serial()
+            && state.voices.iter().all(|v| match (v.note, v.serial) {
+                (Some((n, _)), Some(serial)) => notes.is_held(n, serial),
+                _ => true,
+            })
+        {
+            return;
+        }
+        for v in state.voices.iter_mut() {
+            if let (Some((n, _)), Some(serial)) = (v.note, v.serial) {
+                if !notes.is_held(n, serial) {
+                    v.serial = None;
+                    state.events += 1;
+                    v.last_event = state.events;
+                }
+            }
+        }
+        // in the order of note on
+        while let Some((note, velocity, serial)) = notes
+            .iter()
+            .filter(|(_, _, serial)| state.last_serial < *serial)
+            .min_by_key(|(_, _, serial)| *serial)
+        {
+            let i = self.select_voice(&state.voices, note);
+            state.events += 1;
+            let v = &mut state.voices[i];
+            v.note = Some((note, velocity));
+            v.serial = Some(serial);
+            v.trigger = !v.trigger;
+            v.last_event = state.events;
+            state.last_serial = serial;
+        }
+        state.last_serial = notes.latest_serial();
+    }
+    fn select_voice(&self, voices: &[Voice], note: u8) -> usize {
+        let last_note = |v: &Voice| v.note.map(|(n, _)| n);
+        if self.stealing == VoiceStealing::SameNote {
+            if let Some(i) = voices.iter().position(|v| last_note(v) == Some(note)) {
+                return i;
+            }
+        }
+        let free = voices
+            .iter()
+            .enumerate()
+            .filter(|(_, v)| v.serial.is_none());
+        let busy = voices.iter().enumerate();
+        let selected = match self.stealing {
+            VoiceStealing::Oldest | VoiceStealing::SameNote => free
+                .min_by_key(|(_, v)| v.last_event)
+                .or_else(|| busy.min_by_key(|(_, v)| v.last_event)),
+            VoiceStealing::Quietest => free
+                .min_by(|(_, a), (_, b)| a.level.total_cmp(&b.level))
+                .or_else(|| busy.min_by(|(_, a), (_, b)| a.level.total_cmp(&b.level))),
+        };
+        selected.map(|(i, _)| i).unwrap_or(0)
+    }
+}
+impl<R: Rack, F: Frame> Rack for Poly<R, F> {
+    type Input = PolyInput<R::Input>;
+    fn new_input(&self) -> Self::Input {
+        PolyInput {
+            params: self.voices[0].new_input(),
+            notes: HeldNotes::default(),
+        }
+    }
+    fn state_definition(&self) -> StateDefinition<Self::Input> {
+        self.voices[0]
+            .state_definition()
+            .map(|p: &PolyInput<R::Input>| &p.params, |p| &mut p.params)
+    }
+    fn update(&self, input: &Self::Input, ctx: &ProcessContext) {
+        let mut state = self.state.borrow_mut();
+        self.allocate(&mut state, &input.notes);
+        let mut voice_inputs = self.voice_inputs.borrow_mut();
+        let mut out = F::SILENCE;
+        for ((rack, voice), voice_input) in self
+            .voices
+            .iter()
+            .zip(state.voices.iter_mut())
+            .zip(voice_inputs.iter_mut())
+        {
+            voice_input.clone_from(&input.params);
+            self.state_definition.apply_note(
+                &self.note_inputs,
+                voice_input,
+                voice.note,
+                voice.serial.is_some(),
+                voice.trigger,
+            );
+            rack.update(voice_input, ctx);
+            let value = (self.voice_out)(rack);
+            voice.level = value.peak().max(voice.level * LEVEL_DECAY);
+            out = out.mix(value);
+        }
+        state.out = out;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::module::Buf;
+    use crate::{define_input, define_rack};
+
+    define_input! {
+        VoiceInput {
+            pitch: f32,
+            gate: bool,
+            level: f32 = 1.0,
+        }
+    }
+    define_rack! {
+        VoiceRack: Rack<VoiceInput>(rack, input) {
+            out: Buf {
+                in_value: { if input.gate { input.level } else { 0.0 } },
+            },
+        }
+    }
+
+    fn poly(voices: usize, stealing: VoiceStealing) -> Poly<VoiceRack> {
+        Poly::new(
+            voices,
+            VoiceRack::new,
+            |r| r.out.borrow().out,
+            vec![
+                NoteInputConfig::Pitch {
+                    name: "pitch".to_owned(),
+                    freq_min: 100.0,
+                    freq_max: 1000.0,
+                },
+                NoteInputConfig::Gate {
+                    name: "gate".to_owned(),
+                },
+            ],
+            stealing,
+        )
+    }
+
+    #[test]
+    fn test_allocation() {
+        let ctx = ProcessContext::new(44_100);
+        let poly = poly(3, VoiceStealing::Oldest);
+        let mut input = poly.new_input();
+        input.notes.note_on(60, 100);
+        input.notes.note_on(64, 100);
+        poly.update(&input, &ctx);
+        assert_eq!(poly.voice_notes(), &[Some(60), Some(64), None]);
+        assert_eq!(poly.out(), 2.0);
+
+        input.notes.note_off(60);
+        input.notes.note_on(67, 100);
+        poly.update(&input, &ctx);
+        assert_eq!(poly.voice_notes(), &[None, Some(64), Some(67)]);
+
+        // released voice is reused before stealing
+        input.notes.note_on(71, 100);
+        poly.update(&input, &ctx);
+        assert_eq!(poly.voice_notes(), &[Some(71), Some(64), Some(67)]);
+        assert_eq!(poly.out(), 3.0);
+
+        // steal the oldest
+        input.notes.note_on(72, 100);
+        poly.update(&input, &ctx);
+        assert_eq!(poly.voice_notes(), &[Some(71), Some(72), Some(67)]);
+    }
+
+    #[test]
+    fn test_same_note() {
+        let ctx = ProcessContext::new(44_100);
+        let poly = poly(2, VoiceStealing::SameNote);
+        let mut input = poly.new_input();
+        input.notes.note_on(60, 100);
+        poly.update(&input, &ctx);
+        input.notes.note_on(64, 100);
+        poly.update(&input, &ctx);
+        input.notes.note_off(64);
+        poly.update(&input, &ctx);
+        input.notes.note_off(60);
+        poly.update(&input, &ctx);
+        assert_eq!(poly.voice_notes(), &[None, None]);
+        // Oldest would choose the first voice
+        input.notes.note_on(64, 100);
+        poly.update(&input, &ctx);
+        assert_eq!(poly.voice_notes(), &[None, Some(64)]);
+    }
+
+    #[test]
+    fn test_quietest() {
+        let ctx = ProcessContext::new(44_100);
+        let poly = poly(2, VoiceStealing::Quietest);
+        let mut input = poly.new_input();
+        input.notes.note_on(60, 100);
+        poly.update(&input, &ctx);
+        input.params.level = 0.5;
+        input.notes.note_on(64, 100);
+        poly.update(&input, &ctx);
+        assert_eq!(poly.voice_notes(), &[Some(60), Some(64)]);
+        input.notes.note_on(67, 100);
+        poly.update(&input, &ctx);
+        assert_eq!(poly.voice_notes(), &[Some(60), Some(67)]);
+    }
+}
@@ -0,0 +1,141 @@
+use crate::input::{FieldAccessor, StateDefinition};
+use anyhow::{Context, Result};
+use toml::value::{Table, Value};
+
+/// Current values of all fields
+pub fn to_preset<S>(definition: &StateDefinition<S>, state: &S) -> Table {
+    definition
+        .fields()
+        .map(|(name, accessor)| {
+            let value = match accessor {
+                // Via the decimal representation, to save 0.1 rather than 0.10000000149011612
+                FieldAccessor::F32(get, _) => {
+                    Value::Float(get(state).to_string().parse::<f64>().unwrap())
+                }
+                FieldAccessor::Bool(get, _) => Value::Boolean(get(state)),
+                FieldAccessor::Enum(get, _) => Value::String(get(state).to_owned()),
+            };
+            (name.to_owned(), value)
+        })
+        .collect()
+}
+
+/// Set fields in the preset. Fields not in the preset are left as is.
+/// Nothing is changed if the preset has an error.
+pub fn apply_preset<S: Clone>(
+    definition: &StateDefinition<S>,
+    state: &mut S,
+    preset: &Table,
+) -> Result<()> {
+    // Applied to a copy, because enum values are validated by setting them
+    let mut new_state = state.clone();
+    let state_ref = &mut new_state;
+    for (name, value) in preset.iter() {
+        if definition.field_type(name).is_none() {
+            anyhow::bail!("Field not defined: {}", name);
+        }
+        match definition.field(name) {
+            FieldAccessor::F32(_, set) => {
+                let value = match value {
+                    Value::Float(x) => *x as f32,
+                    Value::Integer(x) => *x as f32,
+                    _ => anyhow::bail!("Type error at {}", name),
+                };
+                set(state_ref, value);
+            }
+            FieldAccessor::Bool(_, set) => {
+                set(
+                    state_ref,
+                    value
+                        .as_bool()
+                        .with_context(|| format!("Type error at {}", name))?,
+                );
+            }
+            FieldAccessor::Enum(get, set) => {
+                let value = value
+                    .as_str()
+                    .with_context(|| format!("Type error at {}", name))?;
+                set(state_ref, value);
+                if get(state_ref) != value {
+                    anyhow::bail!("Invalid value at {}: {}", name, value);
+                }
+            }
+        }
+    }
+    *state = new_state;
+    Ok(())
+}
+
+pub fn load_preset<S: Clone>(
+    path: &str,
+    definition: &StateDefinition<S>,
+    state: &mut S,
+) -> Result<()> {
+    let content =
+        std::fs::read_to_string(path).with_context(|| format!("Can't read preset: {}", path))?;
+    let preset = content
+        .parse::<Value>()
+        .with_context(|| format!("Can't parse preset: {}", path))?;
+    let preset = preset.as_table().context("Type error")?;
+    apply_preset(definition, state, preset).with_context(|| format!("Invalid preset: {}", path))
+}
+
+pub fn save_preset<S>(path: &str, definition: &StateDefinition<S>, state: &S) -> Result<()> {
+    let content = toml::to_string(&to_preset(definition, state))?;
+    std::fs::write(path, content).with_context(|| format!("Can't write preset: {}", path))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::define_input;
+    use crate::input::StaticInput;
+    use crate::WaveForm;
+
+    define_input! {
+        PresetTestInput {
+            freq: f32 = 0.5,
+            gate: bool,
+            waveform: WaveForm = (WaveForm::Sine),
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let definition = PresetTestInput::new_state_definition();
+        let state = PresetTestInput {
+            freq: 0.1,
+            gate: true,
+            waveform: WaveForm::Square,
+        };
+        let preset = to_preset(&definition, &state);
+        let content = toml::to_string(&preset).unwrap();
+        assert_eq!(content, "freq = 0.1\ngate = true\nwaveform = \"Square\"\n");
+
+        let preset = content.parse::<Value>().unwrap();
+        let mut restored = PresetTestInput::default();
+        apply_preset(&definition, &mut restored, preset.as_table().unwrap()).unwrap();
+        assert_eq!(restored.freq, 0.1);
+        assert!(restored.gate);
+        assert_eq!(restored.waveform, WaveForm::Square);
+    }
+
+    #[test]
+    fn test_apply_partial() {
+        let definition = PresetTestInput::new_state_definition();
+        let mut state = PresetTestInput::default();
+        let preset = "freq = 1".parse::<Value>().unwrap();
+        apply_preset(&definition, &mut state, preset.as_table().unwrap()).unwrap();
+        assert_eq!(state.freq, 1.0);
+        assert_eq!(state.waveform, WaveForm::Sine);
+
+        for invalid in ["unknown = 1.0", "gate = 1", "waveform = \"Foo\""] {
+            // valid fields are not applied either
+            let preset = format!("freq = 0.2\n{}", invalid).parse::<Value>().unwrap();
+            assert!(apply_preset(&definition, &mut state, preset.as_table().unwrap()).is_err());
+            assert_eq!(state.freq, 1.0);
+            assert!(!state.gate);
+            assert_eq!(state.waveform, WaveForm::Sine);
+        }
+    }
+}
@@ -0,0 +1,208 @@
+use crate::module::{Frame, ProcessContext, Rack};
+use anyhow::Result;
+use std::io::Write;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum SampleFormat {
+    I16,
+    I24,
+    F32,
+}
+impl SampleFormat {
+    fn bits_per_sample(&self) -> u16 {
+        match self {
+            SampleFormat::I16 => 16,
+            SampleFormat::I24 => 24,
+            SampleFormat::F32 => 32,
+        }
+    }
+    fn format_tag(&self) -> u16 {
+        match self {
+            SampleFormat::I16 | SampleFormat::I24 => 1, // WAVE_FORMAT_PCM
+            SampleFormat::F32 => 3,                     // WAVE_FORMAT_IEEE_FLOAT
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct WavSpec {
+    pub channels: u16,
+    pub sample_rate: u32,
+    pub format: SampleFormat,
+}
+
+type ScriptEvent<I> = (usize, Box<dyn Fn(&mut I)>);
+
+/// Input changes applied at given sample positions while rendering.
+pub struct Script<I> {
+    initial: I,
+    events: Vec<ScriptEvent<I>>,
+}
+impl<I: Clone> Script<I> {
+    pub fn new(initial: I) -> Script<I> {
+        Script {
+            initial,
+            events: Vec::new(),
+        }
+    }
+    /// Modify the input just before the `sample`-th update.
+    pub fn at(mut self, sample: usize, f: impl Fn(&mut I) + 'static) -> Script<I> {
+        let i = self.events.partition_point(|(s, _)| *s <= sample);
+        self.events.insert(i, (sample, Box::new(f)));
+        self
+    }
+}
+
+/// Run `rack` for `len` samples and collect the value of `rack_out` after each update.
+pub fn render<R: Rack, F: Frame>(
+    rack: &R,
+    rack_out: impl Fn(&R) -> F,
+    script: &Script<R::Input>,
+    sample_rate: u32,
+    len: usize,
+) -> Vec<F> {
+    let ctx = ProcessContext::new(sample_rate);
+    let mut input = script.initial.clone();
+    let mut events = script.events.iter().peekable();
+    let mut out = Vec::with_capacity(len);
+    for i in 0..len {
+        while let Some((_, f)) = events.next_if(|(s, _)| *s <= i) {
+            f(&mut input);
+        }
+        rack.update(&input, &ctx);
+        out.push(rack_out(rack));
+    }
+    out
+}
+
+/// Write interleaved `samples` as a RIFF WAVE stream.
+/// Integer formats are clipped to -1.0 - 1.0.
+pub fn write_wav<W: Write>(w: &mut W, spec: &WavSpec, samples: &[f32]) -> Result<()> {
+    let bytes_per_sample = spec.format.bits_per_sample() as u32 / 8;
+    let block_align = bytes_per_sample * spec.channels as u32;
+    let data_len = bytes_per_sample * samples.len() as u32;
+
+    w.write_all(b"RIFF")?;
+    w.write_all(&(36 + data_len).to_le_bytes())?;
+    w.write_all(b"WAVE")?;
+
+    w.write_all(b"fmt ")?;
+    w.write_all(&16u32.to_le_bytes())?;
+    w.write_all(&spec.format.format_tag().to_le_bytes())?;
+    w.write_all(&spec.channels.to_le_bytes())?;
+    w.write_all(&spec.sample_rate.to_le_bytes())?;
+    w.write_all(&(spec.sample_rate * block_align).to_le_bytes())?;
+    w.write_all(&(block_align as u16).to_le_bytes())?;
+    w.write_all(&spec.format.bits_per_sample().to_le_bytes())?;
+
+    w.write_all(b"data")?;
+    w.write_all(&data_len.to_le_bytes())?;
+    for x in samples {
+        match spec.format {
+            SampleFormat::I16 => {
+                let v = (x.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+                w.write_all(&v.to_le_bytes())?;
+            }
+            SampleFormat::I24 => {
+                let v = (x.clamp(-1.0, 1.0) * 8_388_607.0) as i32;
+                w.write_all(&v.to_le_bytes()[0..3])?;
+            }
+            SampleFormat::F32 => {
+                w.write_all(&x.to_le_bytes())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Render `rack` offline and save the result as a WAV file with the channels of the frame.
+pub fn render_to_wav<R: Rack, F: Frame>(
+    path: impl AsRef<std::path::Path>,
+    format: SampleFormat,
+    rack: &R,
+    rack_out: impl Fn(&R) -> F,
+    script: &Script<R::Input>,
+    sample_rate: u32,
+    len: usize,
+) -> Result<()> {
+    let samples = render(rack, rack_out, script, sample_rate, len)
+        .iter()
+        .flat_map(|frame| (0..F::CHANNELS).map(|i| frame.channel(i)))
+        .collect::<Vec<_>>();
+    let spec = WavSpec {
+        channels: F::CHANNELS as u16,
+        sample_rate,
+        format,
+    };
+    let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    write_wav(&mut file, &spec, &samples)?;
+    file.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::module::Buf;
+    use crate::{define_input, define_rack};
+
+    define_input! {
+        TestInput {
+            value: f32 = 0.25,
+        }
+    }
+    define_rack! {
+        TestRack: Rack<TestInput>(rack, input) {
+            buf: Buf {
+                in_value: { input.value },
+            },
+        }
+    }
+
+    #[test]
+    fn test_render_script() {
+        let rack = TestRack::new();
+        let script = Script::new(TestInput::default())
+            .at(3, |i| i.value = 1.0)
+            .at(1, |i| i.value = 0.5)
+            .at(3, |i| i.value += 1.0);
+        let out = render(&rack, |r| r.buf.borrow().out, &script, 44_100, 5);
+        assert_eq!(out, &[0.25, 0.5, 0.5, 2.0, 2.0]);
+    }
+
+    #[test]
+    fn test_write_wav() {
+        let spec = WavSpec {
+            channels: 1,
+            sample_rate: 44_100,
+            format: SampleFormat::I16,
+        };
+        let mut buf = Vec::new();
+        write_wav(&mut buf, &spec, &[0.0, 1.0, -2.0]).unwrap();
+        assert_eq!(buf.len(), 44 + 6);
+        assert_eq!(&buf[0..4], b"RIFF");
+        assert_eq!(&buf[4..8], &42u32.to_le_bytes());
+        assert_eq!(&buf[22..24], &1u16.to_le_bytes());
+        assert_eq!(&buf[24..28], &44_100u32.to_le_bytes());
+        assert_eq!(&buf[40..44], &6u32.to_le_bytes());
+        assert_eq!(&buf[44..], &[0x00, 0x00, 0xFF, 0x7F, 0x01, 0x80]);
+
+        let spec = WavSpec {
+            format: SampleFormat::I24,
+            ..spec
+        };
+        let mut buf = Vec::new();
+        write_wav(&mut buf, &spec, &[1.0]).unwrap();
+        assert_eq!(&buf[34..36], &24u16.to_le_bytes());
+        assert_eq!(&buf[44..], &[0xFF, 0xFF, 0x7F]);
+
+        let spec = WavSpec {
+            format: SampleFormat::F32,
+            ..spec
+        };
+        let mut buf = Vec::new();
+        write_wav(&mut buf, &spec, &[0.5]).unwrap();
+        assert_eq!(&buf[20..22], &3u16.to_le_bytes());
+        assert_eq!(&buf[44..], &0.5f32.to_le_bytes());
+    }
+}
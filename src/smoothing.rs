@@ -0,0 +1,204 @@
+use crate::input::{FieldAccessor, StateDefinition};
+use anyhow::Result;
+use std::collections::BTreeMap;
+
+/// Per sample smoothing of a f32 field to avoid zipper noise of stepped CC values
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Smoothing {
+    /// Exponential approach with the time constant (sec)
+    OnePole(f32),
+    /// Constant rate ramp that reaches the target in the time (sec)
+    Linear(f32),
+}
+
+type Get<S> = Box<dyn Fn(&S) -> f32 + Send + Sync>;
+type Set<S> = Box<dyn Fn(&mut S, f32) + Send + Sync>;
+
+struct SmoothedField<S> {
+    get: Get<S>,
+    set: Set<S>,
+    smoothing: Smoothing,
+    /// Current output
+    value: f32,
+    target: f32,
+    /// Per sample coefficient of Smoothing::OnePole
+    coef: f32,
+    /// Per sample delta of Smoothing::Linear
+    step: f32,
+}
+
+/// Smoothed copy of the input.
+/// Feed the latest input with `set_input` and advance by `next_sample` per sample.
+pub struct Smoother<S> {
+    fields: Vec<SmoothedField<S>>,
+    input: S,
+    sample_rate: f32,
+}
+impl<S: Clone> Smoother<S> {
+    /// Smoothing defined in the state definition is overridden by `smoothing`
+    pub fn new(
+        state_definition: StateDefinition<S>,
+        smoothing: &BTreeMap<String, Smoothing>,
+        sample_rate: u32,
+        input: S,
+    ) -> Result<Smoother<S>> {
+        let mut settings = state_definition
+            .smoothing()
+            .map(|(name, s)| (name.to_owned(), s))
+            .collect::<BTreeMap<_, _>>();
+        settings.extend(smoothing.iter().map(|(name, s)| (name.clone(), *s)));
+        let mut accessors = state_definition.into_fields().collect::<BTreeMap<_, _>>();
+        let mut fields = Vec::new();
+        for (name, smoothing) in settings {
+            match accessors.remove(&name) {
+                Some(FieldAccessor::F32(get, set)) => fields.push(SmoothedField {
+                    value: get(&input),
+                    target: get(&input),
+                    get,
+                    set,
+                    smoothing,
+                    coef: match smoothing {
+                        Smoothing::OnePole(time) => {
+                            1.0 - (-1.0 / (time * sample_rate as f32)).exp()
+                        }
+                        Smoothing::Linear(_) => 1.0,
+                    },
+                    step: 0.0,
+                }),
+                Some(_) => anyhow::bail!("Type error at smoothing.{}: f32 field required", name),
+                None => anyhow::bail!("Field not defined: smoothing.{}", name),
+            }
+        }
+        Ok(Smoother {
+            fields,
+            input,
+            sample_rate: sample_rate as f32,
+        })
+    }
+    pub fn set_input(&mut self, input: &S) {
+        self.input.clone_from(input);
+        for field in self.fields.iter_mut() {
+            let target = (field.get)(input);
+            if target != field.target {
+                field.target = target;
+                if let Smoothing::Linear(time) = field.smoothing {
+                    field.step = (target - field.value) / (time * self.sample_rate).max(1.0);
+                }
+            }
+        }
+    }
+    /// The input at the next sample
+    pub fn next_sample(&mut self) -> &S {
+        for field in self.fields.iter_mut() {
+            field.value = match field.smoothing {
+                Smoothing::OnePole(_) => field.value + (field.target - field.value) * field.coef,
+                Smoothing::Linear(_) => {
+                    if (field.target - field.value).abs() <= field.step.abs() {
+                        field.target
+                    } else {
+                        field.value + field.step
+                    }
+                }
+            };
+            (field.set)(&mut self.input, field.value);
+        }
+        &self.input
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::define_input;
+    use crate::input::StaticInput;
+
+    define_input! {
+        SmoothInput {
+            #[smoothing = Smoothing::OnePole(0.01)]
+            cutoff: f32 = 0.5,
+            level: f32,
+            gate: bool,
+        }
+    }
+
+    #[test]
+    fn test_one_pole() {
+        let input = SmoothInput::default();
+        let mut smoother = Smoother::new(
+            SmoothInput::new_state_definition(),
+            &BTreeMap::new(),
+            1000,
+            input.clone(),
+        )
+        .unwrap();
+        smoother.set_input(&input);
+        assert_eq!(smoother.next_sample().cutoff, 0.5);
+
+        let input = SmoothInput {
+            cutoff: 1.0,
+            level: 1.0,
+            gate: true,
+        };
+        smoother.set_input(&input);
+        // 1 - 1/e after the time constant
+        for _ in 0..9 {
+            smoother.next_sample();
+        }
+        let out = smoother.next_sample();
+        assert!((out.cutoff - (1.0 - 0.5 / std::f32::consts::E)).abs() < 1e-3);
+        // not smoothed
+        assert_eq!(out.level, 1.0);
+        assert!(out.gate);
+    }
+
+    #[test]
+    fn test_linear() {
+        let input = SmoothInput::default();
+        let smoothing = [
+            ("cutoff".to_owned(), Smoothing::OnePole(0.0)),
+            ("level".to_owned(), Smoothing::Linear(0.004)),
+        ]
+        .into_iter()
+        .collect();
+        let mut smoother = Smoother::new(
+            SmoothInput::new_state_definition(),
+            &smoothing,
+            1000,
+            input.clone(),
+        )
+        .unwrap();
+        smoother.set_input(&input);
+        smoother.next_sample();
+        smoother.set_input(&SmoothInput {
+            cutoff: 1.0,
+            level: 1.0,
+            gate: false,
+        });
+        let levels = (0..5)
+            .map(|_| smoother.next_sample().level)
+            .collect::<Vec<_>>();
+        assert_eq!(levels, &[0.25, 0.5, 0.75, 1.0, 1.0]);
+        // overridden by the config
+        assert_eq!(smoother.next_sample().cutoff, 1.0);
+
+        let error = |name: &str| {
+            let smoothing = [(name.to_owned(), Smoothing::Linear(0.1))]
+                .into_iter()
+                .collect();
+            Smoother::new(
+                SmoothInput::new_state_definition(),
+                &smoothing,
+                1000,
+                input.clone(),
+            )
+            .err()
+            .unwrap()
+            .to_string()
+        };
+        assert_eq!(
+            error("gate"),
+            "Type error at smoothing.gate: f32 field required"
+        );
+        assert_eq!(error("foo"), "Field not defined: smoothing.foo");
+    }
+}
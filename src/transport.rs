@@ -0,0 +1,199 @@
+use crate::midi_message::MidiMessage;
+
+const CLOCKS_PER_BEAT: u32 = 24;
+/// Weight of the latest interval in the smoothed tempo
+const SMOOTHING: f64 = 0.1;
+/// us, longer intervals of MIDI clock restart the tempo estimation (10 BPM)
+const MAX_INTERVAL: u64 = 250_000;
+
+/// Tempo and song position of the MIDI clock
+#[derive(Debug, Clone, PartialEq)]
+pub struct Transport {
+    pub playing: bool,
+    /// None until MIDI clock is received
+    pub bpm: Option<f32>,
+    /// Quarter notes since the start of the song.
+    /// f64, so that a sample's step isn't lost in long songs.
+    pub position: f64,
+    /// Position of the next MIDI clock. `advance` doesn't go beyond it.
+    pub next_clock: f64,
+}
+impl Default for Transport {
+    fn default() -> Self {
+        Transport {
+            playing: false,
+            bpm: None,
+            position: 0.0,
+            next_clock: f64::INFINITY,
+        }
+    }
+}
+impl Transport {
+    /// Interpolate the position between MIDI clocks
+    pub fn advance(&mut self, sec: f32) {
+        if let (true, Some(bpm)) = (self.playing, self.bpm) {
+            self.position = (self.position + sec as f64 * bpm as f64 / 60.0).min(self.next_clock);
+        }
+    }
+    /// Follow the transport at the latest MIDI clock.
+    /// The interpolated position is kept unless it's out of the current clock.
+    pub fn sync(&mut self, clock: &Transport) {
+        if !(clock.position <= self.position && self.position <= clock.next_clock) {
+            self.position = clock.position;
+        }
+        self.playing = clock.playing;
+        self.bpm = clock.bpm;
+        self.next_clock = clock.next_clock;
+    }
+}
+
+/// Follows MIDI clock, start, stop, continue and song position pointer.
+#[derive(Debug, Default)]
+pub struct MidiClock {
+    transport: Transport,
+    /// MIDI clocks since the start of the song
+    clocks: u32,
+    /// us
+    last_stamp: Option<u64>,
+    /// us, smoothed interval of MIDI clock
+    interval: Option<f64>,
+}
+impl MidiClock {
+    pub fn new() -> MidiClock {
+        MidiClock::default()
+    }
+    pub fn transport(&self) -> &Transport {
+        &self.transport
+    }
+    /// Process a message received at `stamp`(us). Returns false if the message is not related.
+    pub fn process(&mut self, stamp: u64, message: &MidiMessage) -> bool {
+        match message {
+            MidiMessage::TimingClock => {
+                if let Some(last) = self.last_stamp {
+                    let dt = stamp.saturating_sub(last);
+                    self.interval = if 0 < dt && dt <= MAX_INTERVAL {
+                        let dt = dt as f64;
+                        Some(self.interval.map_or(dt, |x| x + (dt - x) * SMOOTHING))
+                    } else {
+                        None
+                    };
+                }
+                self.last_stamp = Some(stamp);
+                self.transport.bpm = self
+                    .interval
+                    .map(|x| (60_000_000.0 / (x * CLOCKS_PER_BEAT as f64)) as f32);
+                if self.transport.playing {
+                    // The first clock after start is at the position 0
+                    self.set_position(self.clocks);
+                    self.clocks += 1;
+                }
+            }
+            MidiMessage::Start => {
+                self.clocks = 0;
+                self.transport.playing = true;
+                self.set_position(0);
+            }
+            MidiMessage::Continue => {
+                self.transport.playing = true;
+            }
+            MidiMessage::Stop => {
+                self.transport.playing = false;
+            }
+            MidiMessage::SongPosition(beats) => {
+                self.clocks = *beats as u32 * 6;
+                self.set_position(self.clocks);
+            }
+            _ => return false,
+        }
+        true
+    }
+    fn set_position(&mut self, clocks: u32) {
+        self.transport.position = clocks as f64 / CLOCKS_PER_BEAT as f64;
+        self.transport.next_clock = (clocks + 1) as f64 / CLOCKS_PER_BEAT as f64;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_midi_clock() {
+        let mut clock = MidiClock::new();
+        assert!(!clock.process(0, &MidiMessage::TuneRequest));
+        // 120 BPM = 20833us per clock, with jitter
+        let mut stamp = 0;
+        for i in 0..48 {
+            stamp += if i % 2 == 0 { 20_000 } else { 21_666 };
+            clock.process(stamp, &MidiMessage::TimingClock);
+        }
+        let bpm = clock.transport().bpm.unwrap();
+        assert!((bpm - 120.0).abs() < 1.0, "{}", bpm);
+        assert!(!clock.transport().playing);
+        assert_eq!(clock.transport().position, 0.0);
+
+        clock.process(stamp, &MidiMessage::Start);
+        for _ in 0..25 {
+            stamp += 20_833;
+            clock.process(stamp, &MidiMessage::TimingClock);
+        }
+        assert!(clock.transport().playing);
+        assert_eq!(clock.transport().position, 1.0);
+        clock.process(stamp, &MidiMessage::Stop);
+        stamp += 20_833;
+        clock.process(stamp, &MidiMessage::TimingClock);
+        assert_eq!(clock.transport().position, 1.0);
+        clock.process(stamp, &MidiMessage::Continue);
+        stamp += 20_833;
+        clock.process(stamp, &MidiMessage::TimingClock);
+        assert_eq!(clock.transport().position, 1.0 + 1.0 / 24.0);
+        // 4 sixteenth notes
+        clock.process(stamp, &MidiMessage::SongPosition(4));
+        assert_eq!(clock.transport().position, 1.0);
+
+        // clock stopped
+        stamp += 1_000_000;
+        clock.process(stamp, &MidiMessage::TimingClock);
+        assert_eq!(clock.transport().bpm, None);
+    }
+
+    #[test]
+    fn test_interpolation() {
+        let clock = Transport {
+            playing: true,
+            bpm: Some(120.0),
+            position: 1.0,
+            next_clock: 1.0 + 1.0 / 24.0,
+        };
+        let mut transport = Transport::default();
+        transport.sync(&clock);
+        assert_eq!(transport, clock);
+        // a clock is 1/48 sec
+        transport.advance(0.01);
+        assert!((transport.position - 1.02).abs() < 1e-6);
+        transport.advance(0.1);
+        assert_eq!(transport.position, clock.next_clock);
+        // doesn't go back within the clock
+        transport.sync(&clock);
+        assert_eq!(transport.position, clock.next_clock);
+        // long after the start: each sample still moves the position
+        let clock = Transport {
+            position: 10_000.0,
+            next_clock: 10_000.0 + 1.0 / 24.0,
+            ..clock
+        };
+        transport.sync(&clock);
+        for _ in 0..100 {
+            transport.advance(1.0 / 48_000.0);
+        }
+        // 100 samples at 48kHz, 120 BPM
+        assert!((transport.position - (10_000.0 + 100.0 / 24_000.0)).abs() < 1e-9);
+        // restarted
+        transport.sync(&Transport {
+            position: 0.0,
+            next_clock: 1.0 / 24.0,
+            ..clock
+        });
+        assert_eq!(transport.position, 0.0);
+    }
+}
@@ -19,3 +19,165 @@ impl<T: std::error::Error + std::fmt::Display> std::fmt::Display for SyncError<T
     }
 }
 impl<T: std::error::Error> std::error::Error for SyncError<T> {}
+
+const TRIPLE_BUFFER_DIRTY: usize = 4;
+
+struct TripleBufferShared<T> {
+    slots: [std::cell::UnsafeCell<T>; 3],
+    /// Index of the slot not owned by the writer nor the reader, | TRIPLE_BUFFER_DIRTY if it's newer than the reader's one
+    back: std::sync::atomic::AtomicUsize,
+}
+// Each slot is accessed by one side at a time, ownership is exchanged through `back`.
+unsafe impl<T: Send> Sync for TripleBufferShared<T> {}
+
+/// Wait-free single producer, single consumer exchange of the latest value.
+/// Neither side blocks, and the reader never allocates.
+pub fn triple_buffer<T: Clone>(initial: T) -> (TripleBufferWriter<T>, TripleBufferReader<T>) {
+    let shared = std::sync::Arc::new(TripleBufferShared {
+        slots: [
+            std::cell::UnsafeCell::new(initial.clone()),
+            std::cell::UnsafeCell::new(initial.clone()),
+            std::cell::UnsafeCell::new(initial),
+        ],
+        back: std::sync::atomic::AtomicUsize::new(1),
+    });
+    (
+        TripleBufferWriter {
+            shared: std::sync::Arc::clone(&shared),
+            index: 0,
+        },
+        TripleBufferReader { shared, index: 2 },
+    )
+}
+
+pub struct TripleBufferWriter<T> {
+    shared: std::sync::Arc<TripleBufferShared<T>>,
+    index: usize,
+}
+impl<T: Clone> TripleBufferWriter<T> {
+    /// Make a copy of `value` visible to the reader.
+    pub fn publish(&mut self, value: &T) {
+        use std::sync::atomic::Ordering;
+        // Safety: the slot at self.index is owned by the writer
+        unsafe { &mut *self.shared.slots[self.index].get() }.clone_from(value);
+        let prev = self
+            .shared
+            .back
+            .swap(self.index | TRIPLE_BUFFER_DIRTY, Ordering::AcqRel);
+        self.index = prev & !TRIPLE_BUFFER_DIRTY;
+    }
+}
+
+pub struct TripleBufferReader<T> {
+    shared: std::sync::Arc<TripleBufferShared<T>>,
+    index: usize,
+}
+impl<T> TripleBufferReader<T> {
+    /// The latest published value
+    pub fn read(&mut self) -> &T {
+        use std::sync::atomic::Ordering;
+        if self.shared.back.load(Ordering::Relaxed) & TRIPLE_BUFFER_DIRTY != 0 {
+            let prev = self.shared.back.swap(self.index, Ordering::AcqRel);
+            self.index = prev & !TRIPLE_BUFFER_DIRTY;
+        }
+        // Safety: the slot at self.index is owned by the reader
+        unsafe { &*self.shared.slots[self.index].get() }
+    }
+    /// The latest published value if it's not read yet
+    pub fn read_updated(&mut self) -> Option<&T> {
+        use std::sync::atomic::Ordering;
+        if self.shared.back.load(Ordering::Relaxed) & TRIPLE_BUFFER_DIRTY != 0 {
+            Some(self.read())
+        } else {
+            None
+        }
+    }
+}
+
+/// Detects changes of a file by polling its modification time
+pub struct FileWatcher {
+    path: std::path::PathBuf,
+    modified: Option<std::time::SystemTime>,
+}
+impl FileWatcher {
+    pub fn new(path: impl Into<std::path::PathBuf>) -> FileWatcher {
+        let path = path.into();
+        let modified = modified_time(&path);
+        FileWatcher { path, modified }
+    }
+    /// True if the file is modified since the last call.
+    /// A missing file (e.g. while an editor replaces it) is not a change.
+    pub fn changed(&mut self) -> bool {
+        match modified_time(&self.path) {
+            Some(t) if Some(t) != self.modified => {
+                self.modified = Some(t);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+fn modified_time(path: &std::path::Path) -> Option<std::time::SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_triple_buffer() {
+        let (mut writer, mut reader) = triple_buffer(0);
+        assert_eq!(*reader.read(), 0);
+        writer.publish(&1);
+        writer.publish(&2);
+        assert_eq!(*reader.read(), 2);
+        assert_eq!(*reader.read(), 2);
+        writer.publish(&3);
+        assert_eq!(*reader.read(), 3);
+
+        assert_eq!(reader.read_updated(), None);
+        writer.publish(&4);
+        assert_eq!(reader.read_updated(), Some(&4));
+        assert_eq!(reader.read_updated(), None);
+    }
+
+    #[test]
+    fn test_triple_buffer_concurrent() {
+        const N: usize = 100_000;
+        let (mut writer, mut reader) = triple_buffer(vec![0; 16]);
+        let handle = std::thread::spawn(move || {
+            let mut value = vec![0; 16];
+            for i in 1..=N {
+                value.iter_mut().for_each(|x| *x = i);
+                writer.publish(&value);
+            }
+        });
+        let mut last = 0;
+        while last < N {
+            let value = reader.read();
+            // never sees a partially written value
+            assert!(value.iter().all(|x| *x == value[0]));
+            assert!(last <= value[0]);
+            last = value[0];
+        }
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_file_watcher() {
+        let path = std::env::temp_dir().join(format!("file_watcher_{}.toml", std::process::id()));
+        let mut watcher = FileWatcher::new(&path);
+        assert!(!watcher.changed());
+        let file = std::fs::File::create(&path).unwrap();
+        assert!(watcher.changed());
+        assert!(!watcher.changed());
+        let t = file.metadata().unwrap().modified().unwrap();
+        file.set_modified(t + std::time::Duration::from_secs(1))
+            .unwrap();
+        assert!(watcher.changed());
+        assert!(!watcher.changed());
+        std::fs::remove_file(&path).unwrap();
+        assert!(!watcher.changed());
+    }
+}